@@ -158,6 +158,7 @@ pub fn mainloop(mut args: Opt) -> Result<()> {
 struct LoginScreen {
     login_file: LoginFile,
     err_text: String,
+    interp_delay: f32,
 }
 
 impl LoginScreen {
@@ -173,6 +174,7 @@ impl LoginScreen {
         Ok(Self {
             login_file,
             err_text: "".into(),
+            interp_delay: args.interp_delay,
         })
     }
 
@@ -197,7 +199,7 @@ impl LoginScreen {
             login_info.address,
             login_info.username
         );
-        let c = Client::new(gl.clone(), login_info);
+        let c = Client::new(gl.clone(), login_info, self.interp_delay);
         match c {
             Ok(c) => Some(c),
             Err(e) => {
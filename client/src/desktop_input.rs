@@ -1,5 +1,5 @@
 use cimvr_common::desktop::*;
-use cimvr_engine::Engine;
+use cimvr_engine::{inbox::HostInbox, Engine};
 use glutin::{dpi::PhysicalPosition, window::CursorGrabMode};
 
 /// Input handler for Desktop platform
@@ -8,20 +8,20 @@ pub struct DesktopInputHandler {
 }
 
 pub struct WindowController {
+    controls: HostInbox<WindowControl>,
     is_capturing: bool,
 }
 
 impl WindowController {
     pub fn new(engine: &mut Engine) -> Self {
-        engine.subscribe::<WindowControl>();
-
         Self {
+            controls: engine.subscribe(),
             is_capturing: false,
         }
     }
 
     pub fn update(&mut self, engine: &mut Engine, window: &glutin::window::Window) {
-        for msg in engine.inbox::<WindowControl>() {
+        for msg in engine.inbox(&self.controls) {
             match msg {
                 WindowControl::MouseCapture => self.is_capturing = true,
                 WindowControl::MouseRelease => self.is_capturing = false,
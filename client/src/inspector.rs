@@ -0,0 +1,175 @@
+use std::collections::BTreeMap;
+
+use cimvr_engine::interface::prelude::{ComponentId, EntityId};
+use cimvr_engine::Engine;
+use egui::{CollapsingHeader, DragValue, RichText, ScrollArea, Ui};
+use serde_json::{Number, Value};
+
+/// Lists entities and their components, and allows editing component data in place.
+///
+/// Components are decoded using the schemas known to the engine, and shown as raw bytes
+/// otherwise. Note that edits to entities owned by the server are overwritten by the next update.
+pub struct Inspector {
+    /// Only show entities with a component whose ID contains this text
+    filter: String,
+}
+
+impl Inspector {
+    pub fn new() -> Self {
+        Self {
+            filter: String::new(),
+        }
+    }
+
+    pub fn show(&mut self, ui: &mut Ui, engine: &mut Engine) {
+        ui.horizontal(|ui| {
+            ui.label("Component filter");
+            ui.text_edit_singleline(&mut self.filter);
+        });
+        ui.separator();
+
+        // Group entities by the plugin which created them
+        let filter = self.filter.to_lowercase();
+        let mut groups: BTreeMap<String, Vec<(EntityId, Vec<ComponentId>)>> = BTreeMap::new();
+        for entity in engine.ecs().entities().collect::<Vec<_>>() {
+            let mut components = engine.ecs().components(entity);
+            if !components
+                .iter()
+                .any(|component| component.id.to_lowercase().contains(&filter))
+            {
+                continue;
+            }
+            components.sort_by(|a, b| a.id.cmp(&b.id));
+
+            let owner = engine.owner(entity).unwrap_or("Remote").to_string();
+            groups.entry(owner).or_default().push((entity, components));
+        }
+
+        ScrollArea::vertical().show(ui, |ui| {
+            for (owner, mut entities) in groups {
+                entities.sort_by_key(|(entity, _)| entity.0);
+
+                CollapsingHeader::new(format!("{} ({} entities)", owner, entities.len()))
+                    .id_source(&owner)
+                    .show(ui, |ui| {
+                        for (entity, components) in entities {
+                            CollapsingHeader::new(format!("{:032x}", entity.0))
+                                .id_source(entity)
+                                .show(ui, |ui| {
+                                    for component in components {
+                                        show_component(ui, engine, entity, &component);
+                                    }
+                                });
+                        }
+                    });
+            }
+        });
+    }
+}
+
+/// Show the given component, writing any edits back to the ECS
+fn show_component(ui: &mut Ui, engine: &mut Engine, entity: EntityId, component: &ComponentId) {
+    let Some(data) = engine.ecs().get_raw(entity, component).map(|d| d.to_vec()) else { return };
+
+    ui.label(RichText::new(&component.id).strong());
+
+    let mut value = match engine.schemas().to_json(&component.id, &data) {
+        Ok(value) => value,
+        Err(_) => {
+            ui.label(RichText::new(hex(&data)).monospace());
+            return;
+        }
+    };
+
+    if !edit_value(ui, &mut value) {
+        return;
+    }
+
+    match engine.schemas().from_json(&component.id, &value) {
+        Ok(data) if data.len() == usize::from(component.size) => {
+            engine.ecs().add_component_raw(entity, component, &data)
+        }
+        Ok(_) => log::error!("Edited {} has the wrong size", component.id),
+        Err(e) => log::error!("Failed to encode edited {}; {}", component.id, e),
+    }
+}
+
+/// Show an editor for the given value, returning `true` if it was changed
+fn edit_value(ui: &mut Ui, value: &mut Value) -> bool {
+    match value {
+        Value::Null => {
+            ui.label("-");
+            false
+        }
+        Value::Bool(b) => ui.checkbox(b, "").changed(),
+        Value::Number(n) => edit_number(ui, n),
+        Value::String(s) => ui.text_edit_singleline(s).changed(),
+        Value::Array(values) if values.iter().all(is_scalar) => {
+            ui.horizontal_wrapped(|ui| {
+                let mut changed = false;
+                for value in values {
+                    changed |= edit_value(ui, value);
+                }
+                changed
+            })
+            .inner
+        }
+        Value::Array(values) => {
+            let mut changed = false;
+            for (idx, value) in values.iter_mut().enumerate() {
+                ui.label(idx.to_string());
+                changed |= ui.indent(idx, |ui| edit_value(ui, value)).inner;
+            }
+            changed
+        }
+        Value::Object(map) => {
+            let mut changed = false;
+            for (name, value) in map.iter_mut() {
+                if is_scalar(value) || value.as_array().is_some_and(|v| v.iter().all(is_scalar)) {
+                    ui.horizontal(|ui| {
+                        ui.label(name.as_str());
+                        changed |= edit_value(ui, value);
+                    });
+                } else {
+                    ui.label(name.as_str());
+                    changed |= ui.indent(name, |ui| edit_value(ui, value)).inner;
+                }
+            }
+            changed
+        }
+    }
+}
+
+fn edit_number(ui: &mut Ui, n: &mut Number) -> bool {
+    if n.is_f64() {
+        let mut x = n.as_f64().unwrap_or_default();
+        let changed = ui.add(DragValue::new(&mut x).speed(0.01)).changed();
+        if let Some(new) = Number::from_f64(x).filter(|_| changed) {
+            *n = new;
+        }
+        changed
+    } else if let Some(mut x) = n.as_i64() {
+        let changed = ui.add(DragValue::new(&mut x)).changed();
+        *n = x.into();
+        changed
+    } else if let Some(mut x) = n.as_u64() {
+        let changed = ui.add(DragValue::new(&mut x)).changed();
+        *n = x.into();
+        changed
+    } else {
+        // Too large to edit (e.g. handles)
+        ui.label(n.to_string());
+        false
+    }
+}
+
+fn is_scalar(value: &Value) -> bool {
+    !matches!(value, Value::Array(_) | Value::Object(_))
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
@@ -0,0 +1,148 @@
+use std::collections::{HashMap, VecDeque};
+
+use cimvr_common::Transform;
+use cimvr_engine::ecs::EcsMap;
+use cimvr_engine::interface::prelude::*;
+use cimvr_engine::interface::{component_id, serial::deserialize};
+use cimvr_engine::snapshot::{Sample, SnapshotBuffer};
+use cimvr_engine::Engine;
+
+/// Positional error (in meters) above which predicted state is corrected to match the server
+const RECONCILE_THRESHOLD: f32 = 0.05;
+
+/// Seconds of prediction history to keep for each predicted entity
+const PREDICTION_HISTORY: f64 = 1.0;
+
+/// Smooths replicated `Transform`s by interpolating between snapshots from the server, and
+/// reconciles locally predicted entities with the authoritative state from the server
+pub struct Interpolation {
+    /// How far in the past (in seconds) remote entities are displayed
+    delay: f64,
+    /// Transforms received from the server
+    snapshots: SnapshotBuffer,
+    /// Recent transforms of predicted entities, timestamped with the (estimated) server time
+    history: HashMap<EntityId, VecDeque<(f64, Transform)>>,
+    /// Transforms of predicted entities, kept while server state is imported
+    predicted: HashMap<EntityId, Transform>,
+    /// Our connection ID; entities we own are displayed as written locally
+    client: ClientId,
+}
+
+impl Interpolation {
+    pub fn new(delay: f64, client: ClientId) -> Self {
+        Self {
+            delay,
+            snapshots: SnapshotBuffer::new(component_id::<Transform>(), delay + 1.),
+            history: HashMap::new(),
+            predicted: HashMap::new(),
+            client,
+        }
+    }
+
+    /// Buffer a snapshot of server state taken at `server_time`. Must be called before the
+    /// snapshot is imported into the ECS, in order to preserve and reconcile predicted entities.
+    pub fn receive(&mut self, engine: &mut Engine, server_time: f64, ecs: &EcsMap, rtt: f32) {
+        self.snapshots.push(server_time, ecs);
+
+        let authoritative = ecs.get(&component_id::<Transform>());
+
+        self.predicted.clear();
+        for entity in predicted_entities(engine) {
+            let Some(mut local) = engine.ecs().get::<Transform>(entity) else { continue };
+
+            let server: Option<Transform> = authoritative
+                .and_then(|comp| comp.get(&entity))
+                .and_then(|data| deserialize(data.as_slice()).ok());
+
+            // The server's state reflects our predictions from about half a round trip ago
+            let then = server_time - f64::from(rtt) / 2.;
+            let predicted = self.history.get(&entity).and_then(|h| closest(h, then));
+
+            if let (Some(server), Some(predicted)) = (server, predicted) {
+                if server.pos.distance(predicted.pos) > RECONCILE_THRESHOLD {
+                    // Re-apply local movement made since then on top of the server's state
+                    local = server * (predicted.inverse() * local);
+                    self.history.remove(&entity);
+                }
+            }
+
+            self.predicted.insert(entity, local);
+        }
+    }
+
+    /// Restore predicted entities after server state has been imported
+    pub fn restore(&mut self, engine: &mut Engine) {
+        for (entity, transf) in self.predicted.drain() {
+            engine.ecs().add_component(entity, &transf);
+            engine.ecs().add_component(entity, &Predicted);
+        }
+    }
+
+    /// Display remote entities as they were `delay` seconds before the given server time
+    pub fn interpolate(&mut self, engine: &mut Engine, server_time: f64) {
+        let time = server_time - self.delay;
+
+        let entities: Vec<EntityId> = self.snapshots.entities().collect();
+        for entity in entities {
+            if engine.ecs().get::<Predicted>(entity).is_some()
+                || engine.ecs().get::<ClientOwned>(entity) == Some(ClientOwned(self.client))
+            {
+                continue;
+            }
+
+            let transf: Option<Transform> = match self.snapshots.sample(entity, time) {
+                Some(Sample::Exact(data)) => deserialize(data).ok(),
+                Some(Sample::Between(a, b, t)) => {
+                    let a: Option<Transform> = deserialize(a).ok();
+                    let b: Option<Transform> = deserialize(b).ok();
+                    a.zip(b).map(|(a, b)| a.lerp_slerp(&b, t))
+                }
+                None => None,
+            };
+
+            if let Some(transf) = transf {
+                engine.ecs().add_component(entity, &transf);
+            }
+        }
+    }
+
+    /// Record the current state of predicted entities, for later reconciliation
+    pub fn record(&mut self, engine: &mut Engine, server_time: f64) {
+        let entities = predicted_entities(engine);
+        self.history.retain(|entity, _| entities.contains(entity));
+
+        for entity in entities {
+            let Some(transf) = engine.ecs().get::<Transform>(entity) else { continue };
+            let history = self.history.entry(entity).or_default();
+            history.push_back((server_time, transf));
+            while let Some(&(oldest, _)) = history.front() {
+                if server_time - oldest > PREDICTION_HISTORY {
+                    history.pop_front();
+                } else {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Entities marked for prediction which have a Transform
+fn predicted_entities(engine: &mut Engine) -> Vec<EntityId> {
+    engine
+        .ecs()
+        .query(
+            &Query::new()
+                .intersect::<Predicted>(Access::Read)
+                .intersect::<Transform>(Access::Read),
+        )
+        .into_iter()
+        .collect()
+}
+
+/// Find the recorded transform closest to the given time
+fn closest(history: &VecDeque<(f64, Transform)>, time: f64) -> Option<Transform> {
+    history
+        .iter()
+        .min_by(|(a, _), (b, _)| (a - time).abs().total_cmp(&(b - time).abs()))
+        .map(|(_, transf)| *transf)
+}
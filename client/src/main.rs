@@ -3,23 +3,33 @@ extern crate glow as gl;
 #[cfg(feature = "vr")]
 extern crate openxr as xr;
 
-use cimvr_common::InterdimensionalTravelRequest;
+use cimvr_common::render::CameraComponent;
+use cimvr_common::{
+    register_schemas, GlobalTransform, InterdimensionalTravelRequest, QuantizedTransform,
+    Transform,
+};
 use anyhow::{bail, format_err, Context, Result};
 use cimvr_common::glam::Mat4;
+use cimvr_engine::hierarchy::Hierarchy;
 use cimvr_engine::hotload::Hotloader;
+use cimvr_engine::inbox::HostInbox;
 use cimvr_engine::interface::prelude::{
-    Access, ConnectionRequest, ConnectionResponse, PluginData, Query, Synchronized,
+    Access, ClientId, ConnectionRequest, ConnectionResponse, LocalClient, PluginData, Query,
+    ServerTime, Synchronized,
 };
 use cimvr_engine::interface::serial::{deserialize, serialize};
 use cimvr_engine::network::{
-    length_delimit_message, AsyncBufferedReceiver, ClientToServer, ReadState, ServerToClient,
+    length_delimit_message, AsyncBufferedReceiver, ClientToServer, ClockSync, ReadState,
+    ServerToClient,
 };
+use cimvr_engine::replication::Codec;
 use cimvr_engine::Engine;
 use cimvr_engine::{calculate_digest, Config};
 use directories::ProjectDirs;
 use gamepad::GamepadPlugin;
+use interpolation::Interpolation;
 use plugin_cache::FileCache;
-use render::RenderPlugin;
+use render::{world_transform, RenderPlugin};
 use std::collections::HashSet;
 use std::io::Write;
 use std::net::{SocketAddr, TcpStream};
@@ -33,6 +43,8 @@ mod vr;
 mod desktop;
 mod desktop_input;
 mod gamepad;
+mod inspector;
+mod interpolation;
 mod plugin_cache;
 mod render;
 mod ui;
@@ -56,6 +68,11 @@ pub struct Opt {
     /// Username (optional, defaults to anonymousXXXX)
     #[structopt(short, long)]
     pub username: Option<String>,
+
+    /// How far in the past (in milliseconds) to display entities synchronized from the server.
+    /// Larger values result in smoother motion, at the cost of latency
+    #[structopt(long, default_value = "100")]
+    pub interp_delay: f32,
 }
 
 struct Client {
@@ -65,6 +82,11 @@ struct Client {
     conn: TcpStream,
     gamepad: GamepadPlugin,
     ui: OverlayUi,
+    clock: ClockSync,
+    interp: Interpolation,
+    /// Connection ID assigned by the server
+    id: ClientId,
+    travel: HostInbox<InterdimensionalTravelRequest>,
 }
 
 fn main() -> Result<()> {
@@ -91,7 +113,7 @@ fn main() -> Result<()> {
 // code uplication!
 
 impl Client {
-    pub fn new(gl: Arc<gl::Context>, login: LoginInfo) -> Result<Self> {
+    pub fn new(gl: Arc<gl::Context>, login: LoginInfo, interp_delay: f32) -> Result<Self> {
         // Set up plugin cache
         let mut plugin_cache = FileCache::new(project_dirs().cache_dir().into())?;
 
@@ -145,7 +167,18 @@ impl Client {
         }
 
         // Set up engine and initialize plugins
-        let mut engine = Engine::new(&plugins, Config { is_server: false })?;
+        let cfg = Config::default();
+        let mut engine = Engine::new(&plugins, cfg)?;
+
+        // Must match the server's wire formats
+        engine
+            .ecs()
+            .replication()
+            .set_codec::<Transform>(Codec::via::<Transform, QuantizedTransform>());
+        engine.set_hierarchy(Hierarchy::new::<Transform, GlobalTransform>());
+
+        // Allow inspecting components used by server-side plugins
+        register_schemas(engine.schemas_mut());
 
         // Set up rendering
         let render = RenderPlugin::new(gl, &mut engine).context("Setting up render engine")?;
@@ -155,18 +188,22 @@ impl Client {
         let gamepad = GamepadPlugin::new()?;
 
         // Set up interdimensional travel
-        engine.subscribe::<InterdimensionalTravelRequest>();
+        let travel = engine.subscribe();
 
         // Initialize plugins AFTER we set up our plugins
         engine.init_plugins()?;
 
         Ok(Self {
             recv_buf,
+            clock: ClockSync::new(),
+            interp: Interpolation::new(f64::from(interp_delay) / 1000., response.client_id),
+            id: response.client_id,
             gamepad,
             conn,
             ui,
             engine,
             render,
+            travel,
         })
     }
 
@@ -181,7 +218,23 @@ impl Client {
                 ReadState::Invalid => {
                     log::error!("Failed to parse invalid message");
                 }
-                ReadState::Incomplete => break Ok(()),
+                ReadState::Incomplete => {
+                    // Let plugins know which entities are ours
+                    self.engine.send(LocalClient { id: self.id });
+
+                    // Let plugins know what time it is on the server
+                    if let (Some(time), Some(rtt), Some(offset)) = (
+                        self.clock.remote_time(),
+                        self.clock.rtt(),
+                        self.clock.offset(),
+                    ) {
+                        self.engine.send(ServerTime { time, rtt, offset });
+
+                        // Smooth out synchronized entities
+                        self.interp.interpolate(&mut self.engine, time);
+                    }
+                    break Ok(());
+                }
                 ReadState::Disconnected => {
                     bail!("Disconnected");
                 }
@@ -189,10 +242,32 @@ impl Client {
                     // Update state!
                     let recv: ServerToClient = deserialize(std::io::Cursor::new(buf))?;
 
+                    // Update clock synchronization
+                    if let Some(pong) = recv.pong {
+                        self.clock.pong(pong);
+                    }
+
+                    // Unload plugins removed from the server
+                    for name in recv.unload {
+                        log::info!("Unloading {}", name);
+                        if let Err(e) = self.engine.unload_plugin(&name) {
+                            log::error!("Failed to unload {}; {:#}", name, e);
+                        }
+                    }
+
                     // Load hotloaded plugins
                     for (name, bytecode) in recv.hotload {
-                        log::info!("Reloading {}", name);
-                        self.engine.reload(name, &bytecode)?;
+                        if self.engine.is_loaded(&name) {
+                            log::info!("Reloading {}", name);
+                            if let Err(e) = self.engine.reload(name.clone(), &bytecode) {
+                                log::error!("Failed to reload {}; {:#}", name, e);
+                            }
+                        } else {
+                            log::info!("Loading {}", name);
+                            if let Err(e) = self.engine.load_plugin(name.clone(), &bytecode) {
+                                log::error!("Failed to load {}; {:#}", name, e);
+                            }
+                        }
                     }
 
                     // Receive remote messages
@@ -200,11 +275,18 @@ impl Client {
                         self.engine.broadcast_local(msg);
                     }
 
-                    // Synchronize ECS state
-                    self.engine.ecs().import(
-                        &Query::new().intersect::<Synchronized>(Access::Write),
-                        recv.ecs,
-                    );
+                    // Synchronize ECS state, preserving locally predicted entities and our own
+                    // writes to the entities we own
+                    let owned = self.engine.ecs().export_owned(self.id);
+                    let ecs = self.engine.ecs().replication().receive(recv.ecs);
+                    let rtt = self.clock.rtt().unwrap_or(0.);
+                    self.interp
+                        .receive(&mut self.engine, recv.server_time, &ecs, rtt);
+                    self.engine
+                        .ecs()
+                        .import(&Query::new().intersect::<Synchronized>(Access::Write), ecs);
+                    self.engine.ecs().import_owned(self.id, owned);
+                    self.interp.restore(&mut self.engine);
                 }
             }
         }
@@ -220,9 +302,17 @@ impl Client {
     }
 
     pub fn upload(&mut self) -> Result<()> {
+        // Remember what we predicted, for reconciliation with the server later
+        if let Some(time) = self.clock.remote_time() {
+            self.interp.record(&mut self.engine, time);
+        }
+
         // Send message to server
         let msg = ClientToServer {
             messages: self.engine.network_inbox(),
+            ping: Some(self.clock.ping()),
+            viewpoint: self.viewpoint(),
+            ecs: self.engine.ecs().export_owned(self.id),
         };
 
         self.conn.set_nonblocking(false)?;
@@ -233,12 +323,29 @@ impl Client {
         Ok(())
     }
 
+    /// Position of the camera, which the server uses to decide which entities are relevant to us
+    fn viewpoint(&mut self) -> Option<[f32; 3]> {
+        let camera = self
+            .engine
+            .ecs()
+            .query(
+                &Query::new()
+                    .intersect::<CameraComponent>(Access::Read)
+                    .intersect::<Transform>(Access::Read),
+            )
+            .into_iter()
+            .next()?;
+
+        let transf = world_transform(&mut self.engine, camera)?;
+        Some(transf.pos.to_array())
+    }
+
     fn engine(&mut self) -> &mut Engine {
         &mut self.engine
     }
 
     fn travel_request(&mut self) -> Option<InterdimensionalTravelRequest> {
-        self.engine().inbox().next()
+        self.engine.inbox(&self.travel).next()
     }
 }
 
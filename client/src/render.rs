@@ -2,9 +2,9 @@ use anyhow::bail;
 use anyhow::format_err;
 use anyhow::Result;
 use cimvr_common::glam::Mat4;
-use cimvr_common::{render::*, Transform};
+use cimvr_common::{render::*, GlobalTransform, Transform};
 use cimvr_engine::interface::prelude::*;
-use cimvr_engine::{interface::pkg_namespace, Engine};
+use cimvr_engine::{inbox::HostInbox, interface::pkg_namespace, Engine};
 use gl::HasContext;
 use glow::NativeUniformLocation;
 
@@ -17,6 +17,17 @@ const DEFAULT_SHADER: ShaderHandle = ShaderHandle::new(pkg_namespace!("Default s
 pub struct RenderPlugin {
     gl: Arc<glow::Context>,
     rdr: RenderEngine,
+    meshes: HostInbox<UploadMesh>,
+    shaders: HostInbox<ShaderSource>,
+}
+
+/// The world-space transform of the given entity; its `GlobalTransform` if the host computed one,
+/// and otherwise its `Transform`
+pub fn world_transform(engine: &mut Engine, entity: EntityId) -> Option<Transform> {
+    match engine.ecs().get::<GlobalTransform>(entity) {
+        Some(GlobalTransform(transf)) => Some(transf),
+        None => engine.ecs().get::<Transform>(entity),
+    }
 }
 
 // TODO: destructors! (lol)
@@ -47,12 +58,14 @@ struct GpuShader {
 
 impl RenderPlugin {
     pub fn new(gl: Arc<gl::Context>, engine: &mut Engine) -> Result<Self> {
-        engine.subscribe::<UploadMesh>();
-        engine.subscribe::<ShaderSource>();
-
         let rdr = RenderEngine::new(&gl)?;
 
-        Ok(Self { gl, rdr })
+        Ok(Self {
+            gl,
+            rdr,
+            meshes: engine.subscribe(),
+            shaders: engine.subscribe(),
+        })
     }
 
     pub fn set_screen_size(&mut self, width: u32, height: u32) {
@@ -65,14 +78,14 @@ impl RenderPlugin {
     /// Draw a frame, prepending camera transform to the given view
     pub fn frame(&mut self, engine: &mut Engine, vr_view: Mat4, camera_idx: usize) -> Result<()> {
         // Upload render data
-        for msg in engine.inbox::<UploadMesh>() {
+        for msg in engine.inbox(&self.meshes) {
             if let Err(e) = self.rdr.upload_render_data(&self.gl, &msg) {
                 log::error!("Error uploading render data at id {:?}; {:?}", msg.id, e);
             }
         }
 
         // Upload shader
-        for msg in engine.inbox::<ShaderSource>() {
+        for msg in engine.inbox(&self.shaders) {
             if let Err(e) = self.rdr.upload_shader(&self.gl, &msg) {
                 log::error!("Error uploading shader at id {:?}; {:?}", msg.id, e);
             }
@@ -96,7 +109,7 @@ impl RenderPlugin {
             }
         };
 
-        let camera_transf = engine.ecs().get::<Transform>(camera_entity).unwrap();
+        let camera_transf = world_transform(engine, camera_entity).unwrap();
         let camera_comp = engine.ecs().get::<CameraComponent>(camera_entity).unwrap();
         let proj = camera_comp.projection[camera_idx];
         let view = vr_view * camera_transf.view();
@@ -112,7 +125,7 @@ impl RenderPlugin {
         );
 
         for entity in entities {
-            let transf = engine.ecs().get::<Transform>(entity).unwrap();
+            let transf = world_transform(engine, entity).unwrap();
             let rdr_comp = engine.ecs().get::<Render>(entity).unwrap();
 
             // TODO: Sort entities by shader in order to set this less!
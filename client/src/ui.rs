@@ -1,11 +1,26 @@
 use std::collections::HashMap;
 
+use crate::inspector::Inspector;
 use cimvr_common::ui::*;
-use cimvr_engine::Engine;
-use egui::{color_picker::color_edit_button_rgb, Context, DragValue, ScrollArea, TextEdit, Ui};
+use cimvr_engine::metrics::MessageCounters;
+use cimvr_engine::{inbox::HostInbox, Engine};
+use egui::{
+    color_picker::color_edit_button_rgb, Context, DragValue, Key, ScrollArea, Slider, TextEdit, Ui,
+};
 
 pub struct OverlayUi {
+    requests: HostInbox<UiRequest>,
     elements: HashMap<UiHandle, Element>,
+    /// Whether to show the engine clock controls (toggled with F3)
+    show_clock: bool,
+    /// ECS inspector
+    inspector: Inspector,
+    /// Whether to show the ECS inspector (toggled with F4)
+    show_inspector: bool,
+    /// Whether to show message bus metrics (toggled with F5)
+    show_metrics: bool,
+    /// Whether to show system timings (toggled with F6)
+    show_profiler: bool,
 }
 
 struct Element {
@@ -16,13 +31,59 @@ struct Element {
 
 impl OverlayUi {
     pub fn new(engine: &mut Engine) -> Self {
-        engine.subscribe::<UiRequest>();
         Self {
+            requests: engine.subscribe(),
             elements: HashMap::new(),
+            show_clock: false,
+            inspector: Inspector::new(),
+            show_inspector: false,
+            show_metrics: false,
+            show_profiler: false,
         }
     }
 
     pub fn run(&mut self, ctx: &Context, engine: &mut Engine) {
+        if ctx.input().key_pressed(Key::F3) {
+            self.show_clock = !self.show_clock;
+        }
+
+        if ctx.input().key_pressed(Key::F4) {
+            self.show_inspector = !self.show_inspector;
+        }
+
+        if ctx.input().key_pressed(Key::F5) {
+            self.show_metrics = !self.show_metrics;
+        }
+
+        if ctx.input().key_pressed(Key::F6) {
+            self.show_profiler = !self.show_profiler;
+        }
+
+        // Write out traces once captured
+        if let Some(trace) = engine.profiler_mut().take_trace() {
+            match trace.write(TRACE_PATH) {
+                Ok(()) => log::info!("Wrote profiling trace to {}", TRACE_PATH),
+                Err(e) => log::error!("Failed to write profiling trace; {:#}", e),
+            }
+        }
+
+        egui::Window::new("Engine clock")
+            .open(&mut self.show_clock)
+            .show(ctx, |ui| clock_controls(ui, engine));
+
+        let inspector = &mut self.inspector;
+        egui::Window::new("Inspector")
+            .open(&mut self.show_inspector)
+            .show(ctx, |ui| inspector.show(ui, engine));
+
+        egui::Window::new("Messages")
+            .open(&mut self.show_metrics)
+            .show(ctx, |ui| message_metrics(ui, engine));
+
+        egui::Window::new("Profiler")
+            .open(&mut self.show_profiler)
+            .show(ctx, |ui| profiler(ui, engine));
+
         if self.elements.is_empty() {
             return;
         }
@@ -45,7 +106,7 @@ impl OverlayUi {
 
     pub fn update(&mut self, engine: &mut Engine) {
         // Process requests
-        for req in engine.inbox::<UiRequest>() {
+        for req in engine.inbox(&self.requests) {
             self.process_request(req);
         }
 
@@ -102,6 +163,185 @@ impl OverlayUi {
     }
 }
 
+/// Pause, time scale and single-step controls for the engine clock
+fn clock_controls(ui: &mut Ui, engine: &mut Engine) {
+    let time = engine.frame_time();
+    ui.label(format!("Tick {}, {:.2}s", time.tick, time.time));
+
+    let mut paused = engine.is_paused();
+    if ui.checkbox(&mut paused, "Paused").changed() {
+        if paused {
+            engine.pause();
+        } else {
+            engine.resume();
+        }
+    }
+
+    let mut scale = engine.time_scale();
+    if ui
+        .add(Slider::new(&mut scale, 0.0..=4.0).text("Time scale"))
+        .changed()
+    {
+        engine.set_time_scale(scale);
+    }
+
+    if ui.button("Step").clicked() {
+        engine.step(1);
+    }
+}
+
+/// Number of frames captured by the profiler window
+const TRACE_FRAMES: u32 = 300;
+
+/// Where traces captured by the profiler window are written
+const TRACE_PATH: &str = "cimvr_trace.json";
+
+/// Average time spent in each system, and trace capture
+fn profiler(ui: &mut Ui, engine: &mut Engine) {
+    ui.horizontal(|ui| {
+        let capturing = engine.profiler().is_capturing();
+        let text = format!("Capture {} frames to {}", TRACE_FRAMES, TRACE_PATH);
+        if ui
+            .add_enabled(!capturing, egui::Button::new(text))
+            .clicked()
+        {
+            engine.profiler_mut().capture(TRACE_FRAMES);
+        }
+
+        if ui.button("Reset").clicked() {
+            engine.profiler_mut().reset();
+        }
+    });
+
+    let mut systems: Vec<_> = engine
+        .profiler()
+        .systems()
+        .iter()
+        .flat_map(|(plugin, stats)| stats.iter().enumerate().map(move |(i, s)| ((plugin, i), s)))
+        .filter(|(_, stats)| stats.calls > 0)
+        .collect();
+    systems.sort_by_key(|(_, stats)| std::cmp::Reverse(stats.average.total()));
+
+    let ms = |d: std::time::Duration| format!("{:.3}", d.as_secs_f64() * 1e3);
+
+    ScrollArea::vertical().show(ui, |ui| {
+        egui::Grid::new("system_timings")
+            .striped(true)
+            .show(ui, |ui| {
+                let headers = [
+                    "System", "Stage", "Total", "Query", "Ser", "Call", "De", "Apply",
+                ];
+                for header in headers {
+                    ui.strong(header);
+                }
+                ui.end_row();
+
+                for ((plugin, idx), stats) in systems {
+                    let avg = &stats.average;
+                    ui.label(format!("{} #{}", plugin, idx));
+                    ui.label(stats.stage.map(|s| format!("{:?}", s)).unwrap_or_default());
+                    for time in [
+                        avg.total(),
+                        avg.query,
+                        avg.serialize,
+                        avg.call,
+                        avg.deserialize,
+                        avg.apply,
+                    ] {
+                        ui.label(ms(time));
+                    }
+                    ui.end_row();
+                }
+            });
+    });
+}
+
+/// Number of recent messages shown by the message tap
+const TAP_CAPACITY: usize = 100;
+
+/// Per-channel and per-plugin message counters, and the most recent messages
+fn message_metrics(ui: &mut Ui, engine: &mut Engine) {
+    ui.horizontal(|ui| {
+        let mut tap = engine.metrics().tap_capacity() > 0;
+        if ui.checkbox(&mut tap, "Record recent messages").changed() {
+            let capacity = if tap { TAP_CAPACITY } else { 0 };
+            engine.metrics_mut().set_tap_capacity(capacity);
+        }
+
+        if ui.button("Reset").clicked() {
+            engine.metrics_mut().reset();
+        }
+    });
+
+    ScrollArea::vertical().show(ui, |ui| {
+        let metrics = engine.metrics();
+
+        let mut channels: Vec<_> = metrics
+            .channels()
+            .iter()
+            .map(|(channel, counters)| (channel.id.as_str(), counters))
+            .collect();
+        channels.sort_by_key(|(_, counters)| std::cmp::Reverse(counters.bytes));
+
+        let mut plugins: Vec<_> = metrics
+            .plugins()
+            .iter()
+            .map(|(name, counters)| (name.as_str(), counters))
+            .collect();
+        plugins.sort_by_key(|(_, counters)| std::cmp::Reverse(counters.bytes));
+
+        ui.collapsing("Channels", |ui| {
+            counter_grid(ui, "channel_metrics", &channels)
+        });
+        ui.collapsing("Plugins", |ui| counter_grid(ui, "plugin_metrics", &plugins));
+
+        ui.collapsing("Recent messages", |ui| {
+            for tapped in metrics.tap().collect::<Vec<_>>().into_iter().rev() {
+                let msg = &tapped.message;
+                let sender = tapped.sender.as_deref().unwrap_or("host");
+                ui.label(format!(
+                    "#{} {} -> {} ({} bytes)",
+                    tapped.tick,
+                    sender,
+                    msg.channel.id,
+                    msg.data.len()
+                ));
+
+                if let Ok(value) = engine.schemas().to_json(&msg.channel.id, &msg.data) {
+                    ui.label(egui::RichText::new(value.to_string()).monospace());
+                }
+            }
+        });
+    });
+}
+
+fn counter_grid(ui: &mut Ui, id: &str, rows: &[(&str, &MessageCounters)]) {
+    egui::Grid::new(id).striped(true).show(ui, |ui| {
+        let headers = [
+            "Name",
+            "Messages",
+            "Bytes",
+            "Dropped",
+            "Unread",
+            "Undelivered",
+        ];
+        for header in headers {
+            ui.strong(header);
+        }
+        ui.end_row();
+
+        for (name, counters) in rows {
+            ui.label(*name);
+            ui.label(counters.messages.to_string());
+            ui.label(counters.bytes.to_string());
+            ui.label(counters.dropped_sent.to_string());
+            ui.label(counters.dropped_unread.to_string());
+            ui.label(counters.undelivered.to_string());
+            ui.end_row();
+        }
+    });
+}
+
 impl Element {
     /// Returns `true` if the given state updated
     pub fn show(&mut self, ui: &mut Ui) -> bool {
@@ -65,6 +65,7 @@ struct MainLoop {
     _glutin_window: glutin::window::Window,
     plugin_interface: PluginVrInterfacing,
     login_info: LoginInfo,
+    interp_delay: f32,
 }
 
 impl MainLoop {
@@ -143,7 +144,7 @@ impl MainLoop {
             glutin_openxr_opengl_helper::session_create_info(&glutin_ctx, &glutin_window)?;
 
         // Setup client code
-        let client = Client::new(gl.clone(), login_info.clone())?;
+        let client = Client::new(gl.clone(), login_info.clone(), args.interp_delay)?;
 
         // Create session
         let (xr_session, xr_frame_waiter, xr_frame_stream) =
@@ -230,6 +231,7 @@ impl MainLoop {
         let inst = Self {
             client,
             login_info,
+            interp_delay: args.interp_delay,
             gl,
             gl_framebuffers,
             xr_frame_stream,
@@ -434,7 +436,11 @@ impl MainLoop {
         // Check for travel requests
         if let Some(travel_request) = self.client.travel_request() {
             self.login_info.address = travel_request.address;
-            self.client = Client::new(self.gl.clone(), self.login_info.clone())?;
+            self.client = Client::new(
+                self.gl.clone(),
+                self.login_info.clone(),
+                self.interp_delay,
+            )?;
         }
 
         Ok(true)
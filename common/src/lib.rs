@@ -6,12 +6,14 @@
 
 use cimvr_engine_interface::pkg_namespace;
 use cimvr_engine_interface::prelude::*;
+use cimvr_engine_interface::schema::{Field, FieldType, Fields, Schema, SchemaRegistry, Variant};
 pub use glam;
 use serde::{Deserialize, Serialize};
 
 pub mod desktop;
 pub mod gamepad;
 mod generic_handle;
+pub mod relevance;
 pub mod render;
 mod transform;
 pub mod ui;
@@ -19,7 +21,51 @@ pub mod utils;
 pub mod vr;
 
 pub use generic_handle::GenericHandle;
-pub use transform::Transform;
+pub use transform::{GlobalTransform, QuantizedTransform, Transform};
+
+/// Make the layouts of the components defined in this crate known to the host (or a plugin), so
+/// that their contents may be inspected, and loaded from scenes, even if no local plugin uses them
+pub fn register_schemas(registry: &mut SchemaRegistry) {
+    let components = [
+        Transform::schema(),
+        GlobalTransform::schema(),
+        render::Render::schema(),
+        render::RenderExtra::schema(),
+        render::CameraComponent::schema(),
+        relevance::Relevance::schema(),
+    ];
+    registry.extend(components.into_iter().flatten());
+
+    // Types nested within components, which do not derive a schema themselves
+    let handle = || Fields::Unnamed(vec![Field::new("0", FieldType::U128)]);
+    registry.insert(Schema::new_struct(
+        pkg_namespace!("MeshHandle"),
+        "MeshHandle",
+        handle(),
+    ));
+    registry.insert(Schema::new_struct(
+        pkg_namespace!("ShaderHandle"),
+        "ShaderHandle",
+        handle(),
+    ));
+
+    let unit = |name: &str| Variant {
+        name: name.into(),
+        fields: Fields::Unit,
+    };
+    let primitives = vec![unit("Points"), unit("Lines"), unit("Triangles")];
+    registry.insert(Schema::new_enum(
+        pkg_namespace!("Primitive"),
+        "Primitive",
+        primitives,
+    ));
+    let modes = vec![unit("Spatial"), unit("Always"), unit("OwnerOnly")];
+    registry.insert(Schema::new_enum(
+        pkg_namespace!("RelevanceMode"),
+        "RelevanceMode",
+        modes,
+    ));
+}
 
 /// Requests that the client disconnect from the current server in favor of this new server
 #[derive(Message, Serialize, Deserialize, Clone, Debug)]
@@ -27,3 +73,74 @@ pub use transform::Transform;
 pub struct InterdimensionalTravelRequest {
     pub address: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cimvr_engine_interface::schema::SchemaKind;
+    use cimvr_engine_interface::serial::{serialize, FixedOption};
+    use relevance::{Relevance, RelevanceMode};
+    use render::{MeshHandle, Primitive, ShaderHandle};
+    use std::fmt::Debug;
+
+    /// Checks that the schema with the given ID describes the encoding of `value`, which must be
+    /// the `variant`th variant if it is an enum
+    fn check<T: Serialize + Debug>(registry: &SchemaRegistry, id: &str, value: T, variant: usize) {
+        let data = serialize(&value).unwrap();
+        let json = registry.to_json(id, &data).unwrap();
+        assert_eq!(registry.from_json(id, &json).unwrap(), data, "{}", id);
+
+        let schema = registry.get(id).unwrap();
+        let (start, fields) = match &schema.kind {
+            SchemaKind::Struct(fields) => (0, fields),
+            SchemaKind::Enum(variants) => {
+                // Unit variants decode to their name
+                assert_eq!(json.as_str(), Some(format!("{:?}", value).as_str()));
+                (4, &variants[variant].fields)
+            }
+        };
+        let size: Option<u32> = fields.as_slice().iter().map(|field| field.ty.size()).sum();
+        assert_eq!(size, Some(data.len() as u32 - start), "{}", id);
+    }
+
+    #[test]
+    fn test_nested_schemas() {
+        let mut registry = SchemaRegistry::new();
+        register_schemas(&mut registry);
+
+        let mesh = MeshHandle::new("Mesh");
+        let shader = ShaderHandle::new("Shader");
+        check(&registry, pkg_namespace!("MeshHandle"), mesh, 0);
+        check(&registry, pkg_namespace!("ShaderHandle"), shader, 0);
+
+        let primitives = [Primitive::Points, Primitive::Lines, Primitive::Triangles];
+        for (idx, primitive) in primitives.into_iter().enumerate() {
+            check(&registry, pkg_namespace!("Primitive"), primitive, idx);
+        }
+
+        let modes = [
+            RelevanceMode::Spatial,
+            RelevanceMode::Always,
+            RelevanceMode::OwnerOnly,
+        ];
+        for (idx, mode) in modes.into_iter().enumerate() {
+            check(&registry, pkg_namespace!("RelevanceMode"), mode, idx);
+        }
+
+        // Components containing them decode as a whole
+        let render = render::Render {
+            id: mesh,
+            primitive: Primitive::Lines,
+            limit: FixedOption::some(3),
+            shader: FixedOption::some(shader),
+        };
+        let relevance = Relevance::owner_only(ClientId(7));
+        for (id, data) in [
+            (render::Render::ID, serialize(&render).unwrap()),
+            (Relevance::ID, serialize(&relevance).unwrap()),
+        ] {
+            let json = registry.to_json(id, &data).unwrap();
+            assert_eq!(registry.from_json(id, &json).unwrap(), data, "{}", id);
+        }
+    }
+}
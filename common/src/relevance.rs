@@ -0,0 +1,51 @@
+//! Types for controlling which clients `Synchronized` entities are sent to
+use cimvr_engine_interface::{pkg_namespace, prelude::*};
+use serde::{Deserialize, Serialize};
+
+/// Component overriding which clients a `Synchronized` entity is replicated to.
+///
+/// By default, the server only sends an entity to the clients whose viewpoint lies within the
+/// server's area of interest around the entity's `Transform`. Entities without a `Transform` are
+/// always sent to every client.
+#[derive(Component, Serialize, Deserialize, Default, Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Relevance {
+    /// How relevance is determined
+    pub mode: RelevanceMode,
+    /// Owning client; only used with `RelevanceMode::OwnerOnly`
+    pub owner: ClientId,
+}
+
+/// Determines which clients an entity is relevant to
+#[derive(Serialize, Deserialize, Default, Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum RelevanceMode {
+    /// Relevant to clients whose viewpoint is nearby
+    #[default]
+    Spatial,
+    /// Relevant to all clients, regardless of distance
+    Always,
+    /// Only relevant to the owning client
+    OwnerOnly,
+}
+
+impl Relevance {
+    /// Relevant to clients whose viewpoint is nearby (the default)
+    pub fn spatial() -> Self {
+        Self::default()
+    }
+
+    /// Relevant to all clients, regardless of distance
+    pub fn always() -> Self {
+        Self {
+            mode: RelevanceMode::Always,
+            ..Default::default()
+        }
+    }
+
+    /// Only relevant to the given client
+    pub fn owner_only(owner: ClientId) -> Self {
+        Self {
+            mode: RelevanceMode::OwnerOnly,
+            owner,
+        }
+    }
+}
@@ -82,6 +82,90 @@ impl Transform {
     }
 }
 
+/// # Component representing an entity's world-space position and orientation
+///
+/// Computed by the host from the entity's `Transform` and those of its ancestors (see `Parent`),
+/// after each stage. Entities without a parent simply have a copy of their `Transform`. Plugins
+/// should treat this as read-only.
+#[derive(Component, Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Default)]
+pub struct GlobalTransform(pub Transform);
+
+/// Compact encoding of a `Transform`, suitable for sending over the network.
+///
+/// The position is kept at full precision, while the orientation is stored as the three smallest
+/// components of the (normalized) quaternion at 16 bits each; the largest is recovered from the
+/// other three.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub struct QuantizedTransform {
+    /// Position
+    pub pos: [f32; 3],
+    /// The three smallest quaternion components, scaled to the range of an i16
+    pub orient: [i16; 3],
+    /// Index of the omitted (largest) quaternion component
+    pub largest: u8,
+}
+
+/// Quaternion components other than the largest lie within +/- 1/sqrt(2)
+const QUAT_COMPONENT_RANGE: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+impl From<Transform> for QuantizedTransform {
+    fn from(transf: Transform) -> Self {
+        let mut q = transf.orient.normalize().to_array();
+
+        let largest = (0..4)
+            .max_by(|&a, &b| q[a].abs().total_cmp(&q[b].abs()))
+            .unwrap();
+
+        // q and -q are the same rotation; make the omitted component positive
+        if q[largest] < 0. {
+            q.iter_mut().for_each(|c| *c = -*c);
+        }
+
+        let mut orient = [0; 3];
+        let others = (0..4).filter(|&i| i != largest);
+        for (out, i) in orient.iter_mut().zip(others) {
+            *out = (q[i] / QUAT_COMPONENT_RANGE * f32::from(i16::MAX)).round() as i16;
+        }
+
+        Self {
+            pos: transf.pos.to_array(),
+            orient,
+            largest: largest as u8,
+        }
+    }
+}
+
+impl From<QuantizedTransform> for Transform {
+    fn from(quant: QuantizedTransform) -> Self {
+        let largest = usize::from(quant.largest.min(3));
+
+        let mut q = [0.; 4];
+        let others = (0..4).filter(|&i| i != largest);
+        for (&c, i) in quant.orient.iter().zip(others) {
+            q[i] = f32::from(c) / f32::from(i16::MAX) * QUAT_COMPONENT_RANGE;
+        }
+        q[largest] = (1. - q.iter().map(|c| c * c).sum::<f32>()).max(0.).sqrt();
+
+        Self {
+            pos: Vec3::from_array(quant.pos),
+            orient: Quat::from_array(q).normalize(),
+        }
+    }
+}
+
+impl From<Transform> for GlobalTransform {
+    fn from(transf: Transform) -> Self {
+        Self(transf)
+    }
+}
+
+impl Mul<Transform> for GlobalTransform {
+    type Output = Self;
+    fn mul(self, rhs: Transform) -> Self::Output {
+        Self(self.0 * rhs)
+    }
+}
+
 impl Into<Mat4> for Transform {
     fn into(self) -> Mat4 {
         Mat4::from_quat(self.orient) * Mat4::from_translation(self.pos)
@@ -1,24 +1,65 @@
 use ahash::{HashSet, HashSetExt};
-use anyhow::Result;
+use anyhow::{bail, Result};
 use cimvr_engine_interface::{
     component_id,
     prelude::*,
+    scene::{Scene, SceneEntity},
+    schema::SchemaRegistry,
     serial::{deserialize, serialize, EcsData},
+    Parent,
 };
 use rand::prelude::*;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 
-use crate::PluginIndex;
+use crate::{replication::Replication, PluginIndex};
 
 // TODO: FxHash
 
 pub type ComponentData = Vec<u8>;
 pub type EcsMap = HashMap<ComponentId, HashMap<EntityId, ComponentData>>;
 
+/// Number of deleted entity IDs remembered in order to detect stale IDs
+const DESPAWN_LOG_LEN: usize = 1 << 16;
+
 /// Rather poor ECS implementation for prototyping
+#[derive(Clone)]
 pub struct Ecs {
     map: EcsMap,
     entities: HashSet<EntityId>,
+    replication: Replication,
+    despawned: DespawnLog,
+    collisions: ImportCollisions,
+}
+
+/// Whether an entity ID refers to a live entity
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EntityStatus {
+    /// The entity exists
+    Alive,
+    /// The entity existed, but has since been deleted
+    Despawned,
+    /// The entity never existed, or was deleted long enough ago to be forgotten
+    Unknown,
+}
+
+/// Imported entities which were refused because they collided with a local entity
+#[derive(Default, Clone)]
+struct ImportCollisions {
+    /// Colliding IDs which have already been logged. Forgotten once the local entity is deleted
+    reported: HashSet<EntityId>,
+    /// Number of imported entities refused, including repeats
+    count: u64,
+}
+
+/// Log of recently deleted entities
+#[derive(Default, Clone)]
+struct DespawnLog {
+    /// Deleted entities, oldest first
+    order: VecDeque<EntityId>,
+    /// Deleted entities, for fast lookup
+    set: HashSet<EntityId>,
+    /// Entities deleted since the last call to `Ecs::drain_despawned()`
+    recent: Vec<EntityId>,
 }
 
 impl Ecs {
@@ -27,6 +68,9 @@ impl Ecs {
         Self {
             map: HashMap::new(),
             entities: HashSet::new(),
+            replication: Replication::new(),
+            despawned: DespawnLog::default(),
+            collisions: ImportCollisions::default(),
         }
     }
 
@@ -74,29 +118,127 @@ impl Ecs {
     }
     */
 
-    /// Import an entity ID from elsewhere
-    pub fn import_entity(&mut self, id: EntityId) {
-        self.entities.insert(id);
+    /// Import an entity ID from elsewhere. Fails if the ID is already in use, or belonged to a
+    /// recently deleted entity
+    pub fn import_entity(&mut self, id: EntityId) -> Result<()> {
+        match self.entity_status(id) {
+            EntityStatus::Alive => bail!("Entity {:?} already exists", id),
+            EntityStatus::Despawned => bail!("Entity {:?} was deleted, and cannot be reused", id),
+            EntityStatus::Unknown => {
+                self.entities.insert(id);
+                Ok(())
+            }
+        }
     }
 
-    /// Remove an existing entity
+    /// Remove an existing entity, along with its descendants (see `Parent`)
     pub fn remove_entity(&mut self, id: EntityId) {
+        self.remove_entities(&[id]);
+    }
+
+    /// Remove existing entities, along with their descendants (see `Parent`)
+    pub fn remove_entities(&mut self, ids: &[EntityId]) {
+        let mut stack = vec![];
+        for &id in ids {
+            if self.despawn(id) {
+                stack.push(id);
+            } else {
+                log::warn!("Attempted to remove non-existant entity {:#?}", id);
+            }
+        }
+        if stack.is_empty() {
+            return;
+        }
+
+        // Removing entities never gives others new children, so this is computed once
+        let children = self.children_by_parent();
+        while let Some(entity) = stack.pop() {
+            for &child in children.get(&entity).into_iter().flatten() {
+                // Entities already removed (e.g. in a cycle of parents) are skipped
+                if self.despawn(child) {
+                    stack.push(child);
+                }
+            }
+        }
+    }
+
+    /// Remove a single entity, returning `false` if it did not exist
+    fn despawn(&mut self, id: EntityId) -> bool {
         for component in self.map.values_mut() {
             component.remove(&id);
         }
 
         let did_remove = self.entities.remove(&id);
-        if !did_remove {
-            log::warn!("Attempted to remove non-existant entity {:#?}", id);
+        if did_remove {
+            self.despawned.push(id);
+            self.collisions.reported.remove(&id);
         }
+        did_remove
+    }
+
+    /// Entities with a `Parent`, grouped by their parent
+    fn children_by_parent(&self) -> HashMap<EntityId, Vec<EntityId>> {
+        let mut children: HashMap<EntityId, Vec<EntityId>> = HashMap::new();
+        let Some(parents) = self.map.get(&component_id::<Parent>()) else {
+            return children;
+        };
+        for (&child, data) in parents {
+            if let Ok(Parent(parent)) = deserialize(data.as_slice()) {
+                children.entry(parent).or_default().push(child);
+            }
+        }
+        children
+    }
+
+    /// All live entities, in no particular order
+    pub fn entities(&self) -> impl Iterator<Item = EntityId> + '_ {
+        self.entities.iter().copied()
+    }
+
+    /// Components attached to the given entity, in no particular order
+    pub fn components(&self, id: EntityId) -> Vec<ComponentId> {
+        self.map
+            .iter()
+            .filter(|(_, data)| data.contains_key(&id))
+            .map(|(component, _)| component.clone())
+            .collect()
     }
 
     /// Create a new entity
     pub fn create_entity(&mut self) -> EntityId {
-        // TODO: While new entity id is not in entities loop...
-        let id = EntityId(rand::thread_rng().gen());
-        self.entities.insert(id);
-        id
+        loop {
+            let id = EntityId(rand::thread_rng().gen());
+            if self.entity_status(id) == EntityStatus::Unknown {
+                self.entities.insert(id);
+                break id;
+            }
+        }
+    }
+
+    /// Determine whether the given ID refers to a live entity, or to one which was deleted
+    pub fn entity_status(&self, id: EntityId) -> EntityStatus {
+        if self.entities.contains(&id) {
+            EntityStatus::Alive
+        } else if self.despawned.set.contains(&id) {
+            EntityStatus::Despawned
+        } else {
+            EntityStatus::Unknown
+        }
+    }
+
+    /// Returns `true` if the given entity exists
+    pub fn is_alive(&self, id: EntityId) -> bool {
+        self.entities.contains(&id)
+    }
+
+    /// Number of imported entities refused so far because they collided with a local entity
+    pub fn import_collisions(&self) -> u64 {
+        self.collisions.count
+    }
+
+    /// Take the list of entities deleted since the last call to this function
+    pub fn drain_despawned(&mut self) -> Vec<EntityId> {
+        std::mem::take(&mut self.despawned.recent)
     }
 
     /// Convenient add component
@@ -197,6 +339,16 @@ impl Ecs {
             .iter_mut()
     }
 
+    /// Other layouts of the given component which have data in the ECS, e.g. data saved before a
+    /// field was added to the component
+    pub fn stale_layouts(&self, component: &ComponentId) -> Vec<ComponentId> {
+        self.map
+            .keys()
+            .filter(|other| other.id == component.id && other.size != component.size)
+            .cloned()
+            .collect()
+    }
+
     /*
     /// Get all data associated with a component
     pub get_all(&mut self, component: ComponentId) -> impl Iterator<Item=(EntityId, &ComponentData)> {
@@ -240,11 +392,21 @@ impl Ecs {
     }
     */
 
+    /// Replication policies for component data exported by this ECS
+    pub fn replication(&mut self) -> &mut Replication {
+        &mut self.replication
+    }
+
+    /// Export the queried entities, including only components which are replicated
     pub fn export(&mut self, query: &Query) -> EcsMap {
         let entities: Vec<EntityId> = self.query(query).into_iter().collect();
 
         let mut exp = EcsMap::new();
         for (id, comp) in &mut self.map {
+            if !self.replication.replicates(id) {
+                continue;
+            }
+
             let map = exp.entry(id.clone()).or_default();
             for ent in &entities {
                 if let Some(data) = comp.get(ent) {
@@ -256,21 +418,178 @@ impl Ecs {
         exp
     }
 
+    /// Replace the entities matching the given query with the imported ones. Imported entities may
+    /// not share an ID with local entities outside of the query
     pub fn import(&mut self, query: &Query, imported: EcsMap) {
-        // Remove existing entities in the given query
-        let entities: Vec<EntityId> = self.query(query).into_iter().collect();
-        for id in entities {
-            self.remove_entity(id);
+        let incoming: HashSet<EntityId> = imported
+            .values()
+            .flat_map(|comp| comp.keys().copied())
+            .collect();
+        let existing = self.query(query);
+
+        // Refuse to merge into unrelated local entities
+        let collisions: HashSet<EntityId> = incoming
+            .iter()
+            .copied()
+            .filter(|ent| self.entities.contains(ent) && !existing.contains(ent))
+            .collect();
+        self.collisions.count += collisions.len() as u64;
+        for &ent in &collisions {
+            // The remote keeps sending the same entities, so only report each one once
+            if self.collisions.reported.insert(ent) {
+                log::error!("Imported entity {:?} collides with a local entity", ent);
+            }
+        }
+
+        // Remove existing entities in the given query. Those missing from the import were deleted
+        for id in existing {
+            if incoming.contains(&id) {
+                for component in self.map.values_mut() {
+                    component.remove(&id);
+                }
+            } else {
+                self.despawn(id);
+            }
         }
 
         // Add component data from import
         for (id, import_comp) in imported {
             let my_comp = self.map.entry(id).or_default();
             for (ent, data) in import_comp {
+                if collisions.contains(&ent) {
+                    continue;
+                }
                 my_comp.insert(ent, data);
                 self.entities.insert(ent);
             }
         }
+
+        // The remote is authoritative; entities it brings back are no longer deleted
+        for ent in incoming {
+            self.despawned.forget(ent);
+        }
+    }
+
+    /// Export the client-writable components of the entities owned by the given client
+    pub fn export_owned(&mut self, client: ClientId) -> EcsMap {
+        let owned: Vec<EntityId> = self
+            .query(&Query::new().intersect::<ClientOwned>(Access::Read))
+            .into_iter()
+            .filter(|&entity| self.get::<ClientOwned>(entity) == Some(ClientOwned(client)))
+            .collect();
+
+        let mut exp = EcsMap::new();
+        for (id, comp) in &self.map {
+            if !self.replication.policy(id).client_writable {
+                continue;
+            }
+
+            let map = exp.entry(id.clone()).or_default();
+            for ent in &owned {
+                if let Some(data) = comp.get(ent) {
+                    map.insert(*ent, data.clone());
+                }
+            }
+        }
+
+        exp
+    }
+
+    /// Apply writes made by the given client, discarding those to entities it does not own and to
+    /// components which are not client-writable
+    pub fn import_owned(&mut self, client: ClientId, imported: EcsMap) {
+        for (id, import_comp) in imported {
+            if !self.replication.policy(&id).client_writable {
+                log::trace!("{:?} may not write to {:?}", client, id);
+                continue;
+            }
+
+            for (ent, data) in import_comp {
+                if self.get::<ClientOwned>(ent) != Some(ClientOwned(client)) {
+                    log::trace!("{:?} wrote to {:?}, which it does not own", client, ent);
+                    continue;
+                }
+
+                if data.len() != usize::from(id.size) {
+                    log::warn!("{:?} wrote malformed data to {:?}", client, id);
+                    continue;
+                }
+
+                self.add_component_raw(ent, &id, &data);
+            }
+        }
+    }
+
+    /// Create the entities described by the given scene, returning their IDs
+    pub fn load_scene(
+        &mut self,
+        scene: &Scene,
+        registry: &SchemaRegistry,
+    ) -> Result<Vec<EntityId>> {
+        let decoded = scene.decode(registry, || self.create_entity())?;
+
+        let mut entities = vec![];
+        for (entity, components) in decoded {
+            for (component, data) in components {
+                self.add_component_raw(entity, &component, &data);
+            }
+            entities.push(entity);
+        }
+
+        Ok(entities)
+    }
+
+    /// Describe the queried entities as a scene. Components without a schema are omitted
+    pub fn save_scene(&mut self, query: &Query, registry: &SchemaRegistry) -> Scene {
+        let mut entities: Vec<EntityId> = self.query(query).into_iter().collect();
+        entities.sort_by_key(|entity| entity.0);
+
+        let mut scene = Scene::default();
+        for entity in entities {
+            let mut components = BTreeMap::new();
+            for (id, comp) in &self.map {
+                let Some(data) = comp.get(&entity) else { continue };
+                if registry.get(&id.id).is_none() {
+                    log::debug!("Omitting component {:?} without a schema from scene", id.id);
+                    continue;
+                }
+
+                match registry.to_json(&id.id, data) {
+                    Ok(value) => {
+                        components.insert(id.id.clone(), value);
+                    }
+                    Err(e) => log::error!("Failed to save {:?} of {:?}; {}", id.id, entity, e),
+                }
+            }
+
+            scene.entities.push(SceneEntity {
+                id: Some(entity),
+                components,
+            });
+        }
+
+        scene
+    }
+}
+
+impl DespawnLog {
+    fn push(&mut self, id: EntityId) {
+        if self.set.insert(id) {
+            self.order.push_back(id);
+        }
+        self.recent.push(id);
+
+        while self.order.len() > DESPAWN_LOG_LEN {
+            if let Some(oldest) = self.order.pop_front() {
+                self.set.remove(&oldest);
+            }
+        }
+    }
+
+    fn forget(&mut self, id: EntityId) {
+        if self.set.remove(&id) {
+            self.order.retain(|&ent| ent != id);
+        }
     }
 }
 
@@ -301,14 +620,22 @@ pub fn apply_ecs_commands(
     commands: &[EcsCommand],
     plugin_idx: PluginIndex,
 ) -> Result<()> {
+    // Entities which could not be created; further commands on them would affect other entities
+    let mut rejected = HashSet::new();
+
     // Apply commands
     for command in commands {
         // TODO: Throw error on modification of non-queried data...
         match command {
-            EcsCommand::Create(id) => {
-                ecs.import_entity(*id);
-                ecs.add_component(*id, &plugin_idx);
-            }
+            EcsCommand::Create(id) => match ecs.import_entity(*id) {
+                Ok(()) => ecs.add_component(*id, &plugin_idx),
+                Err(e) => {
+                    log::error!("{:?} failed to create entity; {:#}", plugin_idx, e);
+                    rejected.insert(*id);
+                }
+            },
+            EcsCommand::Delete(id) | EcsCommand::AddComponent(id, _, _)
+                if rejected.contains(id) => {}
             EcsCommand::Delete(id) => ecs.remove_entity(*id),
             EcsCommand::AddComponent(entity, component, data) => {
                 ecs.add_component_raw(*entity, component, data)
@@ -322,6 +649,7 @@ pub fn apply_ecs_commands(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use cimvr_engine_interface::Saved;
 
     #[test]
     fn test_ecs_basic() {
@@ -336,10 +664,7 @@ mod tests {
         let e = ecs.create_entity();
         ecs.add_component_raw(e, &comp_a, &test_val.to_le_bytes());
 
-        let entities = ecs.query(&[QueryComponent {
-            component: comp_a.clone(),
-            access: Access::Read,
-        }]);
+        let entities = ecs.query(&Query::new().intersect_raw(comp_a.clone(), Access::Read));
 
         for ent in entities {
             let buf = ecs.get_raw(ent, &comp_a);
@@ -371,16 +696,11 @@ mod tests {
             ecs.add_component_raw(e, &comp_a, &0x1337_3621_0420_6969_u64.to_le_bytes());
         }
 
-        let entities = ecs.query(&[
-            QueryComponent {
-                component: comp_a.clone(),
-                access: Access::Read.clone(),
-            },
-            QueryComponent {
-                component: comp_b.clone(),
-                access: Access::Read,
-            },
-        ]);
+        let entities = ecs.query(
+            &Query::new()
+                .intersect_raw(comp_a.clone(), Access::Read)
+                .intersect_raw(comp_b.clone(), Access::Read),
+        );
 
         let mut showed_up = vec![false; 50];
         for ent in entities {
@@ -393,19 +713,156 @@ mod tests {
         assert!(showed_up.iter().all(|&v| v), "But it was my birthday!!");
 
         let n_comp_a = ecs
-            .query(&[QueryComponent {
-                component: comp_a,
-                access: Access::Read,
-            }])
+            .query(&Query::new().intersect_raw(comp_a, Access::Read))
             .len();
         assert_eq!(n_comp_a, 100);
 
         let n_comp_b = ecs
-            .query(&[QueryComponent {
-                component: comp_b,
-                access: Access::Read,
-            }])
+            .query(&Query::new().intersect_raw(comp_b, Access::Read))
             .len();
         assert_eq!(n_comp_b, 50);
     }
+
+    #[test]
+    fn test_ecs_client_owned() {
+        let mut ecs = Ecs::new();
+
+        let comp = ComponentId {
+            id: "client_owned_test".into(),
+            size: 8,
+        };
+        ecs.replication()
+            .set_policy_raw(comp.clone(), ReplicationPolicy::default().client_writable());
+
+        let mine = ecs.create_entity();
+        ecs.add_component(mine, &ClientOwned(ClientId(1)));
+        let theirs = ecs.create_entity();
+        ecs.add_component(theirs, &ClientOwned(ClientId(2)));
+
+        for ent in [mine, theirs] {
+            ecs.add_component_raw(ent, &comp, &0_u64.to_le_bytes());
+        }
+
+        // Client 1 attempts to write to both entities, and to a component it may not write to
+        let mut writes = EcsMap::new();
+        for ent in [mine, theirs] {
+            writes
+                .entry(comp.clone())
+                .or_default()
+                .insert(ent, 5_u64.to_le_bytes().to_vec());
+            writes
+                .entry(component_id::<ClientOwned>())
+                .or_default()
+                .insert(ent, serialize(&ClientOwned(ClientId(1))).unwrap());
+        }
+        ecs.import_owned(ClientId(1), writes);
+
+        assert_eq!(ecs.get_raw(mine, &comp), Some(&5_u64.to_le_bytes()[..]));
+        assert_eq!(ecs.get_raw(theirs, &comp), Some(&0_u64.to_le_bytes()[..]));
+        let owner = ecs.get::<ClientOwned>(theirs);
+        assert_eq!(owner, Some(ClientOwned(ClientId(2))));
+
+        let exported = ecs.export_owned(ClientId(1));
+        assert_eq!(exported[&comp].len(), 1);
+        assert!(exported[&comp].contains_key(&mine));
+        assert!(!exported.contains_key(&component_id::<ClientOwned>()));
+    }
+
+    #[test]
+    fn test_ecs_entity_collisions() {
+        let mut ecs = Ecs::new();
+
+        let a = ecs.create_entity();
+        assert_eq!(ecs.entity_status(a), EntityStatus::Alive);
+        assert!(ecs.import_entity(a).is_err());
+
+        // Deleted IDs are recognized as stale, and cannot be reused
+        ecs.remove_entity(a);
+        assert_eq!(ecs.entity_status(a), EntityStatus::Despawned);
+        assert!(!ecs.is_alive(a));
+        assert!(ecs.import_entity(a).is_err());
+        assert_eq!(ecs.drain_despawned(), vec![a]);
+        assert!(ecs.drain_despawned().is_empty());
+
+        let b = EntityId(1234);
+        assert_eq!(ecs.entity_status(b), EntityStatus::Unknown);
+        assert!(ecs.import_entity(b).is_ok());
+
+        // A second plugin creating the same entity must not touch the first one's data
+        let commands = [
+            EcsCommand::Create(b),
+            EcsCommand::AddComponent(b, component_id::<Saved>(), vec![]),
+            EcsCommand::Delete(b),
+        ];
+        apply_ecs_commands(&mut ecs, &commands, PluginIndex(1)).unwrap();
+        assert!(ecs.is_alive(b));
+        assert_eq!(ecs.get::<Saved>(b), None);
+        assert_eq!(ecs.get::<PluginIndex>(b), None);
+    }
+
+    #[test]
+    fn test_ecs_import_collisions() {
+        let mut ecs = Ecs::new();
+        let query = Query::new().intersect::<Synchronized>(Access::Read);
+
+        let local = ecs.create_entity();
+        ecs.add_component(local, &Saved);
+
+        let remote = EntityId(99);
+        let mut imported = EcsMap::new();
+        let sync = imported.entry(component_id::<Synchronized>()).or_default();
+        sync.insert(local, vec![]);
+        sync.insert(remote, vec![]);
+
+        // Local entities sharing an ID with remote ones are left alone
+        ecs.import(&query, imported.clone());
+        assert!(ecs.is_alive(remote));
+        assert_eq!(ecs.get::<Synchronized>(local), None);
+        assert_eq!(ecs.get::<Saved>(local), Some(Saved));
+        assert_eq!(ecs.import_collisions(), 1);
+
+        // Repeated collisions are counted, but only reported once
+        ecs.import(&query, imported.clone());
+        assert_eq!(ecs.import_collisions(), 2);
+        assert_eq!(ecs.collisions.reported.len(), 1);
+
+        // Entities missing from the next import were deleted
+        ecs.import(&query, EcsMap::new());
+        assert_eq!(ecs.entity_status(remote), EntityStatus::Despawned);
+
+        // ... but the remote may bring them back
+        ecs.import(&query, imported);
+        assert!(ecs.is_alive(remote));
+    }
+
+    #[test]
+    fn test_ecs_scene_roundtrip() {
+        let registry = SchemaRegistry::new();
+        let json = r#"{
+            "entities": [
+                { "id": 7, "components": { "cimvr_engine_interface/Saved": null } },
+                { "components": { "cimvr_engine_interface/Parent": 7 } }
+            ]
+        }"#;
+        let scene = Scene::from_json(json).unwrap();
+
+        // Parents are remapped to the newly created entities
+        let mut ecs = Ecs::new();
+        let entities = ecs.load_scene(&scene, &registry).unwrap();
+        assert_eq!(entities.len(), 2);
+        assert_ne!(entities[0], EntityId(7));
+        assert_eq!(ecs.get::<Parent>(entities[1]), Some(Parent(entities[0])));
+
+        // Saving and reloading preserves the structure
+        ecs.add_component(entities[1], &PluginIndex(0));
+        let saved = ecs.save_scene(&Query::new().intersect::<Parent>(Access::Read), &registry);
+        assert_eq!(saved.entities.len(), 1);
+        assert_eq!(saved.entities[0].components.len(), 1);
+        let json = saved.to_json().unwrap();
+        assert_eq!(Scene::from_json(&json).unwrap(), saved);
+
+        // Unknown components are rejected
+        let scene = Scene::from_json(r#"{"entities":[{"components":{"nope/Nope":null}}]}"#);
+        assert!(ecs.load_scene(&scene.unwrap(), &registry).is_err());
+    }
 }
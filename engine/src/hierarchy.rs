@@ -0,0 +1,206 @@
+use std::collections::{HashMap, HashSet};
+use std::ops::Mul;
+
+use anyhow::Result;
+use cimvr_engine_interface::{
+    component_id,
+    prelude::*,
+    serial::{deserialize, serialize},
+    Parent,
+};
+
+use crate::ecs::Ecs;
+
+/// Computes a global component (e.g. a world-space transform) for each entity from its local
+/// component and the global component of its `Parent`
+pub struct Hierarchy {
+    /// Component set by plugins, relative to the parent
+    local: ComponentId,
+    /// Component computed by the host
+    global: ComponentId,
+    /// Computes the global component of an entity without a parent
+    root: fn(&[u8]) -> Result<Vec<u8>>,
+    /// Computes the global component of a child from its parent's global component
+    child: fn(&[u8], &[u8]) -> Result<Vec<u8>>,
+    /// Entities found to be part of (or descended from) a cycle during the last update
+    cycles: HashSet<EntityId>,
+}
+
+impl Hierarchy {
+    /// Compute `G` from `L` for each entity; children receive `parent_global * local`
+    pub fn new<L, G>() -> Self
+    where
+        L: Component,
+        G: Component + From<L> + Mul<L, Output = G>,
+    {
+        Self {
+            local: component_id::<L>(),
+            global: component_id::<G>(),
+            root: root_global::<L, G>,
+            child: child_global::<L, G>,
+            cycles: HashSet::new(),
+        }
+    }
+
+    /// The component computed by the host
+    pub fn global(&self) -> &ComponentId {
+        &self.global
+    }
+
+    /// Recompute the global component of every entity, parents first
+    pub fn update(&mut self, ecs: &mut Ecs) {
+        let entities = ecs.query(&Query::new().intersect_raw(self.local.clone(), Access::Read));
+
+        // Entities whose parent lacks the local component are treated as roots
+        let mut roots = vec![];
+        let mut children: HashMap<EntityId, Vec<EntityId>> = HashMap::new();
+        for &entity in &entities {
+            match ecs.get::<Parent>(entity) {
+                Some(Parent(parent)) if entities.contains(&parent) => {
+                    children.entry(parent).or_default().push(entity)
+                }
+                _ => roots.push(entity),
+            }
+        }
+
+        // Depth-first from the roots, so that parents are always computed before their children
+        let mut visited = HashSet::new();
+        let mut stack: Vec<(EntityId, Option<Vec<u8>>)> =
+            roots.into_iter().map(|entity| (entity, None)).collect();
+
+        while let Some((entity, parent)) = stack.pop() {
+            if !visited.insert(entity) {
+                continue;
+            }
+
+            let Some(global) = self.compute(ecs, entity, parent.as_deref()) else { continue };
+
+            for &child in children.get(&entity).into_iter().flatten() {
+                stack.push((child, Some(global.clone())));
+            }
+        }
+
+        // Anything left over is part of a cycle, or descends from one
+        let cycles: HashSet<EntityId> = entities
+            .into_iter()
+            .filter(|entity| !visited.contains(entity))
+            .collect();
+
+        for &entity in &cycles {
+            if !self.cycles.contains(&entity) {
+                log::warn!("Entity {:?} is part of a cycle of parents", entity);
+            }
+            self.compute(ecs, entity, None);
+        }
+
+        self.cycles = cycles;
+    }
+
+    /// Compute and store the global component of the given entity
+    fn compute(&self, ecs: &mut Ecs, entity: EntityId, parent: Option<&[u8]>) -> Option<Vec<u8>> {
+        let local = ecs.get_raw(entity, &self.local)?;
+
+        let global = match parent {
+            Some(parent) => (self.child)(parent, local),
+            None => (self.root)(local),
+        };
+
+        match global {
+            Ok(global) => {
+                ecs.add_component_raw(entity, &self.global, &global);
+                Some(global)
+            }
+            Err(e) => {
+                log::error!("Failed to compute {:?} of {:?}; {:#}", self.global.id, entity, e);
+                None
+            }
+        }
+    }
+}
+
+fn root_global<L, G>(local: &[u8]) -> Result<Vec<u8>>
+where
+    L: Component,
+    G: Component + From<L>,
+{
+    let local: L = deserialize(local)?;
+    Ok(serialize(&G::from(local))?)
+}
+
+fn child_global<L, G>(parent: &[u8], local: &[u8]) -> Result<Vec<u8>>
+where
+    L: Component,
+    G: Component + Mul<L, Output = G>,
+{
+    let parent: G = deserialize(parent)?;
+    let local: L = deserialize(local)?;
+    Ok(serialize(&(parent * local))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cimvr_engine_interface::pkg_namespace;
+    use serde::{Deserialize, Serialize};
+
+    /// Scale relative to the parent
+    #[derive(Component, Serialize, Deserialize, Default, Copy, Clone, Debug, PartialEq)]
+    struct Scale(i32);
+
+    /// Scale relative to the world
+    #[derive(Component, Serialize, Deserialize, Default, Copy, Clone, Debug, PartialEq)]
+    struct GlobalScale(i32);
+
+    impl From<Scale> for GlobalScale {
+        fn from(Scale(x): Scale) -> Self {
+            Self(x)
+        }
+    }
+
+    impl Mul<Scale> for GlobalScale {
+        type Output = Self;
+        fn mul(self, Scale(x): Scale) -> Self {
+            Self(self.0 * x)
+        }
+    }
+
+    #[test]
+    fn test_hierarchy_propagation() {
+        let mut ecs = Ecs::new();
+        let mut hierarchy = Hierarchy::new::<Scale, GlobalScale>();
+
+        // Create a chain of entities, children first
+        let mut chain = vec![];
+        for factor in [2, 3, 5, 7, 11] {
+            let entity = ecs.create_entity();
+            ecs.add_component(entity, &Scale(factor));
+            if let Some(&child) = chain.last() {
+                ecs.add_component(child, &Parent(entity));
+            }
+            chain.push(entity);
+        }
+
+        hierarchy.update(&mut ecs);
+        assert_eq!(ecs.get::<GlobalScale>(chain[0]), Some(GlobalScale(2310)));
+        assert_eq!(ecs.get::<GlobalScale>(chain[3]), Some(GlobalScale(77)));
+        assert_eq!(ecs.get::<GlobalScale>(chain[4]), Some(GlobalScale(11)));
+
+        // Close the loop; entities in the cycle fall back to their local component
+        ecs.add_component(chain[4], &Parent(chain[0]));
+        hierarchy.update(&mut ecs);
+        assert_eq!(hierarchy.cycles.len(), 5);
+        assert_eq!(ecs.get::<GlobalScale>(chain[0]), Some(GlobalScale(2)));
+
+        // Deleting the root of a subtree deletes its descendants
+        ecs.remove_component(chain[4], &component_id::<Parent>());
+        ecs.remove_entity(chain[2]);
+        assert!(ecs.is_alive(chain[4]) && ecs.is_alive(chain[3]));
+        assert!(chain[..3].iter().all(|&entity| !ecs.is_alive(entity)));
+
+        // Entities may be removed along with their descendants in one go
+        let leaf = ecs.create_entity();
+        ecs.add_component(leaf, &Parent(chain[3]));
+        ecs.remove_entities(&[chain[3], chain[4]]);
+        assert!(!ecs.is_alive(chain[3]) && !ecs.is_alive(chain[4]) && !ecs.is_alive(leaf));
+    }
+}
@@ -1,18 +1,48 @@
-use ahash::HashSet;
+use ahash::{HashMap, HashSet};
 use anyhow::{format_err, Context, Result};
 use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
 use std::{
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::mpsc::{channel, Receiver},
+    time::{Duration, Instant},
 };
 
+/// Default time a plugin file must go without changes before it is (re)loaded. A single build
+/// usually writes the file several times in quick succession
+pub const DEFAULT_SETTLE_TIME: Duration = Duration::from_millis(300);
+
+/// A change to a plugin file, reported once the file has settled
+#[derive(Debug)]
+pub enum HotloadEvent {
+    /// The plugin was created or modified, and now contains this complete WASM module
+    Load(PathBuf, Vec<u8>),
+    /// The plugin was deleted from a watched directory
+    Unload(PathBuf),
+}
+
+/// Watches plugin files for changes
 pub struct Hotloader {
-    _watcher: RecommendedWatcher,
+    watcher: RecommendedWatcher,
     rx: Receiver<PathBuf>,
+    /// Individually watched plugins
     paths: HashSet<PathBuf>,
+    /// Directories in which all `.wasm` files are watched
+    dirs: HashSet<PathBuf>,
+    /// Changes which have yet to settle
+    debounce: Debounce,
+    /// Used to validate modules before reporting them
+    wasm: wasmtime::Engine,
+}
+
+/// Holds back changed paths until they have gone unchanged for the settle time
+struct Debounce {
+    settle_time: Duration,
+    /// Changed paths, and when they last changed
+    pending: HashMap<PathBuf, Instant>,
 }
 
 impl Hotloader {
+    /// Watch the given plugin files
     pub fn new(plugins: &[PathBuf]) -> Result<Self> {
         let paths: HashSet<PathBuf> = plugins
             .iter()
@@ -42,16 +72,138 @@ impl Hotloader {
         Ok(Self {
             rx,
             paths,
-            _watcher: watcher,
+            dirs: HashSet::default(),
+            debounce: Debounce::new(DEFAULT_SETTLE_TIME),
+            wasm: wasmtime::Engine::default(),
+            watcher,
         })
     }
 
-    pub fn hotload(&mut self) -> Result<HashSet<PathBuf>> {
-        Ok(self
-            .rx
-            .try_iter()
-            .filter_map(|p| p.canonicalize().ok())
-            .filter(|p| self.paths.contains(p))
-            .collect())
+    /// Set the time a plugin file must go without changes before it is (re)loaded
+    pub fn with_settle_time(mut self, settle_time: Duration) -> Self {
+        self.debounce.settle_time = settle_time;
+        self
+    }
+
+    /// Also watch all `.wasm` files in the given directory, including ones added or deleted later
+    pub fn watch_dir(&mut self, dir: &Path) -> Result<()> {
+        let dir = dir
+            .canonicalize()
+            .with_context(|| format_err!("Plugin directory not found {}", dir.display()))?;
+        self.watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+        self.dirs.insert(dir);
+        Ok(())
+    }
+
+    /// Collect the changes to plugin files which have settled since the last call. Files which
+    /// are not (yet) complete WASM modules are skipped
+    pub fn hotload(&mut self) -> Vec<HotloadEvent> {
+        let now = Instant::now();
+        for path in self.rx.try_iter().collect::<Vec<_>>() {
+            if let Some(path) = self.watched(path) {
+                self.debounce.touch(path, now);
+            }
+        }
+
+        let mut events = vec![];
+        for path in self.debounce.settled(now) {
+            match std::fs::read(&path) {
+                Ok(code) => match wasmtime::Module::validate(&self.wasm, &code) {
+                    Ok(()) => events.push(HotloadEvent::Load(path, code)),
+                    Err(e) => log::warn!(
+                        "Skipping {}; not a complete WASM module: {:#}",
+                        path.display(),
+                        e
+                    ),
+                },
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    // Individually watched plugins are expected to come back
+                    if !self.paths.contains(&path) {
+                        events.push(HotloadEvent::Unload(path));
+                    }
+                }
+                Err(e) => log::warn!("Failed to read {}; {}", path.display(), e),
+            }
+        }
+
+        events
+    }
+
+    /// Returns the (canonical) path if it is a watched plugin
+    fn watched(&self, path: PathBuf) -> Option<PathBuf> {
+        // Deleted files cannot be canonicalized
+        let path = path.canonicalize().unwrap_or(path);
+        let in_dir = path.extension().is_some_and(|ext| ext == "wasm")
+            && path.parent().is_some_and(|dir| self.dirs.contains(dir));
+        (in_dir || self.paths.contains(&path)).then_some(path)
+    }
+}
+
+impl Debounce {
+    fn new(settle_time: Duration) -> Self {
+        Self {
+            settle_time,
+            pending: HashMap::default(),
+        }
+    }
+
+    /// Record a change to the given path
+    fn touch(&mut self, path: PathBuf, now: Instant) {
+        self.pending.insert(path, now);
+    }
+
+    /// Take the paths which have not changed for the settle time, in a deterministic order
+    fn settled(&mut self, now: Instant) -> Vec<PathBuf> {
+        let mut settled: Vec<PathBuf> = self
+            .pending
+            .iter()
+            .filter(|(_, &changed)| now.duration_since(changed) >= self.settle_time)
+            .map(|(path, _)| path.clone())
+            .collect();
+        settled.sort();
+
+        for path in &settled {
+            self.pending.remove(path);
+        }
+
+        settled
+    }
+}
+
+/// List the `.wasm` files in the given directory, in order
+pub fn wasm_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    for entry in std::fs::read_dir(dir)
+        .with_context(|| format_err!("Reading plugin directory {}", dir.display()))?
+    {
+        let path = entry?.path();
+        if path.is_file() && path.extension().is_some_and(|ext| ext == "wasm") {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_debounce() {
+        let start = Instant::now();
+        let ms = |n| start + Duration::from_millis(n);
+        let mut debounce = Debounce::new(Duration::from_millis(100));
+
+        // Repeated writes keep postponing the event
+        debounce.touch("a.wasm".into(), ms(0));
+        debounce.touch("b.wasm".into(), ms(20));
+        debounce.touch("a.wasm".into(), ms(50));
+        assert!(debounce.settled(ms(100)).is_empty());
+        assert_eq!(debounce.settled(ms(120)), vec![PathBuf::from("b.wasm")]);
+        assert_eq!(debounce.settled(ms(150)), vec![PathBuf::from("a.wasm")]);
+
+        // Each change is reported once
+        assert!(debounce.settled(ms(500)).is_empty());
     }
 }
@@ -0,0 +1,168 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    marker::PhantomData,
+    time::{Duration, Instant},
+};
+
+use cimvr_engine_interface::prelude::*;
+
+/// Maximum number of unread messages held for a single subscriber on a single channel. Beyond
+/// this, the oldest messages are dropped (and counted as such in the message metrics)
+pub const INBOX_CAPACITY: usize = 1 << 14;
+
+/// Minimum time between warnings about messages dropped on the same channel
+const DROP_WARNING_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Handle to a host-side inbox for messages of type `M`, obtained from `Engine::subscribe`.
+///
+/// Host inboxes have the same delivery guarantees as plugin systems: every message sent on the
+/// channel after subscribing is delivered at most once, in the order sent, and is held until
+/// read regardless of the stage it was sent in. At most `INBOX_CAPACITY` unread messages are
+/// held; beyond that, the oldest are dropped (and counted in `Engine::metrics`), so inboxes
+/// must be read regularly to receive every message.
+pub struct HostInbox<M> {
+    id: usize,
+    _phantom: PhantomData<fn() -> M>,
+}
+
+/// Message queues of all host inboxes
+#[derive(Default)]
+pub(crate) struct HostInboxes {
+    /// Unread messages of each inbox, by ID. None once unsubscribed
+    queues: Vec<Option<VecDeque<MessageData>>>,
+    /// Inboxes subscribed to each channel
+    channels: HashMap<ChannelId, Vec<usize>>,
+}
+
+/// Rate-limits warnings about dropped messages, so that a flooded channel does not flood the log
+#[derive(Default)]
+pub(crate) struct DropWarnings {
+    /// When each channel was last warned about, and the number of messages dropped since
+    channels: HashMap<ChannelId, (Instant, usize)>,
+}
+
+impl<M> HostInbox<M> {
+    pub(crate) fn id(&self) -> usize {
+        self.id
+    }
+}
+
+impl HostInboxes {
+    /// Create a new inbox subscribed to the given channel
+    pub fn subscribe<M: Message>(&mut self) -> HostInbox<M> {
+        let id = self.queues.len();
+        self.queues.push(Some(VecDeque::new()));
+        self.channels.entry(M::CHANNEL.into()).or_default().push(id);
+        HostInbox {
+            id,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Remove the given inbox, discarding unread messages
+    pub fn unsubscribe<M: Message>(&mut self, inbox: HostInbox<M>) {
+        self.queues[inbox.id] = None;
+        if let Some(ids) = self.channels.get_mut(&M::CHANNEL.into()) {
+            ids.retain(|&id| id != inbox.id);
+        }
+    }
+
+    /// Queue the message for each inbox subscribed to its channel. Returns whether any inbox was
+    /// subscribed, and the number of messages dropped to make room
+    pub fn deliver(&mut self, msg: &MessageData) -> (bool, usize) {
+        let Some(ids) = self.channels.get(&msg.channel) else { return (false, 0) };
+
+        let mut dropped = 0;
+        for &id in ids {
+            let Some(queue) = &mut self.queues[id] else { continue };
+            dropped += push_bounded(queue, msg.clone()) as usize;
+        }
+
+        (!ids.is_empty(), dropped)
+    }
+
+    /// Take all unread messages of the given inbox
+    pub fn drain(&mut self, id: usize) -> VecDeque<MessageData> {
+        self.queues[id].as_mut().map(std::mem::take).unwrap_or_default()
+    }
+}
+
+/// Queue the message, dropping the oldest one if the queue already holds `INBOX_CAPACITY`
+/// messages. Returns whether a message was dropped
+pub(crate) fn push_bounded(queue: &mut VecDeque<MessageData>, msg: MessageData) -> bool {
+    let full = queue.len() >= INBOX_CAPACITY;
+    if full {
+        queue.pop_front();
+    }
+    queue.push_back(msg);
+    full
+}
+
+impl DropWarnings {
+    /// Record messages dropped on the given channel. Returns the number of drops to warn about,
+    /// if a warning is due
+    pub fn dropped(&mut self, channel: &ChannelId, count: usize, now: Instant) -> Option<usize> {
+        let Some((last, pending)) = self.channels.get_mut(channel) else {
+            self.channels.insert(channel.clone(), (now, 0));
+            return Some(count);
+        };
+
+        *pending += count;
+        if now.duration_since(*last) < DROP_WARNING_INTERVAL {
+            return None;
+        }
+        *last = now;
+        Some(std::mem::take(pending))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Config, Engine};
+    use cimvr_engine_interface::{system::Stage, FrameTime};
+
+    #[test]
+    fn test_host_inbox_delivery() {
+        let cfg = Config::default();
+        let mut engine = Engine::new(&[], cfg).unwrap();
+
+        let a = engine.subscribe::<FrameTime>();
+        let b = engine.subscribe::<FrameTime>();
+
+        // Messages survive across stages until read
+        engine.dispatch(Stage::PreUpdate).unwrap();
+        engine.dispatch(Stage::PostUpdate).unwrap();
+        assert_eq!(engine.inbox(&a).count(), 2);
+        assert_eq!(engine.inbox(&a).count(), 0);
+
+        // Each subscriber has its own queue
+        engine.dispatch(Stage::PreUpdate).unwrap();
+        let ticks: Vec<u64> = engine.inbox(&b).map(|time| time.tick).collect();
+        assert_eq!(ticks.len(), 3);
+        assert!(ticks.windows(2).all(|w| w[0] <= w[1]));
+
+        // Unsubscribed inboxes no longer receive messages
+        engine.unsubscribe(a);
+        engine.unsubscribe(b);
+        engine.dispatch(Stage::Update).unwrap();
+        let channel = ChannelId::from(FrameTime::CHANNEL);
+        assert_eq!(engine.metrics().channels()[&channel].undelivered, 1);
+    }
+
+    #[test]
+    fn test_drop_warnings() {
+        let start = Instant::now();
+        let channel = ChannelId::from(FrameTime::CHANNEL);
+        let mut warnings = DropWarnings::default();
+
+        // The first drop is reported immediately, later ones at most once per interval
+        assert_eq!(warnings.dropped(&channel, 1, start), Some(1));
+        assert_eq!(warnings.dropped(&channel, 3, start), None);
+        let soon = start + Duration::from_secs(1);
+        assert_eq!(warnings.dropped(&channel, 2, soon), None);
+        let later = start + DROP_WARNING_INTERVAL;
+        assert_eq!(warnings.dropped(&channel, 1, later), Some(6));
+        assert_eq!(warnings.dropped(&channel, 1, later), None);
+    }
+}
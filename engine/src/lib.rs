@@ -1,35 +1,67 @@
 pub mod ecs;
+pub mod hierarchy;
 pub mod hotload;
+pub mod inbox;
+pub mod metrics;
 pub mod network;
+mod parallel;
 pub mod plugin;
+pub mod profiler;
+pub mod replication;
+pub mod snapshot;
+pub mod timers;
 pub mod timing;
 use cimvr_engine_interface::network::Digest;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, path::PathBuf};
-use timing::Timing;
+use std::{
+    collections::{HashMap, VecDeque},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+use timing::{FixedTimestep, Timing};
 
 use anyhow::{format_err, Context, Ok, Result};
 pub use cimvr_engine_interface as interface;
 use ecs::{apply_ecs_commands, query_ecs_data, Ecs};
+use hierarchy::Hierarchy;
+use inbox::{push_bounded, DropWarnings, HostInbox, HostInboxes};
 use interface::{
     pkg_namespace,
     prelude::*,
-    serial::{deserialize, serialize, EcsData, ReceiveBuf},
-    system::Stage,
-    Saved,
+    scene::Scene,
+    schema::SchemaRegistry,
+    serial::{deserialize, serialize, EcsData, MigrationData, ReceiveBuf, SendBuf, UserStateData},
+    system::{RunCondition, ScheduleCommand, Stage, SystemDescriptor, SystemId},
+    timer::TimerFired,
+    ClockControl, EntitiesDespawned, FrameTime, Saved,
 };
-use plugin::Plugin;
+use metrics::MessageMetrics;
+use plugin::{DispatchTimings, HostFunctions, Plugin, PluginContext};
+use profiler::{Profiler, SystemTimings};
+use rayon::prelude::*;
+use timers::Timers;
 
 // Keep the ECS in an Arc, so that it may be read simultaneously
+/// Engine settings. The default is a client following the wall clock, running systems one at a
+/// time, with only the built-in host functions
+#[derive(Default)]
 pub struct Config {
     /// Run server-side plugins
     pub is_server: bool,
+    /// Run the engine clock at a fixed rate. If None, the clock follows the wall clock
+    pub fixed_timestep: Option<FixedTimestep>,
+    /// Run systems of different plugins which do not access the same components in parallel.
+    /// Systems in the same batch do not see entities created or deleted, or components added, by
+    /// each other during the stage; only enable this if plugins do not rely on doing so
+    pub parallel: bool,
+    /// Native functions made available to plugins, in addition to the built-in ones
+    pub host_functions: HostFunctions,
 }
 
 /// Plugin state, plugin code, ECS state, messaging machinery, and more
 pub struct Engine {
-    /// WASM engine
-    wasm: wasmtime::Engine,
+    /// WASM engine, along with the host functions available to plugins
+    linker: wasmtime::Linker<PluginContext>,
     /// Plugin states
     plugins: Vec<PluginState>,
     /// Entity and Component data
@@ -37,16 +69,31 @@ pub struct Engine {
     /// Message distribution indices, maps (channel id) -> (plugin index, system index)
     indices: HashMap<ChannelId, Vec<(PluginIndex, usize)>>,
     /// Host inboxes
-    external_inbox: Inbox,
+    host_inboxes: HostInboxes,
+    /// Rate limits warnings about messages dropped from full inboxes
+    drop_warnings: DropWarnings,
     /// Network inbox; messages to be sent from plugins to the remote(s)
     network_inbox: Vec<MessageData>,
     /// Configuration we were constructed with
     cfg: Config,
     /// Manages FrameTime
     time: Timing,
+    /// Computes global transforms from the `Parent` hierarchy, if set
+    hierarchy: Option<Hierarchy>,
+    /// Layouts of the components and messages used by plugins
+    schemas: SchemaRegistry,
+    /// Message traffic counters
+    metrics: MessageMetrics,
+    /// Time spent in each system and stage
+    profiler: Profiler,
+    /// Timers started by plugins
+    timers: Timers,
 }
 
 /// Plugin management structure
+/// Unread messages of a single system, by channel
+type SystemInbox = HashMap<ChannelId, VecDeque<MessageData>>;
+
 struct PluginState {
     /// Unique name of this plugin
     name: String,
@@ -55,59 +102,120 @@ struct PluginState {
     /// Systems on this plugin
     systems: Vec<SystemDescriptor>,
     /// Message inboxes, one for each system
-    inbox: Vec<HashMap<ChannelId, Vec<MessageData>>>,
+    inbox: Vec<SystemInbox>,
+    /// Frame time at which each system last ran, if ever
+    last_run: Vec<Option<FrameTime>>,
+    /// Components for which the plugin can convert data from outdated layouts
+    migrations: Vec<ComponentId>,
     // TODO: Make this Vec<Arc<Message>>? Faster! (No unnecessary copying)
     /// Message outbox
     outbox: Vec<MessageData>,
 }
 
+/// Engine state as it was before a plugin was (re)started
+struct Snapshot {
+    ecs: Ecs,
+    indices: HashMap<ChannelId, Vec<(PluginIndex, usize)>>,
+    timers: Timers,
+}
+
+/// A system whose input has been gathered, ready to run
+struct SystemRun {
+    plugin: usize,
+    system: usize,
+    /// Time at which we began preparing the system
+    start: Instant,
+    /// Time spent querying the ECS
+    query: Duration,
+    input: ReceiveBuf,
+}
+
 /// Marker of plugin ownership, by plugin index
 #[derive(Component, Copy, Clone, Debug, Default, Hash, PartialEq, Eq, Serialize, Deserialize)]
 pub struct PluginIndex(usize);
 
 impl PluginState {
-    pub fn new(name: String, bytecode: &[u8], wasm: &wasmtime::Engine) -> Result<Self> {
-        let code = Plugin::new(wasm, bytecode)?;
+    pub fn new(
+        name: String,
+        bytecode: &[u8],
+        linker: &wasmtime::Linker<PluginContext>,
+    ) -> Result<Self> {
+        let code = Plugin::new(linker, &name, bytecode)?;
         Ok(PluginState {
             name,
             code,
             outbox: vec![],
             systems: vec![],
             inbox: Default::default(),
+            last_run: vec![],
+            migrations: vec![],
         })
     }
 
     pub fn name(&self) -> &str {
         &self.name
     }
+
+    /// Send the init signal, restoring the in-memory state of a previous instance if given
+    fn init(&mut self, is_server: bool, state: Option<UserStateData>) -> Result<SendBuf> {
+        log::info!("Initializing {}", self.name());
+        let send = ReceiveBuf {
+            system: None,
+            inbox: Default::default(),
+            ecs: EcsData::default(),
+            is_server,
+            migrations: vec![],
+            save_state: false,
+            state,
+            log_level: Some(log::max_level() as u8),
+        };
+        let (recv, _) = self.code.dispatch(&send)?;
+        Ok(recv)
+    }
 }
 
 impl Engine {
     /// Load plugins at the given paths
     pub fn new(plugins: &[(String, Vec<u8>)], cfg: Config) -> Result<Self> {
-        let time = Timing::init();
+        if let Some(fixed) = &cfg.fixed_timestep {
+            fixed.validate().context("Invalid fixed timestep")?;
+        }
+        let time = Timing::new(cfg.fixed_timestep);
 
         let wasm = wasmtime::Engine::new(&Default::default())?;
+        let linker = cfg
+            .host_functions
+            .linker(&wasm)
+            .context("Setting up host functions")?;
 
         let plugins: Vec<PluginState> = plugins
             .iter()
             .map(|(name, bytecode)| {
-                PluginState::new(name.clone(), bytecode, &wasm)
+                PluginState::new(name.clone(), bytecode, &linker)
                     .with_context(|| format_err!("Initializing plugin {}", name))
             })
             .collect::<Result<_>>()?;
 
         let ecs = Ecs::new();
 
+        let mut schemas = SchemaRegistry::new();
+        schemas.insert(PluginIndex::schema().unwrap());
+
         Ok(Self {
             time,
-            wasm,
+            linker,
             indices: HashMap::new(),
             plugins,
             ecs,
-            external_inbox: HashMap::new(),
+            host_inboxes: HostInboxes::default(),
+            drop_warnings: DropWarnings::default(),
             network_inbox: vec![],
             cfg,
+            hierarchy: None,
+            schemas,
+            metrics: MessageMetrics::new(),
+            profiler: Profiler::new(),
+            timers: Timers::default(),
         })
     }
 
@@ -118,7 +226,7 @@ impl Engine {
         // Dispatch all plugins
         for plugin_idx in 0..self.plugins.len() {
             let name = self.plugins[plugin_idx].name().to_string();
-            self.init_plugin(plugin_idx)
+            self.init_plugin(plugin_idx, None)
                 .with_context(|| format_err!("Plugin {}", name))?;
         }
 
@@ -132,115 +240,529 @@ impl Engine {
         Ok(())
     }
 
-    fn init_plugin(&mut self, plugin_idx: usize) -> Result<()> {
-        log::info!("Initializing {}", self.plugins[plugin_idx].name());
-        // Dispatch init signal
-        let send = ReceiveBuf {
-            system: None,
-            inbox: Default::default(),
-            ecs: EcsData::default(),
-            is_server: self.cfg.is_server,
-        };
-        let recv = self.plugins[plugin_idx].code.dispatch(&send)?;
+    /// Initialize the given plugin, restoring the in-memory state of its previous instance if given
+    fn init_plugin(&mut self, plugin_idx: usize, state: Option<UserStateData>) -> Result<()> {
+        let recv = self.plugins[plugin_idx].init(self.cfg.is_server, state)?;
+        self.setup_plugin(plugin_idx, recv)
+    }
 
+    /// Set up the given plugin's schedule and initial state from its response to the init signal
+    fn setup_plugin(&mut self, plugin_idx: usize, recv: SendBuf) -> Result<()> {
         // Apply ECS commands
         apply_ecs_commands(&mut self.ecs, &recv.commands, PluginIndex(plugin_idx))?;
 
-        // Setup message indices for each system
+        // Setup message indices for each enabled system
         for (sys_idx, sys) in recv.systems.iter().enumerate() {
-            // Set up lookup table
-            for channel in &sys.subscriptions {
-                self.indices
-                    .entry(channel.clone())
-                    .or_default()
-                    .push((PluginIndex(plugin_idx), sys_idx));
+            if sys.enabled {
+                self.add_indices(plugin_idx, sys_idx, &sys.subscriptions);
             }
-
-            // Initialize system's inbox
-            self.plugins[plugin_idx].inbox = vec![HashMap::new(); recv.systems.len()];
         }
 
+        // Initialize systems' inboxes
+        self.plugins[plugin_idx].inbox = vec![HashMap::new(); recv.systems.len()];
+        self.plugins[plugin_idx].last_run = vec![None; recv.systems.len()];
+
+        // Learn the layouts of the plugin's types
+        self.schemas.extend(recv.schemas);
+
         // Set up schedule, send first messages
         self.plugins[plugin_idx].systems = recv.systems;
         self.plugins[plugin_idx].outbox = recv.outbox;
+        self.apply_schedule_commands(plugin_idx, recv.schedule);
+        let now = self.time.get_frame_time().time;
+        self.timers.apply(plugin_idx, recv.timers, now);
+
+        // Components used by the plugin
+        let mut components = recv.components;
+        for system in &self.plugins[plugin_idx].systems {
+            for query in system.queries.values() {
+                components.extend(query.intersect.iter().map(|term| term.component.clone()));
+            }
+        }
+        components.extend(recv.migrations.iter().cloned());
+        self.plugins[plugin_idx].migrations = recv.migrations;
+
+        self.migrate_components(plugin_idx, components)
+            .context("Migrating component data")?;
+
+        Ok(())
+    }
+
+    /// Ask the plugin to serialize its in-memory state, for restoring after a hot reload
+    fn save_user_state(&mut self, plugin_idx: usize) -> Option<UserStateData> {
+        let send = ReceiveBuf {
+            is_server: self.cfg.is_server,
+            save_state: true,
+            ..Default::default()
+        };
+        let plugin = &mut self.plugins[plugin_idx];
+        match plugin.code.dispatch(&send) {
+            Result::Ok((recv, _)) => recv.state,
+            Err(e) => {
+                log::warn!("Failed to save state of {}; {:?}", plugin.name(), e);
+                None
+            }
+        }
+    }
+
+    /// Convert data stored with outdated layouts of the given components, which the plugin uses,
+    /// using the plugin's migrations. Data which cannot be converted is left in place and reported
+    fn migrate_components(
+        &mut self,
+        plugin_idx: usize,
+        mut current: Vec<ComponentId>,
+    ) -> Result<()> {
+        let targets = &self.plugins[plugin_idx].migrations;
+        current.sort_by(|a, b| a.id.cmp(&b.id));
+        current.dedup();
+
+        let mut migrations = vec![];
+        let mut unmigrated = vec![];
+        for component in &current {
+            for old in self.ecs.stale_layouts(component) {
+                let data: Vec<(EntityId, Vec<u8>)> = self
+                    .ecs
+                    .fast_all_component(old.clone())
+                    .map(|(&entity, data)| (entity, data.clone()))
+                    .collect();
+                if targets.contains(component) {
+                    migrations.push(MigrationData {
+                        from: old,
+                        to: component.clone(),
+                        data,
+                    });
+                } else {
+                    unmigrated.push((old, component.clone(), data.len()));
+                }
+            }
+        }
+
+        if !migrations.is_empty() {
+            let pending: Vec<(ComponentId, ComponentId, Vec<EntityId>)> = migrations
+                .iter()
+                .map(|m| {
+                    let entities = m.data.iter().map(|(entity, _)| *entity).collect();
+                    (m.from.clone(), m.to.clone(), entities)
+                })
+                .collect();
+
+            let send = ReceiveBuf {
+                system: None,
+                inbox: Default::default(),
+                ecs: EcsData::default(),
+                is_server: self.cfg.is_server,
+                migrations,
+                save_state: false,
+                state: None,
+                log_level: None,
+            };
+            let (recv, _) = self.plugins[plugin_idx].code.dispatch(&send)?;
+            apply_ecs_commands(&mut self.ecs, &recv.commands, PluginIndex(plugin_idx))?;
+
+            // Remove the old data of converted entities
+            for (old, new, entities) in pending {
+                let mut failed = 0;
+                for entity in entities {
+                    if self.ecs.get_raw(entity, &new).is_some() {
+                        self.ecs.remove_component(entity, &old);
+                    } else {
+                        failed += 1;
+                    }
+                }
+
+                if failed > 0 {
+                    unmigrated.push((old, new, failed));
+                }
+            }
+        }
+
+        for (old, new, count) in unmigrated {
+            log::warn!(
+                "{} entities have {} data with a layout of {} bytes, but {} now uses {} bytes. \
+                Register a migration with EngineSchedule::migrate() to convert it",
+                count,
+                old.id,
+                old.size,
+                self.plugins[plugin_idx].name(),
+                new.size
+            );
+        }
 
         Ok(())
     }
 
+    /// Advance the engine clock, returning the number of frames (PreUpdate, Update and PostUpdate)
+    /// the host should run now. Always 1 unless running at a fixed timestep.
+    pub fn poll_frames(&mut self) -> u32 {
+        self.time.poll()
+    }
+
+    /// Time remaining until the next frame is due. Always zero unless running at a fixed timestep.
+    pub fn time_until_next_frame(&self) -> Duration {
+        self.time.time_until_next_step()
+    }
+
+    /// Get the timing of the current frame
+    pub fn frame_time(&self) -> FrameTime {
+        self.time.get_frame_time()
+    }
+
+    /// Pause the engine clock
+    pub fn pause(&mut self) {
+        self.time.pause()
+    }
+
+    /// Resume the engine clock after a pause
+    pub fn resume(&mut self) {
+        self.time.resume()
+    }
+
+    /// Returns `true` if the engine clock is paused
+    pub fn is_paused(&self) -> bool {
+        self.time.is_paused()
+    }
+
+    /// Set the rate at which the engine clock runs relative to real time
+    pub fn set_time_scale(&mut self, scale: f32) {
+        self.time.set_time_scale(scale)
+    }
+
+    /// Get the rate at which the engine clock runs relative to real time
+    pub fn time_scale(&self) -> f32 {
+        self.time.time_scale()
+    }
+
+    /// Advance exactly `n` frames, then pause
+    pub fn step(&mut self, n: u32) {
+        self.time.step(n)
+    }
+
     /// Dispatch plugin code on the given stage
     pub fn dispatch(&mut self, stage: Stage) -> Result<()> {
         // TODO: Should this be the responsibility of something else?
         // Pre-update formally marks the start of a new frame
         if stage == Stage::PreUpdate {
             self.time.frame();
+            self.profiler.begin_frame();
+
+            // Let plugins know which entities were deleted during the last frame
+            let entities = self.ecs.drain_despawned();
+            if !entities.is_empty() {
+                self.send(EntitiesDespawned { entities });
+            }
+
+            self.fire_timers();
         }
         // Send time each frame
         self.send(self.time.get_frame_time());
 
+        let start = Instant::now();
+
         // Run plugins
-        for i in 0..self.plugins.len() {
-            self.dispatch_plugin(stage, i)?;
+        if self.cfg.parallel {
+            self.dispatch_parallel(stage)?;
+        } else {
+            for i in 0..self.plugins.len() {
+                self.dispatch_plugin(stage, i)?;
+            }
         }
 
         // Distribute messages
         self.propagate();
 
-        // TODO: This is kind of a hack, and it means that host-side systems cannot receive
-        // messages from WASM plugins after PostUpdate. We should really have handles for host-side
-        // inboxes...
-        if stage == Stage::PostUpdate {
-            // Clear external inbox so that unread messages don't build up
-            for inbox in self.external_inbox.values_mut() {
-                inbox.clear();
-            }
+        // Bring global transforms up to date with this stage's changes
+        if let Some(hierarchy) = &mut self.hierarchy {
+            hierarchy.update(&mut self.ecs);
         }
 
+        self.profiler.record_stage(stage, start);
+
         Ok(())
     }
 
     pub fn dispatch_plugin(&mut self, stage: Stage, plugin_idx: usize) -> Result<()> {
+        let mut schedule = vec![];
+
+        for system_idx in 0..self.plugins[plugin_idx].systems.len() {
+            let Some(run) = self.prepare_system(stage, plugin_idx, system_idx)? else { continue };
+
+            // Run plugin
+            let plugin = &mut self.plugins[plugin_idx];
+            let output = plugin
+                .code
+                .dispatch(&run.input)
+                .with_context(|| format_err!("Running plugin {}", plugin.name()))?;
+
+            self.finish_system(stage, run, output, &mut schedule)?;
+        }
+
+        // Schedule changes take effect once all of the plugin's systems in this stage have run
+        self.apply_schedule_commands(plugin_idx, schedule);
+
+        Ok(())
+    }
+
+    /// Run the given stage, running systems which do not conflict in parallel. Commands are
+    /// applied in the same order as they would be when running sequentially
+    fn dispatch_parallel(&mut self, stage: Stage) -> Result<()> {
+        let mut schedules = vec![vec![]; self.plugins.len()];
+
+        // Systems in the order they would run sequentially
+        let systems = self
+            .plugins
+            .iter()
+            .enumerate()
+            .flat_map(|(plugin_idx, plugin)| {
+                plugin
+                    .systems
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, system)| system.stage == stage && system.enabled)
+                    .map(move |(system_idx, system)| (plugin_idx, system_idx, &system.queries))
+            });
+
+        for batch in parallel::batches(systems) {
+            // Gather inputs
+            let mut runs = vec![];
+            for (plugin_idx, system_idx) in batch {
+                runs.extend(self.prepare_system(stage, plugin_idx, system_idx)?);
+            }
+
+            // Each system in the batch belongs to a different plugin
+            let mut plugins: Vec<Option<&mut PluginState>> =
+                self.plugins.iter_mut().map(Some).collect();
+            let jobs: Vec<_> = runs
+                .into_iter()
+                .map(|run| {
+                    let plugin = plugins[run.plugin].take().with_context(|| {
+                        format_err!("Plugin #{} scheduled twice in one batch", run.plugin)
+                    })?;
+                    Ok((plugin, run))
+                })
+                .collect::<Result<_>>()?;
+
+            // Run plugins. The results keep the order of the batch
+            let outputs: Vec<_> = jobs
+                .into_par_iter()
+                .map(|(plugin, run)| {
+                    let output = plugin
+                        .code
+                        .dispatch(&run.input)
+                        .with_context(|| format_err!("Running plugin {}", plugin.name()));
+                    (run, output)
+                })
+                .collect();
+
+            for (run, output) in outputs {
+                let schedule = &mut schedules[run.plugin];
+                self.finish_system(stage, run, output?, schedule)?;
+            }
+        }
+
+        for (plugin_idx, schedule) in schedules.into_iter().enumerate() {
+            self.apply_schedule_commands(plugin_idx, schedule);
+        }
+
+        Ok(())
+    }
+
+    /// Gather the input of the given system, if it is due to run in this stage
+    fn prepare_system(
+        &mut self,
+        stage: Stage,
+        plugin_idx: usize,
+        system_idx: usize,
+    ) -> Result<Option<SystemRun>> {
+        let time = self.time.get_frame_time();
         let plugin = &mut self.plugins[plugin_idx];
-        for (system_idx, system) in plugin.systems.iter().enumerate() {
-            // Filter to the requested stage
-            if system.stage != stage {
+        let system = &plugin.systems[system_idx];
+
+        // Filter to the requested stage
+        if system.stage != stage || !system.enabled {
+            return Ok(None);
+        }
+
+        // Skip systems whose run conditions do not hold, before doing any work for them
+        let inbox = &plugin.inbox[system_idx];
+        let last_run = plugin.last_run[system_idx];
+        if !should_run(system, inbox, last_run, &time, &mut self.ecs) {
+            return Ok(None);
+        }
+        plugin.last_run[system_idx] = Some(time);
+
+        let start = Instant::now();
+
+        // Query ECS
+        let ecs_data = query_ecs_data(&mut self.ecs, &system.queries).context("ECS query")?;
+        let query = start.elapsed();
+
+        // Write input data
+        let input = ReceiveBuf {
+            system: Some(system_idx),
+            inbox: std::mem::take(&mut plugin.inbox[system_idx])
+                .into_iter()
+                .map(|(channel, msgs)| (channel, msgs.into()))
+                .collect(),
+            is_server: self.cfg.is_server,
+            ecs: ecs_data,
+            migrations: vec![],
+            save_state: false,
+            state: None,
+            log_level: None,
+        };
+
+        Ok(Some(SystemRun {
+            plugin: plugin_idx,
+            system: system_idx,
+            start,
+            query,
+            input,
+        }))
+    }
+
+    /// Apply the output of a system which has run. Schedule changes are collected in `schedule`
+    fn finish_system(
+        &mut self,
+        stage: Stage,
+        run: SystemRun,
+        (ret, timings): (SendBuf, DispatchTimings),
+        schedule: &mut Vec<ScheduleCommand>,
+    ) -> Result<()> {
+        // Convert outdated data of components used for the first time, before writing over it
+        if !ret.components.is_empty() {
+            self.migrate_components(run.plugin, ret.components)
+                .context("Migrating component data")?;
+        }
+
+        // Write back to ECS
+        // TODO: Defer this? It's currently in Arbitrary order!
+        let applying = Instant::now();
+        apply_ecs_commands(&mut self.ecs, &ret.commands, PluginIndex(run.plugin))
+            .context("Updating ECS after dispatch")?;
+
+        let plugin = &mut self.plugins[run.plugin];
+        let timings = SystemTimings::new(run.query, timings, applying.elapsed());
+        self.profiler
+            .record_system(plugin.name(), run.system, stage, run.start, timings);
+
+        // Receive outbox
+        plugin.outbox.extend(ret.outbox);
+
+        // Learn the layouts of any types used for the first time
+        self.schemas.extend(ret.schemas);
+
+        schedule.extend(ret.schedule);
+        let now = self.time.get_frame_time().time;
+        self.timers.apply(run.plugin, ret.timers, now);
+
+        Ok(())
+    }
+
+    /// Deliver `TimerFired` messages for expired timers directly to the systems which own them
+    fn fire_timers(&mut self) {
+        let frame = self.time.get_frame_time();
+        let (now, tick) = (frame.time, frame.tick);
+
+        // Timers of disabled systems wait until the system is enabled again
+        let plugins = &self.plugins;
+        let held = |plugin_idx: usize, SystemId(sys_idx)| {
+            let system = plugins.get(plugin_idx).and_then(|p| p.systems.get(sys_idx));
+            system.is_some_and(|sys| !sys.enabled)
+        };
+
+        for (plugin_idx, SystemId(sys_idx), fired) in self.timers.expire(now, held) {
+            let Some(plugin) = self.plugins.get_mut(plugin_idx) else { continue };
+            if sys_idx >= plugin.systems.len() {
+                let name = plugin.name();
+                log::warn!("{} has no system #{} for its timer", name, sys_idx);
+                self.timers.cancel(plugin_idx, fired.id);
                 continue;
             }
 
-            // Query ECS
-            let ecs_data = query_ecs_data(&mut self.ecs, &system.queries).context("ECS query")?;
-
-            // Write input data
-            let recv_buf = ReceiveBuf {
-                system: Some(system_idx),
-                inbox: std::mem::take(&mut plugin.inbox[system_idx]),
-                is_server: self.cfg.is_server,
-                ecs: ecs_data,
+            let channel: ChannelId = TimerFired::CHANNEL.into();
+            let msg = MessageData {
+                channel: channel.clone(),
+                data: serialize(&fired).expect("Failed to serialize timer message"),
+                client: None,
             };
+            self.metrics.record_sent(None, tick, &msg);
+            let inbox = plugin.inbox[sys_idx].entry(channel.clone()).or_default();
+            let dropped = push_bounded(inbox, msg) as usize;
+            self.record_drops(None, &channel, dropped);
+        }
+    }
 
-            // Run plugin
-            let name = plugin.name().to_string();
-            let ret = plugin
-                .code
-                .dispatch(&recv_buf)
-                .with_context(|| format_err!("Running plugin {}", name))?;
+    /// Apply changes to a plugin's schedule, updating the message indices to match
+    fn apply_schedule_commands(&mut self, plugin_idx: usize, commands: Vec<ScheduleCommand>) {
+        for command in commands {
+            let (ScheduleCommand::Subscribe(SystemId(sys_idx), _)
+            | ScheduleCommand::Unsubscribe(SystemId(sys_idx), _)
+            | ScheduleCommand::Enable(SystemId(sys_idx))
+            | ScheduleCommand::Disable(SystemId(sys_idx))) = command;
 
-            // Write back to ECS
-            // TODO: Defer this? It's currently in Arbitrary order!
-            apply_ecs_commands(&mut self.ecs, &ret.commands, PluginIndex(plugin_idx))
-                .context("Updating ECS after dispatch")?;
+            let plugin = &mut self.plugins[plugin_idx];
+            let Some(system) = plugin.systems.get_mut(sys_idx) else {
+                log::error!("Plugin {} has no system #{}", plugin.name(), sys_idx);
+                continue;
+            };
 
-            // Receive outbox
-            plugin.outbox.extend(ret.outbox);
+            match command {
+                ScheduleCommand::Subscribe(_, channel) => {
+                    if system.subscriptions.contains(&channel) {
+                        continue;
+                    }
+                    system.subscriptions.push(channel.clone());
+                    if system.enabled {
+                        self.add_indices(plugin_idx, sys_idx, &[channel]);
+                    }
+                }
+                ScheduleCommand::Unsubscribe(_, channel) => {
+                    system.subscriptions.retain(|c| *c != channel);
+                    plugin.inbox[sys_idx].remove(&channel);
+                    self.remove_indices(plugin_idx, sys_idx, &[channel]);
+                }
+                ScheduleCommand::Enable(_) => {
+                    if !system.enabled {
+                        system.enabled = true;
+                        let channels = system.subscriptions.clone();
+                        self.add_indices(plugin_idx, sys_idx, &channels);
+                    }
+                }
+                ScheduleCommand::Disable(_) => {
+                    system.enabled = false;
+                    let channels = system.subscriptions.clone();
+                    plugin.inbox[sys_idx].clear();
+                    self.remove_indices(plugin_idx, sys_idx, &channels);
+                }
+            }
         }
+    }
 
-        Ok(())
+    /// Route messages on the given channels to the system
+    fn add_indices(&mut self, plugin_idx: usize, sys_idx: usize, channels: &[ChannelId]) {
+        for channel in channels {
+            let destinations = self.indices.entry(channel.clone()).or_default();
+            let destination = (PluginIndex(plugin_idx), sys_idx);
+            if !destinations.contains(&destination) {
+                destinations.push(destination);
+            }
+        }
+    }
+
+    /// Stop routing messages on the given channels to the system
+    fn remove_indices(&mut self, plugin_idx: usize, sys_idx: usize, channels: &[ChannelId]) {
+        for channel in channels {
+            if let Some(destinations) = self.indices.get_mut(channel) {
+                destinations.retain(|&dest| dest != (PluginIndex(plugin_idx), sys_idx));
+            }
+        }
     }
 
     /// Propagate messages from plugin outboxes
     fn propagate(&mut self) {
         for i in 0..self.plugins.len() {
             for msg in std::mem::take(&mut self.plugins[i].outbox) {
-                self.broadcast(msg);
+                self.broadcast_from(Some(i), msg);
             }
         }
     }
@@ -248,8 +770,17 @@ impl Engine {
     // TODO: Find a better name for this
     /// Broadcast message to relevant destinations
     pub fn broadcast(&mut self, msg: MessageData) {
+        self.broadcast_from(None, msg)
+    }
+
+    /// Broadcast message to relevant destinations, on behalf of the given plugin
+    fn broadcast_from(&mut self, sender: Option<usize>, msg: MessageData) {
+        let name = sender.map(|i| self.plugins[i].name());
+        let tick = self.time.get_frame_time().tick;
+        self.metrics.record_sent(name, tick, &msg);
+
         match msg.channel.locality {
-            Locality::Local => self.broadcast_local(msg),
+            Locality::Local => self.deliver_local(sender, msg),
             Locality::Remote => {
                 self.network_inbox.push(msg);
             }
@@ -258,19 +789,77 @@ impl Engine {
 
     /// Broadcast the message locally, without checkint to see if it's marked with local locality
     pub fn broadcast_local(&mut self, msg: MessageData) {
+        let tick = self.time.get_frame_time().tick;
+        self.metrics.record_sent(None, tick, &msg);
+        self.deliver_local(None, msg);
+    }
+
+    /// Deliver the message to local plugins and host inboxes
+    fn deliver_local(&mut self, sender: Option<usize>, msg: MessageData) {
+        let mut delivered = false;
+        let mut dropped = 0;
+
+        // Clock control requests are handled by the engine itself
+        if msg.channel.id == ClockControl::CHANNEL.id {
+            delivered = true;
+            match deserialize(std::io::Cursor::new(&msg.data)) {
+                Result::Ok(ctrl) => self.time.control(ctrl),
+                Err(e) => log::error!("Malformed clock control message; {:#}", e),
+            }
+        }
+
+        // As are replication policy changes
+        if msg.channel.id == SetReplicationPolicy::CHANNEL.id {
+            delivered = true;
+            match deserialize::<_, SetReplicationPolicy>(std::io::Cursor::new(&msg.data)) {
+                Result::Ok(set) => self
+                    .ecs
+                    .replication()
+                    .set_policy_raw(set.component, set.policy),
+                Err(e) => log::error!("Malformed replication policy message; {:#}", e),
+            }
+        }
+
         if let Some(destinations) = self.indices.get(&msg.channel) {
             for (PluginIndex(plugin_idx), system_idx) in destinations {
-                self.plugins[*plugin_idx].inbox[*system_idx]
+                let inbox = self.plugins[*plugin_idx].inbox[*system_idx]
                     .entry(msg.channel.clone())
-                    .or_default()
-                    .push(msg.clone());
+                    .or_default();
+                dropped += push_bounded(inbox, msg.clone()) as usize;
+                delivered = true;
             }
-        } else {
+        }
+
+        let (subscribed, host_dropped) = self.host_inboxes.deliver(&msg);
+        delivered |= subscribed;
+        dropped += host_dropped;
+
+        self.record_drops(sender, &msg.channel, dropped);
+
+        if !delivered {
             log::trace!("Message on channel {:?} has no destination", msg.channel);
+            let name = sender.map(|i| self.plugins[i].name());
+            self.metrics.record_undelivered(name, &msg.channel);
         }
+    }
 
-        if let Some(inbox) = self.external_inbox.get_mut(&msg.channel) {
-            inbox.push(msg.clone());
+    /// Warn about and count messages pushed out of full inboxes
+    fn record_drops(&mut self, sender: Option<usize>, channel: &ChannelId, dropped: usize) {
+        if dropped == 0 {
+            return;
+        }
+
+        let warning = self.drop_warnings.dropped(channel, dropped, Instant::now());
+        if let Some(count) = warning {
+            log::warn!(
+                "Inbox full, dropped {} oldest message(s) on {:?}",
+                count,
+                channel
+            );
+        }
+        let name = sender.map(|i| self.plugins[i].name());
+        for _ in 0..dropped {
+            self.metrics.record_dropped(name, channel);
         }
     }
 
@@ -279,19 +868,92 @@ impl Engine {
         &mut self.ecs
     }
 
-    /// Subscribe to the given channel
-    pub fn subscribe<M: Message>(&mut self) {
-        self.external_inbox.entry(M::CHANNEL.into()).or_default();
+    /// Create the entities described by the given scene, decoding components with the schemas
+    /// known to the host
+    pub fn load_scene(&mut self, scene: &Scene) -> Result<Vec<EntityId>> {
+        self.ecs.load_scene(scene, &self.schemas)
     }
 
-    /// Drain messages from the given channel (external inbox)
-    pub fn inbox<M: Message>(&mut self) -> impl Iterator<Item = M> + '_ {
-        self.external_inbox
-            .get_mut(&M::CHANNEL.into())
-            .expect("Attempted to access a channel we haven't subscribed to")
-            .iter()
-            .map(|msg| {
-                deserialize(std::io::Cursor::new(&msg.data)).expect("Failed to decode message")
+    /// Describe the queried entities as a scene, encoding components with the schemas known to
+    /// the host. Plugin ownership is omitted, as plugin indices differ between runs
+    pub fn save_scene(&mut self, query: &Query) -> Scene {
+        let mut scene = self.ecs.save_scene(query, &self.schemas);
+        for entity in &mut scene.entities {
+            entity.components.remove(PluginIndex::ID);
+        }
+        scene
+    }
+
+    /// Layouts of the components and messages used by plugins, for converting their data to and
+    /// from JSON
+    pub fn schemas(&self) -> &SchemaRegistry {
+        &self.schemas
+    }
+
+    /// Layouts of the components and messages used by plugins. Types known to the host (e.g. those
+    /// used by remote plugins) may be added here
+    pub fn schemas_mut(&mut self) -> &mut SchemaRegistry {
+        &mut self.schemas
+    }
+
+    /// Message traffic counters, and the message tap
+    pub fn metrics(&self) -> &MessageMetrics {
+        &self.metrics
+    }
+
+    /// Message traffic counters, and the message tap. Host code may record its own drops here
+    pub fn metrics_mut(&mut self) -> &mut MessageMetrics {
+        &mut self.metrics
+    }
+
+    /// Time spent in each system and stage
+    pub fn profiler(&self) -> &Profiler {
+        &self.profiler
+    }
+
+    /// Time spent in each system and stage. Use to capture traces
+    pub fn profiler_mut(&mut self) -> &mut Profiler {
+        &mut self.profiler
+    }
+
+    /// Name of the plugin which created the given entity, if it was created locally
+    pub fn owner(&self, entity: EntityId) -> Option<&str> {
+        let PluginIndex(idx) = self.ecs.get(entity)?;
+        self.plugins.get(idx).map(|plugin| plugin.name())
+    }
+
+    /// Compute global components (e.g. transforms) along the `Parent` hierarchy after each stage.
+    /// The global component is derived locally, and so is not replicated
+    pub fn set_hierarchy(&mut self, hierarchy: Hierarchy) {
+        self.ecs
+            .replication()
+            .set_policy_raw(hierarchy.global().clone(), ReplicationPolicy::never());
+        self.hierarchy = Some(hierarchy);
+    }
+
+    /// Subscribe to the given channel. Messages are held for the returned inbox until read with
+    /// `Engine::inbox`, regardless of the stage they were sent in
+    pub fn subscribe<M: Message>(&mut self) -> HostInbox<M> {
+        self.host_inboxes.subscribe()
+    }
+
+    /// Stop receiving messages on the given inbox, discarding any unread messages
+    pub fn unsubscribe<M: Message>(&mut self, inbox: HostInbox<M>) {
+        self.host_inboxes.unsubscribe(inbox)
+    }
+
+    /// Drain messages received by the given inbox since it was last read, in the order they were
+    /// sent. Messages which fail to decode are logged and skipped
+    pub fn inbox<M: Message>(&mut self, inbox: &HostInbox<M>) -> impl Iterator<Item = M> {
+        self.host_inboxes
+            .drain(inbox.id())
+            .into_iter()
+            .filter_map(|msg| match deserialize(std::io::Cursor::new(&msg.data)) {
+                Result::Ok(msg) => Some(msg),
+                Err(e) => {
+                    log::error!("Failed to decode message on {:?}; {:#}", msg.channel, e);
+                    None
+                }
             })
     }
 
@@ -309,52 +971,699 @@ impl Engine {
         });
     }
 
-    /// Reload the plugin at the given path
-    pub fn reload(&mut self, name: String, code: &[u8]) -> Result<()> {
-        // Find old plugin
+    /// Returns `true` if a plugin with the given name is loaded
+    pub fn is_loaded(&self, name: &str) -> bool {
+        self.plugins.iter().any(|p| p.name() == name)
+    }
+
+    /// Load and initialize a plugin while running, e.g. one added to a watched directory. The
+    /// engine is left as it was if the plugin fails to start
+    pub fn load_plugin(&mut self, name: String, code: &[u8]) -> Result<()> {
+        if self.is_loaded(&name) {
+            return Err(format_err!("Plugin {} is already loaded", name));
+        }
+
+        let mut plugin = PluginState::new(name.clone(), code, &self.linker)
+            .with_context(|| format_err!("Initializing plugin {}", name))?;
+
+        // Only add plugins which initialize successfully
+        let recv = plugin
+            .init(self.cfg.is_server, None)
+            .with_context(|| format_err!("Plugin {}", name))?;
+
+        let snapshot = self.snapshot();
+        self.plugins.push(plugin);
+        let i = self.plugins.len() - 1;
+
+        if let Err(e) = self.start_plugin(i, recv) {
+            self.plugins.pop();
+            self.restore(snapshot);
+            return Err(e.context(format!("Plugin {}", name)));
+        }
+
+        Ok(())
+    }
+
+    /// Unload the given plugin, deleting its unsaved entities. Its `Saved` entities are kept
+    pub fn unload_plugin(&mut self, name: &str) -> Result<()> {
         let i = self
             .plugins
-            .iter_mut()
+            .iter()
             .position(|p| p.name() == name)
-            .expect("Requested plugin is not loaded");
+            .ok_or_else(|| format_err!("Plugin {} is not loaded", name))?;
 
-        // Replace old plugin
-        let new_plugin = PluginState::new(name.clone(), code, &self.wasm)?;
+        self.clear_plugin(i);
+        let plugin = self.plugins.remove(i);
+        self.discard_inbox(&plugin);
+        self.timers.remove_plugin(i);
 
-        self.plugins[i] = new_plugin;
+        // Shift the indices of the plugins after it
+        for channel in self.indices.values_mut() {
+            for (PluginIndex(j), _) in channel {
+                if *j > i {
+                    *j -= 1;
+                }
+            }
+        }
 
-        // Delete all unsaved entities from that plugin
-        let indices = self
+        let owned = self
             .ecs
             .query(&Query::new().intersect::<PluginIndex>(Access::Read));
-        for ent in indices {
-            if let Some(PluginIndex(idx)) = self.ecs().get::<PluginIndex>(ent) {
-                // Only those from the plugin that have no Saved component
-                if idx == i && self.ecs().get::<Saved>(ent).is_none() {
-                    // TODO: This is slow lol
-                    self.ecs.remove_entity(ent);
-                }
+        for ent in owned {
+            let Some(PluginIndex(j)) = self.ecs.get::<PluginIndex>(ent) else { continue };
+            if j == i {
+                self.ecs
+                    .remove_component(ent, &interface::component_id::<PluginIndex>());
+            } else if j > i {
+                self.ecs.add_component(ent, &PluginIndex(j - 1));
             }
         }
 
-        // Delete message indices for that plugin
-        for channel in self.indices.values_mut() {
-            channel.retain(|(PluginIndex(j), _)| *j != i);
-        }
+        Ok(())
+    }
+
+    /// Reload the plugin at the given path. The old instance keeps running if the new one fails
+    /// to start
+    pub fn reload(&mut self, name: String, code: &[u8]) -> Result<()> {
+        // Find old plugin
+        let i = self
+            .plugins
+            .iter_mut()
+            .position(|p| p.name() == name)
+            .ok_or_else(|| format_err!("Plugin {} is not loaded", name))?;
 
-        // Initialize new plugin
-        self.init_plugin(i)
+        // Replace old plugin
+        let mut new_plugin = PluginState::new(name.clone(), code, &self.linker)?;
+
+        // Keep the old instance's in-memory state, if it persists any
+        let state = self.save_user_state(i);
+
+        // Initialize new plugin. If it fails, the old instance keeps running
+        let recv = new_plugin
+            .init(self.cfg.is_server, state)
             .with_context(|| format_err!("Initializing reloaded plugin {}", name))?;
 
+        let snapshot = self.snapshot();
+        self.clear_plugin(i);
+        let old_plugin = std::mem::replace(&mut self.plugins[i], new_plugin);
+
+        if let Err(e) = self.start_plugin(i, recv) {
+            self.plugins[i] = old_plugin;
+            self.restore(snapshot);
+            return Err(e.context(format!("Initializing reloaded plugin {}", name)));
+        }
+
+        self.discard_inbox(&old_plugin);
+        Ok(())
+    }
+
+    /// Set up a newly initialized plugin, and run its PostInit stage
+    fn start_plugin(&mut self, plugin_idx: usize, recv: SendBuf) -> Result<()> {
+        self.setup_plugin(plugin_idx, recv)?;
+
         // Propagate startup messages
         self.propagate();
 
         // Run PostInit stage
-        self.dispatch_plugin(Stage::PostInit, i)
+        self.dispatch_plugin(Stage::PostInit, plugin_idx)
+    }
+
+    /// Copy the state changed by starting a plugin, so that a plugin which fails to start may be
+    /// removed without a trace. Messages it sent before failing are still delivered
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            ecs: self.ecs.clone(),
+            indices: self.indices.clone(),
+            timers: self.timers.clone(),
+        }
+    }
+
+    /// Undo everything since the snapshot was taken
+    fn restore(&mut self, snapshot: Snapshot) {
+        self.ecs = snapshot.ecs;
+        self.indices = snapshot.indices;
+        self.timers = snapshot.timers;
+    }
+
+    /// Messages still waiting in the plugin's inboxes will never be read
+    fn discard_inbox(&mut self, plugin: &PluginState) {
+        for inbox in &plugin.inbox {
+            for (channel, msgs) in inbox {
+                for _ in msgs {
+                    self.metrics.record_unread(plugin.name(), channel);
+                }
+            }
+        }
+    }
+
+    /// Delete the unsaved entities and message indices of the given plugin
+    fn clear_plugin(&mut self, i: usize) {
+        // Delete all unsaved entities from that plugin
+        let owned = self
+            .ecs
+            .query(&Query::new().intersect::<PluginIndex>(Access::Read));
+        let unsaved: Vec<EntityId> = owned
+            .into_iter()
+            .filter(|&ent| self.ecs.get::<PluginIndex>(ent) == Some(PluginIndex(i)))
+            .filter(|&ent| self.ecs.get::<Saved>(ent).is_none())
+            .collect();
+        self.ecs.remove_entities(&unsaved);
+
+        // Delete message indices for that plugin
+        for channel in self.indices.values_mut() {
+            channel.retain(|(PluginIndex(j), _)| *j != i);
+        }
     }
 }
 
+/// Returns `true` if all of the system's run conditions hold
+fn should_run(
+    system: &SystemDescriptor,
+    inbox: &SystemInbox,
+    last_run: Option<FrameTime>,
+    time: &FrameTime,
+    ecs: &mut Ecs,
+) -> bool {
+    system.conditions.iter().all(|condition| match condition {
+        RunCondition::HasMessages => inbox.values().any(|msgs| !msgs.is_empty()),
+        RunCondition::EveryFrames(frames) => {
+            last_run.is_none_or(|last| time.tick.saturating_sub(last.tick) >= u64::from(*frames))
+        }
+        RunCondition::EverySeconds(seconds) => {
+            last_run.is_none_or(|last| time.time - last.time >= *seconds)
+        }
+        RunCondition::QueryNotEmpty(name) => match system.queries.get(name) {
+            Some(query) => !ecs.query(query).is_empty(),
+            None => {
+                log::error!("Run condition refers to unknown query {:?}", name);
+                false
+            }
+        },
+    })
+}
+
 /// Calculate the hash of a particular peice of data
 pub fn calculate_digest(data: &[u8]) -> Digest {
     Digest(xxhash_rust::xxh3::xxh3_128(data))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use interface::component_id;
+    use std::sync::{Mutex, Once};
+
+    /// Target, level and message of each record logged
+    pub type Logs = Mutex<Vec<(String, log::Level, String)>>;
+
+    /// Keeps the records logged by all tests
+    struct Capture(Logs);
+
+    impl log::Log for Capture {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            let entry = (
+                record.target().to_string(),
+                record.level(),
+                record.args().to_string(),
+            );
+            self.0.lock().unwrap().push(entry);
+        }
+
+        fn flush(&self) {}
+    }
+
+    static CAPTURE: Capture = Capture(Mutex::new(vec![]));
+
+    /// Capture log records, for checking what was logged. Shared by all tests
+    pub fn capture_logs() -> &'static Logs {
+        static INSTALL: Once = Once::new();
+        INSTALL.call_once(|| {
+            log::set_logger(&CAPTURE).unwrap();
+            log::set_max_level(log::LevelFilter::Trace);
+        });
+        &CAPTURE.0
+    }
+
+    #[derive(Component, Serialize, Deserialize, Default, Copy, Clone, Debug, PartialEq)]
+    struct Score(u32);
+
+    #[derive(Component, Serialize, Deserialize, Default, Copy, Clone, Debug, PartialEq)]
+    struct Label(u32);
+
+    /// A plugin which returns `init` when initialized, and `run` from every system
+    fn canned_plugin(init: &SendBuf, run: &SendBuf) -> Vec<u8> {
+        scripted_plugin(init, Some(run))
+    }
+
+    /// A plugin which returns `init` when initialized, and traps when any system runs
+    fn failing_plugin(init: &SendBuf) -> Vec<u8> {
+        scripted_plugin(init, None)
+    }
+
+    fn scripted_plugin(init: &SendBuf, run: Option<&SendBuf>) -> Vec<u8> {
+        // Length-delimited output, escaped for a WAT data segment
+        let segment = |buf: &SendBuf| -> (usize, String) {
+            let data = serialize(buf).unwrap();
+            let mut bytes = (data.len() as u32).to_le_bytes().to_vec();
+            bytes.extend(data);
+            let escaped = bytes.iter().map(|b| format!("\\{:02x}", b)).collect();
+            (bytes.len(), escaped)
+        };
+        let (init_len, init) = segment(init);
+        let trap = match run {
+            Some(_) => "",
+            None => "(if (i32.eqz (local.get $first)) (then unreachable))",
+        };
+        let (run_len, run) = run.map(segment).unwrap_or_default();
+        let run_ptr = init_len.next_multiple_of(8);
+        let input_ptr = (run_ptr + run_len).next_multiple_of(8);
+
+        format!(
+            r#"(module
+            (memory (export "memory") {pages})
+            (global $calls (mut i32) (i32.const 0))
+            (data (i32.const 0) "{init}")
+            (data (i32.const {run_ptr}) "{run}")
+            (func (export "_reserve") (param i32) (result i32) i32.const {input_ptr})
+            (func (export "_dispatch") (result i32)
+                (local $first i32)
+                (local.set $first (i32.eqz (global.get $calls)))
+                (global.set $calls (i32.add (global.get $calls) (i32.const 1)))
+                {trap}
+                (select (i32.const 0) (i32.const {run_ptr}) (local.get $first))))"#,
+            pages = input_ptr / 0x10000 + 2,
+        )
+        .into_bytes()
+    }
+
+    fn system(query: Query) -> SystemDescriptor {
+        SystemDescriptor {
+            queries: [("q".to_string(), query)].into_iter().collect(),
+            ..Default::default()
+        }
+    }
+
+    fn add<C: Component>(entity: EntityId, value: C) -> EcsCommand {
+        EcsCommand::AddComponent(entity, component_id::<C>(), serialize(&value).unwrap())
+    }
+
+    #[test]
+    fn test_parallel_dispatch_matches_sequential() {
+        let (a, b) = (EntityId(100), EntityId(101));
+        let score = |access| system(Query::new().intersect::<Score>(access));
+
+        let plugins = vec![
+            // Creates the entities, then keeps writing a score
+            (
+                "spawner".to_string(),
+                canned_plugin(
+                    &SendBuf {
+                        commands: vec![
+                            EcsCommand::Create(a),
+                            add(a, Score(0)),
+                            EcsCommand::Create(b),
+                            add(b, Score(0)),
+                        ],
+                        systems: vec![score(Access::Write)],
+                        ..Default::default()
+                    },
+                    &SendBuf {
+                        commands: vec![add(a, Score(1))],
+                        ..Default::default()
+                    },
+                ),
+            ),
+            // Conflicts with the spawner, so must run after it
+            (
+                "scorer".to_string(),
+                canned_plugin(
+                    &SendBuf {
+                        systems: vec![score(Access::Write)],
+                        ..Default::default()
+                    },
+                    &SendBuf {
+                        commands: vec![add(a, Score(2)), EcsCommand::Delete(b)],
+                        ..Default::default()
+                    },
+                ),
+            ),
+            // Runs alongside the scorer, adding a component it does not query
+            (
+                "labeler".to_string(),
+                canned_plugin(
+                    &SendBuf {
+                        systems: vec![system(Query::new())],
+                        ..Default::default()
+                    },
+                    &SendBuf {
+                        commands: vec![add(a, Label(7))],
+                        ..Default::default()
+                    },
+                ),
+            ),
+        ];
+
+        let run = |parallel| {
+            let cfg = Config {
+                parallel,
+                ..Default::default()
+            };
+            let mut engine = Engine::new(&plugins, cfg).unwrap();
+            engine.init_plugins().unwrap();
+            for _ in 0..3 {
+                engine.dispatch(Stage::PreUpdate).unwrap();
+                engine.dispatch(Stage::Update).unwrap();
+                engine.dispatch(Stage::PostUpdate).unwrap();
+            }
+
+            assert_eq!(engine.ecs().get::<Score>(a), Some(Score(2)));
+            assert_eq!(engine.ecs().get::<Label>(a), Some(Label(7)));
+            assert_eq!(engine.ecs().get::<Score>(b), None);
+
+            // Ownership is not replicated, so it does not show up in the exports below
+            assert_eq!(engine.ecs().get::<PluginIndex>(a), Some(PluginIndex(0)));
+
+            [
+                Query::new().intersect::<Score>(Access::Read),
+                Query::new().intersect::<Label>(Access::Read),
+            ]
+            .map(|query| engine.ecs().export(&query))
+        };
+
+        assert_eq!(run(false), run(true));
+    }
+
+    #[test]
+    fn test_save_scene() {
+        let a = EntityId(400);
+        let init = SendBuf {
+            commands: vec![EcsCommand::Create(a), add(a, Saved), add(a, Score(3))],
+            schemas: vec![Score::schema().unwrap()],
+            ..Default::default()
+        };
+        let plugins = vec![(
+            "saver".to_string(),
+            canned_plugin(&init, &SendBuf::default()),
+        )];
+        let cfg = Config {
+            is_server: true,
+            ..Default::default()
+        };
+        let mut engine = Engine::new(&plugins, cfg).unwrap();
+        engine.init_plugins().unwrap();
+
+        // Plugin ownership is left out
+        let saved = Query::new().intersect::<Saved>(Access::Read);
+        let scene = engine.save_scene(&saved);
+        assert_eq!(scene.entities.len(), 1);
+        let components: Vec<&str> = scene.entities[0]
+            .components
+            .keys()
+            .map(String::as_str)
+            .collect();
+        assert_eq!(components, [Score::ID, Saved::ID]);
+
+        let entities = engine.load_scene(&scene).unwrap();
+        assert_eq!(entities.len(), 1);
+        assert_ne!(entities[0], a);
+        assert_eq!(engine.ecs().get::<Score>(entities[0]), Some(Score(3)));
+        assert_eq!(engine.ecs().get::<PluginIndex>(entities[0]), None);
+    }
+
+    #[test]
+    fn test_reload_migrates_components() {
+        let logs = capture_logs();
+        let (a, b) = (EntityId(200), EntityId(201));
+
+        // Layouts from before a field was added to each component
+        let old = |id: &str| ComponentId {
+            id: id.into(),
+            size: 2,
+        };
+        let (old_score, old_label) = (old(Score::ID), old(Label::ID));
+
+        // Saved entities survive the reload, along with their outdated data
+        let v1 = canned_plugin(
+            &SendBuf {
+                commands: vec![
+                    EcsCommand::Create(a),
+                    add(a, Saved),
+                    EcsCommand::AddComponent(a, old_score.clone(), vec![7, 0]),
+                    EcsCommand::Create(b),
+                    add(b, Saved),
+                    EcsCommand::AddComponent(b, old_label.clone(), vec![1, 0]),
+                ],
+                ..Default::default()
+            },
+            &SendBuf::default(),
+        );
+
+        // Queries and converts scores; adds labels, which it cannot convert. The second output
+        // answers the request to convert the old scores
+        let v2 = canned_plugin(
+            &SendBuf {
+                systems: vec![system(Query::new().intersect::<Score>(Access::Write))],
+                migrations: vec![component_id::<Score>()],
+                components: vec![component_id::<Label>()],
+                ..Default::default()
+            },
+            &SendBuf {
+                commands: vec![add(a, Score(7))],
+                ..Default::default()
+            },
+        );
+
+        let cfg = Config::default();
+        let mut engine = Engine::new(&[("game.wasm".to_string(), v1)], cfg).unwrap();
+        engine.init_plugins().unwrap();
+        engine.reload("game.wasm".to_string(), &v2).unwrap();
+
+        // Converted data replaces the old
+        assert_eq!(engine.ecs().get::<Score>(a), Some(Score(7)));
+        assert!(engine.ecs().get_raw(a, &old_score).is_none());
+
+        // Data which cannot be converted is left in place, and reported
+        assert!(engine.ecs().get_raw(b, &old_label).is_some());
+        let logged = logs.lock().unwrap();
+        assert!(logged.iter().any(|(_, level, message)| {
+            *level == log::Level::Warn && message.contains(Label::ID) && message.contains("game")
+        }));
+    }
+
+    #[test]
+    fn test_failed_load_is_not_kept() {
+        let cfg = Config::default();
+        let mut engine = Engine::new(&[], cfg).unwrap();
+
+        let broken = r#"(module
+            (memory (export "memory") 1)
+            (func (export "_reserve") (param i32) (result i32) i32.const 0)
+            (func (export "_dispatch") (result i32) unreachable))"#;
+        assert!(engine
+            .load_plugin("broken.wasm".into(), broken.as_bytes())
+            .is_err());
+        assert!(!engine.is_loaded("broken.wasm"));
+
+        // The plugin may be loaded once fixed
+        let fixed = canned_plugin(&SendBuf::default(), &SendBuf::default());
+        engine.load_plugin("broken.wasm".into(), &fixed).unwrap();
+        assert!(engine.is_loaded("broken.wasm"));
+    }
+
+    #[test]
+    fn test_failed_start_leaves_engine_unchanged() {
+        let channel = ChannelId {
+            id: "test/Ping".into(),
+            locality: Locality::Local,
+        };
+        let (a, b, c) = (EntityId(300), EntityId(301), EntityId(302));
+
+        // Creates an entity, and subscribes a system which runs in PostInit
+        let init = |entity| SendBuf {
+            commands: vec![EcsCommand::Create(entity), add(entity, Score(1))],
+            systems: vec![SystemDescriptor {
+                stage: Stage::PostInit,
+                subscriptions: vec![channel.clone()],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let idle = SendBuf::default();
+
+        let cfg = Config::default();
+        let plugins = vec![("a.wasm".to_string(), canned_plugin(&init(a), &idle))];
+        let mut engine = Engine::new(&plugins, cfg).unwrap();
+        engine.init_plugins().unwrap();
+
+        // A new plugin which fails in PostInit is not kept
+        let indices = engine.indices.clone();
+        let result = engine.load_plugin("b.wasm".into(), &failing_plugin(&init(b)));
+        assert!(result.is_err());
+        assert!(!engine.is_loaded("b.wasm"));
+        assert!(!engine.ecs().is_alive(b));
+        assert_eq!(engine.indices, indices);
+
+        // ... and may be loaded once fixed
+        let fixed = canned_plugin(&init(b), &idle);
+        engine.load_plugin("b.wasm".into(), &fixed).unwrap();
+        assert!(engine.ecs().is_alive(b));
+
+        // A reloaded plugin which fails in PostInit leaves the old instance running
+        let indices = engine.indices.clone();
+        let result = engine.reload("a.wasm".into(), &failing_plugin(&init(c)));
+        assert!(result.is_err());
+        assert_eq!(engine.ecs().get::<Score>(a), Some(Score(1)));
+        assert!(!engine.ecs().is_alive(c));
+        assert_eq!(engine.indices, indices);
+        engine.dispatch(Stage::PostInit).unwrap();
+    }
+
+    #[test]
+    fn test_schedule_commands() {
+        let channel = |id: &str| ChannelId {
+            id: id.into(),
+            locality: Locality::Local,
+        };
+        let (a, b) = (channel("test/A"), channel("test/B"));
+        let message = |channel: &ChannelId| MessageData {
+            channel: channel.clone(),
+            data: vec![],
+            client: None,
+        };
+
+        // The first system subscribes to B itself once running; the second starts out disabled
+        let init = SendBuf {
+            systems: vec![
+                SystemDescriptor {
+                    subscriptions: vec![a.clone()],
+                    ..Default::default()
+                },
+                SystemDescriptor {
+                    subscriptions: vec![b.clone()],
+                    enabled: false,
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        let run = SendBuf {
+            schedule: vec![ScheduleCommand::Subscribe(SystemId(0), b.clone())],
+            ..Default::default()
+        };
+        let plugins = vec![("listener".to_string(), canned_plugin(&init, &run))];
+
+        let cfg = Config::default();
+        let mut engine = Engine::new(&plugins, cfg).unwrap();
+        engine.init_plugins().unwrap();
+
+        let destinations = |engine: &Engine, channel: &ChannelId| {
+            let indices = engine.indices.get(channel).into_iter().flatten();
+            indices.map(|&(_, sys)| sys).collect::<Vec<_>>()
+        };
+        let unread = |engine: &Engine, sys: usize, channel: &ChannelId| {
+            let inbox = &engine.plugins[0].inbox[sys];
+            inbox.get(channel).map_or(0, |msgs| msgs.len())
+        };
+        assert_eq!(destinations(&engine, &a), [0]);
+        assert_eq!(destinations(&engine, &b), [] as [usize; 0]);
+
+        // Subscribing again has no effect
+        for _ in 0..2 {
+            engine.dispatch(Stage::Update).unwrap();
+        }
+        let subscriptions = &engine.plugins[0].systems[0].subscriptions;
+        assert_eq!(subscriptions, &[a.clone(), b.clone()]);
+        assert_eq!(destinations(&engine, &b), [0]);
+
+        // Unsubscribing discards unread messages on that channel only
+        engine.broadcast(message(&a));
+        engine.broadcast(message(&b));
+        let unsubscribe = ScheduleCommand::Unsubscribe(SystemId(0), a.clone());
+        engine.apply_schedule_commands(0, vec![unsubscribe]);
+        assert_eq!(destinations(&engine, &a), [] as [usize; 0]);
+        assert_eq!((unread(&engine, 0, &a), unread(&engine, 0, &b)), (0, 1));
+
+        // Enabling routes the system's existing subscriptions to it, once
+        let enable = ScheduleCommand::Enable(SystemId(1));
+        engine.apply_schedule_commands(0, vec![enable.clone(), enable]);
+        assert_eq!(destinations(&engine, &b), [0, 1]);
+
+        // Disabling discards unread messages, and stops delivery
+        engine.apply_schedule_commands(0, vec![ScheduleCommand::Disable(SystemId(0))]);
+        assert_eq!(destinations(&engine, &b), [1]);
+        assert_eq!(unread(&engine, 0, &b), 0);
+        engine.broadcast(message(&b));
+        assert_eq!((unread(&engine, 0, &b), unread(&engine, 1, &b)), (0, 1));
+
+        // Subscriptions made while disabled take effect when enabled
+        let subscribe = ScheduleCommand::Subscribe(SystemId(0), a.clone());
+        engine.apply_schedule_commands(0, vec![subscribe]);
+        assert_eq!(destinations(&engine, &a), [] as [usize; 0]);
+        engine.apply_schedule_commands(0, vec![ScheduleCommand::Enable(SystemId(0))]);
+        assert_eq!(destinations(&engine, &a), [0]);
+        assert_eq!(destinations(&engine, &b), [1, 0]);
+
+        // Commands for systems which do not exist are ignored
+        engine.apply_schedule_commands(0, vec![ScheduleCommand::Disable(SystemId(2))]);
+        assert_eq!(destinations(&engine, &b), [1, 0]);
+    }
+
+    fn frame(tick: u64) -> FrameTime {
+        FrameTime {
+            delta: 0.1,
+            time: tick as f32 / 10.,
+            tick,
+            alpha: 1.,
+            paused: false,
+            time_scale: 1.,
+        }
+    }
+
+    #[test]
+    fn test_run_conditions() {
+        let mut ecs = Ecs::new();
+        let mut inbox = HashMap::new();
+        let mut system = SystemDescriptor {
+            conditions: vec![
+                RunCondition::EveryFrames(3),
+                RunCondition::EverySeconds(0.5),
+            ],
+            ..Default::default()
+        };
+
+        // Rate limits
+        let mut run = |system: &SystemDescriptor, last, now| {
+            should_run(system, &HashMap::new(), last, &frame(now), &mut ecs)
+        };
+        assert!(run(&system, None, 0));
+        assert!(!run(&system, Some(frame(0)), 3));
+        assert!(run(&system, Some(frame(0)), 5));
+
+        // Messages
+        system.conditions = vec![RunCondition::HasMessages];
+        let channel = ChannelId::from(FrameTime::CHANNEL);
+        assert!(!should_run(&system, &inbox, None, &frame(0), &mut ecs));
+        inbox.insert(channel.clone(), VecDeque::new());
+        assert!(!should_run(&system, &inbox, None, &frame(0), &mut ecs));
+        let msg = MessageData {
+            channel: channel.clone(),
+            client: None,
+            data: vec![],
+        };
+        inbox.insert(channel, VecDeque::from([msg]));
+        assert!(should_run(&system, &inbox, None, &frame(0), &mut ecs));
+
+        // Queries
+        let query = Query::new().intersect::<Saved>(Access::Read);
+        system.queries.insert("Saved".into(), query);
+        system.conditions = vec![RunCondition::QueryNotEmpty("Saved".into())];
+        assert!(!should_run(&system, &inbox, None, &frame(0), &mut ecs));
+        let entity = ecs.create_entity();
+        ecs.add_component(entity, &Saved);
+        assert!(should_run(&system, &inbox, None, &frame(0), &mut ecs));
+    }
+}
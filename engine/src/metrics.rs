@@ -0,0 +1,198 @@
+use std::collections::{HashMap, VecDeque};
+
+use cimvr_engine_interface::prelude::*;
+
+/// Message counters for a single channel or plugin. Per-plugin counters are attributed to the
+/// sender of the messages, except for `dropped_unread`
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MessageCounters {
+    /// Number of messages sent
+    pub messages: u64,
+    /// Total size of the data carried by those messages, in bytes
+    pub bytes: u64,
+    /// Number of messages lost on the way, either pushed out of a full inbox or addressed to a
+    /// client which disconnected. A full inbox is blamed on the sender of the message which did
+    /// not fit
+    pub dropped_sent: u64,
+    /// Number of messages still unread when the plugin receiving them was unloaded. Attributed to
+    /// the receiving plugin
+    pub dropped_unread: u64,
+    /// Number of local messages which had no destination
+    pub undelivered: u64,
+}
+
+/// A message recorded by the tap
+#[derive(Clone, Debug)]
+pub struct TappedMessage {
+    /// Name of the plugin which sent the message. None if sent by the host or the remote
+    pub sender: Option<String>,
+    /// Frame on which the message was sent
+    pub tick: u64,
+    /// The message itself
+    pub message: MessageData,
+}
+
+/// Per-channel and per-plugin message counters, and an optional tap of recent messages
+#[derive(Default)]
+pub struct MessageMetrics {
+    channels: HashMap<ChannelId, MessageCounters>,
+    plugins: HashMap<String, MessageCounters>,
+    /// Maximum number of messages kept by the tap. The tap is disabled if zero
+    tap_capacity: usize,
+    /// Most recent messages, oldest first
+    tap: VecDeque<TappedMessage>,
+}
+
+impl MessageMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counters for each channel which has seen traffic
+    pub fn channels(&self) -> &HashMap<ChannelId, MessageCounters> {
+        &self.channels
+    }
+
+    /// Counters for each plugin which has sent messages, or had unread messages discarded when it
+    /// was unloaded. See `MessageCounters` for how messages are attributed
+    pub fn plugins(&self) -> &HashMap<String, MessageCounters> {
+        &self.plugins
+    }
+
+    /// Keep up to this many of the most recent messages. Zero disables the tap
+    pub fn set_tap_capacity(&mut self, capacity: usize) {
+        self.tap_capacity = capacity;
+        while self.tap.len() > capacity {
+            self.tap.pop_front();
+        }
+    }
+
+    /// Maximum number of messages kept by the tap
+    pub fn tap_capacity(&self) -> usize {
+        self.tap_capacity
+    }
+
+    /// Most recently sent messages, oldest first
+    pub fn tap(&self) -> impl Iterator<Item = &TappedMessage> {
+        self.tap.iter()
+    }
+
+    /// Reset all counters and clear the tap
+    pub fn reset(&mut self) {
+        self.channels.clear();
+        self.plugins.clear();
+        self.tap.clear();
+    }
+
+    /// Record a message being sent
+    pub fn record_sent(&mut self, sender: Option<&str>, tick: u64, msg: &MessageData) {
+        let bytes = msg.data.len() as u64;
+        self.count(sender, &msg.channel, |c| {
+            c.messages += 1;
+            c.bytes += bytes;
+        });
+
+        if self.tap_capacity > 0 {
+            if self.tap.len() >= self.tap_capacity {
+                self.tap.pop_front();
+            }
+            self.tap.push_back(TappedMessage {
+                sender: sender.map(str::to_string),
+                tick,
+                message: msg.clone(),
+            });
+        }
+    }
+
+    /// Record a message being lost before it reached its destination
+    pub fn record_dropped(&mut self, sender: Option<&str>, channel: &ChannelId) {
+        self.count(sender, channel, |c| c.dropped_sent += 1);
+    }
+
+    /// Record a message being discarded unread because the plugin receiving it was unloaded
+    pub fn record_unread(&mut self, receiver: &str, channel: &ChannelId) {
+        self.count(Some(receiver), channel, |c| c.dropped_unread += 1);
+    }
+
+    /// Record a local message which had no destination
+    pub fn record_undelivered(&mut self, sender: Option<&str>, channel: &ChannelId) {
+        self.count(sender, channel, |c| c.undelivered += 1);
+    }
+
+    fn count(
+        &mut self,
+        plugin: Option<&str>,
+        channel: &ChannelId,
+        f: impl Fn(&mut MessageCounters),
+    ) {
+        // Avoid cloning the channel ID in the common case
+        match self.channels.get_mut(channel) {
+            Some(counters) => f(counters),
+            None => f(self.channels.entry(channel.clone()).or_default()),
+        }
+
+        if let Some(plugin) = plugin {
+            match self.plugins.get_mut(plugin) {
+                Some(counters) => f(counters),
+                None => f(self.plugins.entry(plugin.to_string()).or_default()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: &str, len: usize) -> MessageData {
+        MessageData {
+            channel: ChannelId {
+                id: id.into(),
+                locality: Locality::Local,
+            },
+            data: vec![0; len],
+            client: None,
+        }
+    }
+
+    #[test]
+    fn test_message_metrics() {
+        let mut metrics = MessageMetrics::new();
+        metrics.set_tap_capacity(2);
+
+        let a = message("a", 3);
+        let b = message("b", 5);
+        metrics.record_sent(Some("plugin"), 0, &a);
+        metrics.record_sent(Some("plugin"), 0, &b);
+        metrics.record_sent(None, 1, &a);
+        metrics.record_undelivered(None, &a.channel);
+        metrics.record_dropped(Some("plugin"), &b.channel);
+        metrics.record_unread("receiver", &a.channel);
+
+        let counters = metrics.channels()[&a.channel];
+        assert_eq!((counters.messages, counters.bytes), (2, 6));
+        assert_eq!((counters.undelivered, counters.dropped_sent), (1, 0));
+        assert_eq!(counters.dropped_unread, 1);
+
+        // Host messages are not attributed to any plugin
+        let counters = metrics.plugins()["plugin"];
+        assert_eq!(
+            (counters.messages, counters.bytes, counters.dropped_sent),
+            (2, 8, 1)
+        );
+
+        // Unread messages are attributed to the receiver
+        let counters = metrics.plugins()["receiver"];
+        assert_eq!((counters.messages, counters.dropped_unread), (0, 1));
+
+        // Only the most recent messages are kept
+        let tapped: Vec<_> = metrics
+            .tap()
+            .map(|t| t.message.channel.id.as_str())
+            .collect();
+        assert_eq!(tapped, ["b", "a"]);
+
+        metrics.reset();
+        assert!(metrics.channels().is_empty() && metrics.tap().next().is_none());
+    }
+}
@@ -3,24 +3,76 @@ use cimvr_engine_interface::{
     serial::{serialize_into, serialized_size},
 };
 use serde::{Deserialize, Serialize};
-use std::io::{self, Read, Write};
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    time::Instant,
+};
 
 use crate::ecs::EcsMap;
 
 /// Message packet sent from server to client(s)
 #[derive(Clone, Serialize, Deserialize)]
 pub struct ServerToClient {
-    /// All ECS data with an associated `Synchronized` component attached
+    /// ECS data with an associated `Synchronized` component attached, limited to the entities
+    /// relevant to this client
     pub ecs: EcsMap,
     pub messages: Vec<MessageData>,
     /// Hotload the plugin with this name (String) using the given bytecode (Vec<u8>)
     pub hotload: Vec<(String, Vec<u8>)>,
+    /// Unload the plugins with these names
+    pub unload: Vec<String>,
+    /// Server clock (seconds since server start) when this packet was sent
+    pub server_time: f64,
+    /// Reply to the client's most recent clock ping, if any
+    pub pong: Option<ClockPong>,
 }
 
 /// Message packet sent from client to server
 #[derive(Clone, Serialize, Deserialize)]
 pub struct ClientToServer {
     pub messages: Vec<MessageData>,
+    /// Clock synchronization request
+    pub ping: Option<ClockPing>,
+    /// Position the client is viewing the world from, used to decide which entities are relevant
+    pub viewpoint: Option<[f32; 3]>,
+    /// Client-writable components of the entities owned by this client
+    pub ecs: EcsMap,
+}
+
+/// Clock synchronization request, sent from client to server
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct ClockPing {
+    /// Client clock when the ping was sent
+    pub client_send: f64,
+    /// The client's current estimate of the round trip time, in seconds, reported for statistics
+    pub rtt: Option<f32>,
+    /// The client's current estimate of the clock offset, in seconds, reported for statistics
+    pub offset: Option<f64>,
+}
+
+/// Clock synchronization response, sent from server to client
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct ClockPong {
+    /// Client clock when the corresponding ping was sent
+    pub client_send: f64,
+    /// Server clock when the ping was received
+    pub server_recv: f64,
+    /// Server clock when this pong was sent
+    pub server_send: f64,
+}
+
+/// Monotonic clock measuring seconds since its creation
+#[derive(Clone, Copy, Debug)]
+pub struct NetClock {
+    epoch: Instant,
+}
+
+/// Estimates the round trip time and the offset between the local and remote clocks, NTP-style
+pub struct ClockSync {
+    clock: NetClock,
+    /// Recent (round trip time, offset) samples
+    samples: VecDeque<(f64, f64)>,
 }
 
 /// Facilitates reading a little-endian length header, and then a message body over a reliable,
@@ -105,9 +157,137 @@ impl AsyncBufferedReceiver {
     }
 }
 
+impl NetClock {
+    pub fn new() -> Self {
+        Self {
+            epoch: Instant::now(),
+        }
+    }
+
+    /// Seconds since this clock was created
+    pub fn now(&self) -> f64 {
+        self.epoch.elapsed().as_secs_f64()
+    }
+}
+
+impl Default for NetClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Default for ClockSync {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ClockSync {
+    /// Number of samples to keep. The sample with the smallest round trip time is used, since it
+    /// is the least affected by queueing delays
+    const WINDOW: usize = 16;
+
+    pub fn new() -> Self {
+        Self {
+            clock: NetClock::new(),
+            samples: VecDeque::new(),
+        }
+    }
+
+    /// Current local time, in seconds
+    pub fn local_time(&self) -> f64 {
+        self.clock.now()
+    }
+
+    /// Create a ping to be sent to the server
+    pub fn ping(&self) -> ClockPing {
+        ClockPing {
+            client_send: self.local_time(),
+            rtt: self.rtt(),
+            offset: self.offset(),
+        }
+    }
+
+    /// Record the response to a ping
+    pub fn pong(&mut self, pong: ClockPong) {
+        self.record(pong, self.local_time())
+    }
+
+    fn record(&mut self, pong: ClockPong, client_recv: f64) {
+        let ClockPong {
+            client_send,
+            server_recv,
+            server_send,
+        } = pong;
+
+        let rtt = (client_recv - client_send) - (server_send - server_recv);
+        let offset = ((server_recv - client_send) + (server_send - client_recv)) / 2.;
+
+        if self.samples.len() == Self::WINDOW {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((rtt.max(0.), offset));
+    }
+
+    fn best_sample(&self) -> Option<(f64, f64)> {
+        self.samples
+            .iter()
+            .copied()
+            .min_by(|a, b| a.0.total_cmp(&b.0))
+    }
+
+    /// Estimated round trip time, in seconds
+    pub fn rtt(&self) -> Option<f32> {
+        self.best_sample().map(|(rtt, _)| rtt as f32)
+    }
+
+    /// Estimated offset of the remote clock relative to the local clock, in seconds
+    pub fn offset(&self) -> Option<f64> {
+        self.best_sample().map(|(_, offset)| offset)
+    }
+
+    /// Estimated current time on the remote clock
+    pub fn remote_time(&self) -> Option<f64> {
+        Some(self.local_time() + self.offset()?)
+    }
+}
+
 pub fn length_delimit_message<W: Write, T: Serialize>(obj: &T, mut w: W) -> anyhow::Result<()> {
     let size = serialized_size(obj)?;
     let header = (size as u32).to_le_bytes();
     w.write_all(&header)?;
     Ok(serialize_into(w, obj)?)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clock_sync() {
+        let mut sync = ClockSync::new();
+        assert!(sync.offset().is_none());
+
+        // Server clock is 100 seconds ahead, 20ms each way, 5ms processing
+        sync.record(
+            ClockPong {
+                client_send: 1.0,
+                server_recv: 101.02,
+                server_send: 101.025,
+            },
+            1.045,
+        );
+        // A slower sample, with asymmetric delay
+        sync.record(
+            ClockPong {
+                client_send: 2.0,
+                server_recv: 102.2,
+                server_send: 102.205,
+            },
+            2.225,
+        );
+
+        assert!((sync.rtt().unwrap() - 0.04).abs() < 1e-6);
+        assert!((sync.offset().unwrap() - 100.).abs() < 1e-6);
+    }
+}
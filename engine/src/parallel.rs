@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+
+use cimvr_engine_interface::prelude::*;
+
+/// Splits systems, given in the order in which they would run sequentially, into batches which may
+/// be run in parallel. Batches run one after another, and preserve the sequential order of any two
+/// systems which conflict.
+///
+/// Systems within a batch belong to different plugins (each plugin has one `Store`), and do not
+/// access the same component unless all of them only read it.
+pub fn batches<'a>(
+    systems: impl IntoIterator<Item = (usize, usize, &'a HashMap<String, Query>)>,
+) -> Vec<Vec<(usize, usize)>> {
+    let mut batches: Vec<Vec<(usize, usize)>> = vec![];
+    let mut plugins: Vec<usize> = vec![];
+    let mut accesses: HashMap<&ComponentId, Access> = HashMap::new();
+
+    for (plugin, system, queries) in systems {
+        let components = queries.values().flat_map(|query| &query.intersect);
+
+        let conflicts = plugins.contains(&plugin)
+            || components
+                .clone()
+                .any(|qc| match accesses.get(&qc.component) {
+                    Some(Access::Write) => true,
+                    Some(Access::Read) => qc.access == Access::Write,
+                    None => false,
+                });
+
+        if conflicts || batches.is_empty() {
+            batches.push(vec![]);
+            plugins.clear();
+            accesses.clear();
+        }
+
+        for qc in components {
+            let access = accesses.entry(&qc.component).or_insert(qc.access);
+            if qc.access == Access::Write {
+                *access = Access::Write;
+            }
+        }
+        plugins.push(plugin);
+        batches.last_mut().unwrap().push((plugin, system));
+    }
+
+    batches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pkg_namespace;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Component, Serialize, Deserialize, Default, Copy, Clone)]
+    struct A;
+
+    #[derive(Component, Serialize, Deserialize, Default, Copy, Clone)]
+    struct B;
+
+    fn queries(query: Query) -> HashMap<String, Query> {
+        [("q".to_string(), query)].into_iter().collect()
+    }
+
+    #[test]
+    fn test_batches() {
+        let read_a = queries(Query::new().intersect::<A>(Access::Read));
+        let write_a = queries(Query::new().intersect::<A>(Access::Write));
+        let write_b = queries(Query::new().intersect::<B>(Access::Write));
+        let none = HashMap::new();
+
+        let systems = [
+            // Readers share a batch, as do writers of different components
+            (0, 0, &read_a),
+            (1, 0, &read_a),
+            (2, 0, &write_b),
+            // Conflicting write
+            (3, 0, &write_a),
+            // Conflicting read
+            (4, 0, &read_a),
+            // Same plugin
+            (4, 1, &none),
+            (5, 0, &none),
+        ];
+
+        assert_eq!(
+            batches(systems),
+            vec![
+                vec![(0, 0), (1, 0), (2, 0)],
+                vec![(3, 0)],
+                vec![(4, 0)],
+                vec![(4, 1), (5, 0)],
+            ]
+        );
+    }
+}
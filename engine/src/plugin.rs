@@ -1,61 +1,190 @@
-use anyhow::{Context, Result};
+use anyhow::{format_err, Context, Result};
 use cimvr_engine_interface::serial::{
     deserialize, serialize_into, serialized_size, ReceiveBuf, SendBuf,
 };
+use cimvr_engine_interface::stdout::LogRecord;
 use rand::prelude::*;
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
 use std::io::Cursor;
-use wasmtime::{Caller, Extern, Func, Instance, Memory, Module, Store, TypedFunc};
+use std::time::{Duration, Instant};
+use wasmtime::{Caller, Instance, IntoFunc, Linker, Memory, Module, Store, TypedFunc};
+
+/// Module under which plugins import host functions
+const HOST_MODULE: &str = "env";
+
+/// Time spent in each phase of a call into a plugin
+#[derive(Default, Clone, Copy, Debug)]
+pub struct DispatchTimings {
+    /// Writing the input into the plugin's memory
+    pub serialize: Duration,
+    /// Running plugin code
+    pub call: Duration,
+    /// Reading the plugin's output
+    pub deserialize: Duration,
+}
+
+/// Additional native functions made available to plugins by the host, on top of the built-in
+/// ones. Plugins declare them with `cimvr_engine_interface::host_functions!`
+#[derive(Default)]
+pub struct HostFunctions {
+    definitions: Vec<Definition>,
+}
+
+/// Adds a host function to a linker
+type Definition = Box<dyn Fn(&mut Linker<PluginContext>) -> Result<()> + Send + Sync>;
+
+/// Per-plugin state available to host functions, through `Caller::data()`
+pub struct PluginContext {
+    /// Name of the plugin
+    name: String,
+    /// Data attached by host functions, by type
+    data: HashMap<TypeId, Box<dyn Any + Send>>,
+}
 
 #[allow(dead_code)]
 pub struct Plugin {
-    store: Store<()>,
+    store: Store<PluginContext>,
     module: Module,
     instance: Instance,
     mem: Memory,
-    print_fn: Func,
-    random_fn: Func,
     dispatch_fn: TypedFunc<(), u32>,
     reserve_fn: TypedFunc<u32, u32>,
 }
 
-impl Plugin {
-    /// Load the plugin in an uninitialized state
-    pub fn new(wt: &wasmtime::Engine, code: &[u8]) -> Result<Self> {
-        let module = Module::new(wt, &code)?;
-        let mut store = Store::new(wt, ());
+impl HostFunctions {
+    /// Make `func` available to plugins under the given name. Its parameters and results must be
+    /// WASM value types (integers and floats), and must match the plugin's declaration. It may
+    /// take a `Caller<'_, PluginContext>` as its first parameter, to access the calling plugin's
+    /// context and memory (see `read_memory` and `write_memory`).
+    pub fn add<Params, Args>(
+        &mut self,
+        name: &str,
+        func: impl IntoFunc<PluginContext, Params, Args> + Clone,
+    ) -> &mut Self {
+        let name = name.to_string();
+        self.definitions.push(Box::new(move |linker| {
+            linker
+                .func_wrap(HOST_MODULE, &name, func.clone())
+                .with_context(|| format_err!("Defining host function {}", name))?;
+            Ok(())
+        }));
+        self
+    }
+
+    /// Create a linker providing the built-in host functions, along with these
+    pub(crate) fn linker(&self, wt: &wasmtime::Engine) -> Result<Linker<PluginContext>> {
+        let mut linker = Linker::new(wt);
 
         // Basic printing functionality
-        let print_fn = Func::wrap(
-            &mut store,
-            |mut caller: Caller<'_, ()>, ptr: u32, len: u32| {
-                // TODO: Shorten this
-                let mem = caller.get_export("memory").unwrap().into_memory().unwrap();
-                let mut buf = vec![0; len as usize];
-                mem.read(caller, ptr as usize, &mut buf).unwrap();
-                let s = String::from_utf8(buf).unwrap();
+        linker.func_wrap(
+            HOST_MODULE,
+            "_print",
+            |mut caller: Caller<'_, PluginContext>, ptr: u32, len: u32| {
+                let buf = read_memory(&mut caller, ptr, len)?;
+                let s = String::from_utf8(buf)?;
                 print!("{}", s);
+                Ok(())
             },
-        );
+        )?;
+
+        // Log records, re-emitted with the plugin's name as the target
+        linker.func_wrap(
+            HOST_MODULE,
+            "_log",
+            |mut caller: Caller<'_, PluginContext>, ptr: u32, len: u32| {
+                let buf = read_memory(&mut caller, ptr, len)?;
+                let record: LogRecord = deserialize(Cursor::new(buf))?;
+                emit_log(caller.data().name(), &record);
+                Ok(())
+            },
+        )?;
 
         // Random number "syscall". TODO: Include this in SendBuf instead?
-        let random_fn = Func::wrap(&mut store, || rand::thread_rng().gen::<u64>());
+        linker.func_wrap(HOST_MODULE, "_random", || rand::thread_rng().gen::<u64>())?;
+
+        for define in &self.definitions {
+            define(&mut linker)?;
+        }
+
+        Ok(linker)
+    }
+}
+
+impl PluginContext {
+    /// Name of the plugin
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Data of type `T` attached to this plugin, created with `Default` on first use
+    pub fn get_mut<T: Default + Send + 'static>(&mut self) -> &mut T {
+        self.data
+            .entry(TypeId::of::<T>())
+            .or_insert_with(|| Box::new(T::default()))
+            .downcast_mut()
+            .expect("Mismatched plugin context type")
+    }
+}
+
+/// Copy `len` bytes at `ptr` out of the calling plugin's memory
+pub fn read_memory(caller: &mut Caller<'_, PluginContext>, ptr: u32, len: u32) -> Result<Vec<u8>> {
+    let mem = plugin_memory(caller)?;
+    let mut buf = vec![0; len as usize];
+    mem.read(caller, ptr as usize, &mut buf)?;
+    Ok(buf)
+}
+
+/// Copy the given bytes into the calling plugin's memory at `ptr`
+pub fn write_memory(caller: &mut Caller<'_, PluginContext>, ptr: u32, data: &[u8]) -> Result<()> {
+    let mem = plugin_memory(caller)?;
+    mem.write(caller, ptr as usize, data)?;
+    Ok(())
+}
+
+/// Log a plugin's record under the name of the plugin (without `.wasm`), so that it may be
+/// filtered per plugin, e.g. `RUST_LOG=avatars=debug`
+fn emit_log(plugin: &str, record: &LogRecord) {
+    let Some(level) = record.level() else { return };
+    let target = plugin.strip_suffix(".wasm").unwrap_or(plugin);
+    log::logger().log(
+        &log::Record::builder()
+            .level(level)
+            .target(target)
+            .module_path(Some(&record.target))
+            .file(record.file.as_deref())
+            .line(record.line)
+            .args(format_args!("{}", record.message))
+            .build(),
+    );
+}
+
+fn plugin_memory(caller: &mut Caller<'_, PluginContext>) -> Result<Memory> {
+    caller
+        .get_export("memory")
+        .and_then(|export| export.into_memory())
+        .context("Plugin has no memory")
+}
+
+impl Plugin {
+    /// Load the plugin in an uninitialized state
+    pub fn new(linker: &Linker<PluginContext>, name: &str, code: &[u8]) -> Result<Self> {
+        let module = Module::new(linker.engine(), &code)?;
+        let context = PluginContext {
+            name: name.to_string(),
+            data: HashMap::new(),
+        };
+        let mut store = Store::new(linker.engine(), context);
 
-        let mut imports: Vec<Extern> = vec![];
         let warn = "Did you try to use an IO function from outside the sandbox like File::open()?\n\
                     You may only use io functions supplied by the host! Maybe you want include_bytes!()";
         for imp in module.imports() {
-            match (imp.name(), imp.ty()) {
-                ("_print", wasmtime::ExternType::Func(_)) => {
-                    imports.push(print_fn.into());
-                }
-                ("_random", wasmtime::ExternType::Func(_)) => {
-                    imports.push(random_fn.into());
-                }
-                _ => log::warn!("{}\nUnhandled import {:#?}", warn, imp),
+            if linker.get(&mut store, imp.module(), imp.name()).is_none() {
+                log::warn!("{}\nUnhandled import {:#?}", warn, imp);
             }
         }
 
-        let instance = Instance::new(&mut store, &module, &imports)?;
+        let instance = linker.instantiate(&mut store, &module)?;
 
         let mem = instance.get_memory(&mut store, "memory").unwrap();
 
@@ -65,10 +194,8 @@ impl Plugin {
         let reserve_fn = instance.get_typed_func::<u32, u32>(&mut store, "_reserve")?;
 
         Ok(Self {
-            random_fn,
             mem,
             module,
-            print_fn,
             store,
             instance,
             dispatch_fn,
@@ -77,7 +204,9 @@ impl Plugin {
     }
 
     /// Dispatch plugin internals with given intent
-    pub fn dispatch(&mut self, recv: &ReceiveBuf) -> Result<SendBuf> {
+    pub fn dispatch(&mut self, recv: &ReceiveBuf) -> Result<(SendBuf, DispatchTimings)> {
+        let start = Instant::now();
+
         // Rerve needed space within the plugin's memory
         let size = serialized_size(&recv)?;
         let ptr = self
@@ -89,12 +218,14 @@ impl Plugin {
         let mem = self.mem.data_mut(&mut self.store);
         let cursor = Cursor::new(&mut mem[ptr as usize..][..size]);
         serialize_into(cursor, &recv).expect("Serializing plugin input. This is a bug!");
+        let serialized = Instant::now();
 
         // Call the plugin
         let ptr = self
             .dispatch_fn
             .call(&mut self.store, ())
             .context("Dispatch")?;
+        let called = Instant::now();
 
         // Also deserialize directly from the module's memory
         let mem = self.mem.data_mut(&mut self.store);
@@ -109,6 +240,92 @@ impl Plugin {
         let slice = &forever_after[..payload_len];
 
         // Deserialize it
-        Ok(deserialize(Cursor::new(slice)).context("Deserializing bincode")?)
+        let send = deserialize(Cursor::new(slice)).context("Deserializing bincode")?;
+
+        let timings = DispatchTimings {
+            serialize: serialized - start,
+            call: called - serialized,
+            deserialize: called.elapsed(),
+        };
+
+        Ok((send, timings))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cimvr_engine_interface::serial::serialize;
+
+    #[test]
+    fn test_host_functions() {
+        let mut host = HostFunctions::default();
+        host.add(
+            "add_score",
+            |mut caller: Caller<'_, PluginContext>, points: u32| {
+                *caller.data_mut().get_mut::<u32>() += points;
+            },
+        );
+        let linker = host.linker(&wasmtime::Engine::default()).unwrap();
+
+        let wat = r#"(module
+            (import "env" "add_score" (func $add_score (param i32)))
+            (memory (export "memory") 1)
+            (func (export "_reserve") (param i32) (result i32) i32.const 0)
+            (func (export "_dispatch") (result i32)
+                (call $add_score (i32.const 3))
+                i32.const 0))"#;
+        let mut plugin = Plugin::new(&linker, "scorer", wat.as_bytes()).unwrap();
+        plugin.dispatch_fn.call(&mut plugin.store, ()).unwrap();
+        plugin.dispatch_fn.call(&mut plugin.store, ()).unwrap();
+
+        let context = plugin.store.data_mut();
+        assert_eq!(context.name(), "scorer");
+        assert_eq!(*context.get_mut::<u32>(), 6);
+
+        // Built-in functions cannot be replaced
+        host.add("_random", || 4u64);
+        assert!(host.linker(&wasmtime::Engine::default()).is_err());
+    }
+
+    #[test]
+    fn test_plugin_logging() {
+        let logs = crate::tests::capture_logs();
+
+        let record = LogRecord {
+            level: log::Level::Warn as u8,
+            target: "chat::ui".into(),
+            message: "Window closed".into(),
+            file: None,
+            line: None,
+        };
+        let data = serialize(&record).unwrap();
+        let escaped: String = data.iter().map(|b| format!("\\{:02x}", b)).collect();
+
+        let wat = format!(
+            r#"(module
+            (import "env" "_log" (func $log (param i32 i32)))
+            (memory (export "memory") 1)
+            (data (i32.const 16) "{}")
+            (func (export "_reserve") (param i32) (result i32) i32.const 0)
+            (func (export "_dispatch") (result i32)
+                (call $log (i32.const 16) (i32.const {}))
+                i32.const 0))"#,
+            escaped,
+            data.len()
+        );
+        let linker = HostFunctions::default()
+            .linker(&wasmtime::Engine::default())
+            .unwrap();
+        let mut plugin = Plugin::new(&linker, "chat.wasm", wat.as_bytes()).unwrap();
+        plugin.dispatch_fn.call(&mut plugin.store, ()).unwrap();
+
+        let logged = logs.lock().unwrap();
+        let entry = (
+            "chat".to_string(),
+            log::Level::Warn,
+            "Window closed".to_string(),
+        );
+        assert!(logged.contains(&entry));
     }
 }
@@ -0,0 +1,336 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use cimvr_engine_interface::system::Stage;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::plugin::DispatchTimings;
+
+/// Weight of the latest sample in the running averages
+const SMOOTHING: f64 = 0.1;
+
+/// Trace thread ID of the engine's stages. Plugins are numbered from 1
+const ENGINE_TID: u32 = 0;
+
+/// Time spent in each phase of running a single system
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub struct SystemTimings {
+    /// Gathering the system's query results
+    pub query: Duration,
+    /// Writing the input into the plugin's memory
+    pub serialize: Duration,
+    /// Running plugin code
+    pub call: Duration,
+    /// Reading the plugin's output
+    pub deserialize: Duration,
+    /// Applying the plugin's ECS commands
+    pub apply: Duration,
+}
+
+/// Live statistics of a single system
+#[derive(Default, Clone, Copy, Debug)]
+pub struct SystemStats {
+    /// Stage the system runs in
+    pub stage: Option<Stage>,
+    /// Number of times the system has run
+    pub calls: u64,
+    /// Timings of the most recent run
+    pub last: SystemTimings,
+    /// Running average of the timings
+    pub average: SystemTimings,
+}
+
+/// Records the time spent in each plugin system and stage, and optionally captures a trace of a
+/// number of frames for viewing in `chrome://tracing` or Perfetto
+pub struct Profiler {
+    /// Stats of each plugin's systems, by plugin name and system index
+    systems: HashMap<String, Vec<SystemStats>>,
+    /// Trace thread ID of each plugin. A plugin's systems never overlap, while those of different
+    /// plugins may when run in parallel, so each plugin is shown on its own row
+    threads: HashMap<String, u32>,
+    /// Time the profiler was created; trace timestamps are relative to this
+    epoch: Instant,
+    /// Trace in progress, if any
+    capture: Option<Capture>,
+    /// Most recently completed trace
+    trace: Option<ChromeTrace>,
+}
+
+/// Trace in progress
+struct Capture {
+    /// Frames left to capture
+    frames_left: u32,
+    events: Vec<TraceEvent>,
+}
+
+/// Trace in the Chrome trace event format
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct ChromeTrace {
+    #[serde(rename = "traceEvents")]
+    pub events: Vec<TraceEvent>,
+}
+
+/// A single complete ("X") or metadata ("M") event
+#[derive(Serialize, Clone, Debug)]
+pub struct TraceEvent {
+    pub name: String,
+    #[serde(rename = "cat")]
+    pub category: &'static str,
+    #[serde(rename = "ph")]
+    pub phase: &'static str,
+    /// Start time, in microseconds
+    pub ts: f64,
+    /// Duration, in microseconds
+    pub dur: f64,
+    pub pid: u32,
+    pub tid: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub args: Option<Value>,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self {
+            systems: HashMap::new(),
+            threads: HashMap::new(),
+            epoch: Instant::now(),
+            capture: None,
+            trace: None,
+        }
+    }
+
+    /// Live statistics for each system, by plugin name and system index. Systems which have not
+    /// run since the last reset have zero calls
+    pub fn systems(&self) -> &HashMap<String, Vec<SystemStats>> {
+        &self.systems
+    }
+
+    /// Forget all statistics
+    pub fn reset(&mut self) {
+        self.systems.clear();
+    }
+
+    /// Begin capturing a trace of the next `frames` frames. Replaces any capture in progress
+    pub fn capture(&mut self, frames: u32) {
+        self.capture = Some(Capture {
+            frames_left: frames,
+            events: vec![],
+        });
+    }
+
+    /// Returns `true` if a trace is being captured
+    pub fn is_capturing(&self) -> bool {
+        self.capture.is_some()
+    }
+
+    /// Take the most recently completed trace, if any
+    pub fn take_trace(&mut self) -> Option<ChromeTrace> {
+        self.trace.take()
+    }
+
+    /// Mark the start of a new frame
+    pub fn begin_frame(&mut self) {
+        let Some(capture) = &mut self.capture else { return };
+        if capture.frames_left == 0 {
+            let capture = self.capture.take().unwrap();
+            let mut events = self.thread_names();
+            events.extend(capture.events);
+            self.trace = Some(ChromeTrace { events });
+        } else {
+            capture.frames_left -= 1;
+        }
+    }
+
+    /// Record the time spent running a stage
+    pub fn record_stage(&mut self, stage: Stage, start: Instant) {
+        let end = Instant::now();
+        let name = format!("{:?}", stage);
+        self.event(name, "stage", ENGINE_TID, start, end - start);
+    }
+
+    /// Record the time spent running a system, which began at `start`
+    pub fn record_system(
+        &mut self,
+        plugin: &str,
+        system: usize,
+        stage: Stage,
+        start: Instant,
+        timings: SystemTimings,
+    ) {
+        // Live stats. Avoid allocating the plugin's name in the common case
+        let plugin_stats = match self.systems.get_mut(plugin) {
+            Some(stats) => stats,
+            None => self.systems.entry(plugin.to_string()).or_default(),
+        };
+        if plugin_stats.len() <= system {
+            plugin_stats.resize(system + 1, SystemStats::default());
+        }
+        let stats = &mut plugin_stats[system];
+        stats.average = match stats.calls {
+            0 => timings,
+            _ => stats.average.lerp(&timings, SMOOTHING),
+        };
+        stats.stage = Some(stage);
+        stats.calls += 1;
+        stats.last = timings;
+
+        if self.capture.is_none() {
+            return;
+        }
+
+        // Trace events, with phases nested within the system
+        let next_tid = self.threads.len() as u32 + 1;
+        let tid = *self.threads.entry(plugin.to_string()).or_insert(next_tid);
+        let name = format!("{} #{}", plugin, system);
+        self.event(name, "system", tid, start, timings.total());
+
+        let phases = [
+            ("query", timings.query),
+            ("serialize", timings.serialize),
+            ("call", timings.call),
+            ("deserialize", timings.deserialize),
+            ("apply", timings.apply),
+        ];
+        let mut time = start;
+        for (phase, duration) in phases {
+            self.event(phase.into(), "phase", tid, time, duration);
+            time += duration;
+        }
+    }
+
+    fn event(
+        &mut self,
+        name: String,
+        category: &'static str,
+        tid: u32,
+        start: Instant,
+        dur: Duration,
+    ) {
+        let Some(capture) = &mut self.capture else { return };
+        capture.events.push(TraceEvent {
+            name,
+            category,
+            phase: "X",
+            ts: (start - self.epoch).as_secs_f64() * 1e6,
+            dur: dur.as_secs_f64() * 1e6,
+            pid: 1,
+            tid,
+            args: None,
+        });
+    }
+
+    /// Metadata events naming the row of each trace thread
+    fn thread_names(&self) -> Vec<TraceEvent> {
+        let plugins = self.threads.iter().map(|(name, &tid)| (name.as_str(), tid));
+        std::iter::once(("Engine", ENGINE_TID))
+            .chain(plugins)
+            .map(|(name, tid)| TraceEvent {
+                name: "thread_name".into(),
+                category: "__metadata",
+                phase: "M",
+                ts: 0.,
+                dur: 0.,
+                pid: 1,
+                tid,
+                args: Some(json!({ "name": name })),
+            })
+            .collect()
+    }
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SystemTimings {
+    /// Combine the time spent in the plugin itself with the time spent by the host
+    pub fn new(query: Duration, dispatch: DispatchTimings, apply: Duration) -> Self {
+        Self {
+            query,
+            serialize: dispatch.serialize,
+            call: dispatch.call,
+            deserialize: dispatch.deserialize,
+            apply,
+        }
+    }
+
+    /// Total time spent on the system
+    pub fn total(&self) -> Duration {
+        self.query + self.serialize + self.call + self.deserialize + self.apply
+    }
+
+    fn lerp(&self, other: &Self, t: f64) -> Self {
+        let lerp = |a: Duration, b: Duration| {
+            Duration::from_secs_f64(a.as_secs_f64() * (1. - t) + b.as_secs_f64() * t)
+        };
+        Self {
+            query: lerp(self.query, other.query),
+            serialize: lerp(self.serialize, other.serialize),
+            call: lerp(self.call, other.call),
+            deserialize: lerp(self.deserialize, other.deserialize),
+            apply: lerp(self.apply, other.apply),
+        }
+    }
+}
+
+impl ChromeTrace {
+    /// Write the trace as JSON, which may be opened with `chrome://tracing` or Perfetto
+    pub fn write(&self, path: impl AsRef<Path>) -> Result<()> {
+        let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        serde_json::to_writer(file, self)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profiler_capture() {
+        let mut profiler = Profiler::new();
+        let timings = SystemTimings {
+            call: Duration::from_millis(2),
+            ..Default::default()
+        };
+
+        // Not capturing; only live stats are kept
+        profiler.record_system("a", 0, Stage::Update, Instant::now(), timings);
+        profiler.begin_frame();
+        assert!(profiler.take_trace().is_none());
+
+        profiler.capture(2);
+        for _ in 0..2 {
+            profiler.begin_frame();
+            let start = Instant::now();
+            profiler.record_system("a", 0, Stage::Update, start, timings);
+            profiler.record_stage(Stage::Update, start);
+        }
+        assert!(profiler.is_capturing());
+        profiler.begin_frame();
+        assert!(!profiler.is_capturing());
+
+        // One event for each system, its five phases and the stage, after the names of the
+        // engine's and the plugin's rows
+        let trace = profiler.take_trace().unwrap();
+        assert_eq!(trace.events.len(), 2 + 2 * 7);
+        let tid = |category| {
+            let event = trace.events.iter().find(|e| e.category == category);
+            event.unwrap().tid
+        };
+        assert_eq!(tid("stage"), ENGINE_TID);
+        assert_ne!(tid("system"), ENGINE_TID);
+        assert_eq!(tid("phase"), tid("system"));
+
+        let stats = profiler.systems()["a"][0];
+        assert_eq!(stats.calls, 3);
+        assert!((stats.average.call.as_secs_f64() - 2e-3).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,381 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use cimvr_engine_interface::{
+    component_id,
+    prelude::*,
+    serial::{deserialize, serialize},
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{ecs::EcsMap, PluginIndex};
+
+/// Converts component data to and from a (typically more compact) wire format
+#[derive(Clone, Copy)]
+pub struct Codec {
+    /// Convert component data into its wire format
+    pub encode: fn(&[u8]) -> Result<Vec<u8>>,
+    /// Convert the wire format back into component data
+    pub decode: fn(&[u8]) -> Result<Vec<u8>>,
+}
+
+/// Per-component replication policies, and the state necessary to apply them on either end of
+/// the connection
+#[derive(Clone)]
+pub struct Replication {
+    /// Policies for each component; components not listed here use the default policy
+    policies: HashMap<ComponentId, ReplicationPolicy>,
+    /// Wire formats for each component
+    codecs: HashMap<ComponentId, Codec>,
+    /// Maximum number of bytes of component data sent to each remote per update, if any
+    budget: Option<usize>,
+    /// Most recent state received from the remote
+    received: EcsMap,
+}
+
+/// Replication progress towards a single remote
+#[derive(Default)]
+pub struct ReplicationState {
+    /// Number of updates selected so far
+    sequence: u64,
+    /// Sequence number at which each component was last sent
+    last_sent: HashMap<ComponentId, u64>,
+}
+
+impl Replication {
+    pub fn new() -> Self {
+        let mut instance = Self {
+            policies: HashMap::new(),
+            codecs: HashMap::new(),
+            budget: None,
+            received: EcsMap::new(),
+        };
+
+        // Plugin ownership is meaningless to the remote
+        instance.set_policy::<PluginIndex>(ReplicationPolicy::never());
+
+        instance
+    }
+
+    /// Get the replication policy of the given component
+    pub fn policy(&self, component: &ComponentId) -> ReplicationPolicy {
+        self.policies.get(component).copied().unwrap_or_default()
+    }
+
+    /// Set the replication policy of the given component
+    pub fn set_policy<C: Component>(&mut self, policy: ReplicationPolicy) {
+        self.set_policy_raw(component_id::<C>(), policy)
+    }
+
+    /// Set the replication policy of the given component
+    pub fn set_policy_raw(&mut self, component: ComponentId, policy: ReplicationPolicy) {
+        if policy.interval == 0 {
+            return log::error!("Invalid replication interval for {:?}", component);
+        }
+        self.policies.insert(component, policy);
+    }
+
+    /// Set the wire format of the given component. Must be set identically on both ends
+    pub fn set_codec<C: Component>(&mut self, codec: Codec) {
+        self.codecs.insert(component_id::<C>(), codec);
+    }
+
+    /// Limit the number of bytes of component data sent to each remote per update. Components are
+    /// sent in order of priority, and those which do not fit are deferred to the next update. The
+    /// component which has waited longest is always sent, even if it does not fit, so that no
+    /// component is deferred forever.
+    pub fn set_budget(&mut self, budget: Option<usize>) {
+        self.budget = budget;
+    }
+
+    /// Returns `true` if the given component is sent to the remote at all
+    pub fn replicates(&self, component: &ComponentId) -> bool {
+        self.policy(component).replicate
+    }
+
+    /// Drop the components which are never replicated, and convert the rest to their wire format.
+    /// Call once per update, on exported data, before `select`ing the data for each remote.
+    pub fn encode(&self, mut ecs: EcsMap) -> EcsMap {
+        ecs.retain(|component, _| self.replicates(component));
+        ecs.into_iter()
+            .map(|(component, data)| {
+                let data = match self.codecs.get(&component) {
+                    Some(codec) => encode_all(codec, data),
+                    None => data,
+                };
+                (component, data)
+            })
+            .collect()
+    }
+
+    /// Select the components which are due to be sent to a remote this update, within the budget.
+    /// Call once per update for each remote, on `encode`d data relevant to it.
+    pub fn select(&self, state: &mut ReplicationState, mut ecs: EcsMap) -> EcsMap {
+        let sequence = state.sequence;
+        state.sequence += 1;
+
+        // Components which are due, with the number of updates since they were last sent
+        let mut due: Vec<(ComponentId, ReplicationPolicy, u64)> = ecs
+            .keys()
+            .filter_map(|component| {
+                let policy = self.policy(component);
+                let waited = state
+                    .last_sent
+                    .get(component)
+                    .map_or(u64::MAX, |&last| sequence - last);
+                (waited >= u64::from(policy.interval)).then(|| (component.clone(), policy, waited))
+            })
+            .collect();
+
+        // Highest priority first
+        due.sort_by(|(a, a_policy, _), (b, b_policy, _)| {
+            b_policy
+                .priority
+                .cmp(&a_policy.priority)
+                .then(a.id.cmp(&b.id))
+        });
+
+        // The entity list is always sent, so that the remote knows which entities still exist,
+        // and the component which has waited longest is sent so that none is deferred forever
+        let sync = component_id::<Synchronized>();
+        let oldest = due
+            .iter()
+            .min_by_key(|(_, _, waited)| std::cmp::Reverse(*waited))
+            .map(|(component, _, _)| component.clone());
+
+        let mut remaining = self.budget.unwrap_or(usize::MAX);
+        let mut out = EcsMap::new();
+        for (component, _, _) in due {
+            let Some(data) = ecs.remove(&component) else {
+                continue;
+            };
+
+            let size: usize = data
+                .values()
+                .map(|d| d.len() + std::mem::size_of::<EntityId>())
+                .sum();
+            let exempt = component == sync || Some(&component) == oldest.as_ref();
+            if size > remaining && !exempt {
+                continue;
+            }
+            remaining = remaining.saturating_sub(size);
+
+            state.last_sent.insert(component.clone(), sequence);
+            out.insert(component, data);
+        }
+
+        out
+    }
+
+    /// Convert received data from its wire format, and fill in any components which were not sent
+    /// in this update with their most recently received values
+    pub fn receive(&mut self, ecs: EcsMap) -> EcsMap {
+        for (component, data) in ecs {
+            let data = match self.codecs.get(&component) {
+                Some(codec) => decode_all(codec, &component, data),
+                None => data,
+            };
+            self.received.insert(component, data);
+        }
+
+        // Forget entities which no longer exist
+        let entities = self
+            .received
+            .get(&component_id::<Synchronized>())
+            .cloned()
+            .unwrap_or_default();
+        for data in self.received.values_mut() {
+            data.retain(|entity, _| entities.contains_key(entity));
+        }
+
+        self.received.clone()
+    }
+}
+
+impl Default for Replication {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Codec {
+    /// Send component `C` over the wire as `Q`
+    pub fn via<C, Q>() -> Self
+    where
+        C: Component + From<Q>,
+        Q: Serialize + DeserializeOwned + From<C>,
+    {
+        Self {
+            encode: encode_via::<C, Q>,
+            decode: decode_via::<C, Q>,
+        }
+    }
+}
+
+fn encode_via<C, Q>(data: &[u8]) -> Result<Vec<u8>>
+where
+    C: Component,
+    Q: Serialize + From<C>,
+{
+    let component: C = deserialize(data)?;
+    Ok(serialize(&Q::from(component))?)
+}
+
+fn decode_via<C, Q>(data: &[u8]) -> Result<Vec<u8>>
+where
+    C: Component + From<Q>,
+    Q: DeserializeOwned,
+{
+    let wire: Q = deserialize(data)?;
+    Ok(serialize(&C::from(wire))?)
+}
+
+fn encode_all(codec: &Codec, data: HashMap<EntityId, Vec<u8>>) -> HashMap<EntityId, Vec<u8>> {
+    data.into_iter()
+        .filter_map(|(entity, data)| match (codec.encode)(&data) {
+            Ok(encoded) => Some((entity, encoded)),
+            Err(e) => {
+                log::error!("Failed to encode component of {:?}; {:#}", entity, e);
+                None
+            }
+        })
+        .collect()
+}
+
+fn decode_all(
+    codec: &Codec,
+    component: &ComponentId,
+    data: HashMap<EntityId, Vec<u8>>,
+) -> HashMap<EntityId, Vec<u8>> {
+    data.into_iter()
+        .filter_map(|(entity, data)| match (codec.decode)(&data) {
+            Ok(mut decoded) => {
+                decoded.resize(usize::from(component.size), 0);
+                Some((entity, decoded))
+            }
+            Err(e) => {
+                log::error!("Failed to decode {:?} of {:?}; {:#}", component, entity, e);
+                None
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cimvr_engine_interface::pkg_namespace;
+
+    #[derive(Component, Serialize, serde::Deserialize, Default, Copy, Clone, Debug, PartialEq)]
+    struct Position(f32);
+
+    #[derive(Serialize, serde::Deserialize)]
+    struct HalfPosition(i16);
+
+    impl From<Position> for HalfPosition {
+        fn from(Position(x): Position) -> Self {
+            Self(x as i16)
+        }
+    }
+
+    impl From<HalfPosition> for Position {
+        fn from(HalfPosition(x): HalfPosition) -> Self {
+            Self(x.into())
+        }
+    }
+
+    fn insert<C: Component>(map: &mut EcsMap, entity: EntityId, value: C) {
+        map.entry(component_id::<C>())
+            .or_default()
+            .insert(entity, serialize(&value).unwrap());
+    }
+
+    #[test]
+    fn test_replication_policies() {
+        let mut server = Replication::new();
+        let mut client = Replication::new();
+        for rep in [&mut server, &mut client] {
+            rep.set_policy::<Position>(ReplicationPolicy::default().with_interval(2));
+            rep.set_codec::<Position>(Codec::via::<Position, HalfPosition>());
+        }
+
+        let ent = EntityId(5);
+        let mut state = EcsMap::new();
+        insert(&mut state, ent, Synchronized);
+        insert(&mut state, ent, Position(3.));
+        insert(&mut state, ent, PluginIndex(0));
+
+        // Position is sent in its wire format; PluginIndex is never sent
+        let mut progress = ReplicationState::default();
+        let sent = server.select(&mut progress, server.encode(state.clone()));
+        assert!(!sent.contains_key(&component_id::<PluginIndex>()));
+        assert_eq!(sent[&component_id::<Position>()][&ent].len(), 2);
+
+        let received = client.receive(sent);
+        assert_eq!(
+            received[&component_id::<Position>()][&ent],
+            serialize(&Position(3.)).unwrap()
+        );
+
+        // Position is not due; the client keeps the previous value
+        let sent = server.select(&mut progress, server.encode(state.clone()));
+        assert!(!sent.contains_key(&component_id::<Position>()));
+        let received = client.receive(sent);
+        assert!(received[&component_id::<Position>()].contains_key(&ent));
+
+        // Deleted entities are forgotten, even if their components were not sent
+        state.values_mut().for_each(|data| data.clear());
+        let received = client.receive(server.select(&mut progress, server.encode(state)));
+        assert!(received[&component_id::<Position>()].is_empty());
+    }
+
+    #[derive(Component, Serialize, serde::Deserialize, Default, Copy, Clone, Debug, PartialEq)]
+    struct Blob([u64; 8]);
+
+    #[test]
+    fn test_replication_budget() {
+        let mut server = Replication::new();
+        server.set_policy::<Position>(ReplicationPolicy {
+            priority: 10,
+            ..Default::default()
+        });
+        // Room for Position (and the entity list), but not for Blob
+        let entry = std::mem::size_of::<EntityId>();
+        server.set_budget(Some(entry + 4));
+
+        let ent = EntityId(5);
+        let mut state = EcsMap::new();
+        insert(&mut state, ent, Synchronized);
+        insert(&mut state, ent, Position(3.));
+        insert(&mut state, ent, Blob([7; 8]));
+        let state = server.encode(state);
+
+        let sync = component_id::<Synchronized>();
+        let pos = component_id::<Position>();
+        let blob = component_id::<Blob>();
+
+        // Each remote is budgeted separately
+        let mut first = ReplicationState::default();
+        let mut second = ReplicationState::default();
+        for progress in [&mut first, &mut second] {
+            // Nothing has been sent yet, so the highest priority component goes first
+            let sent = server.select(progress, state.clone());
+            assert!(sent.contains_key(&sync));
+            assert!(sent.contains_key(&pos));
+            assert!(!sent.contains_key(&blob));
+        }
+
+        // Blob has waited longest, so it is sent even though it does not fit
+        let sent = server.select(&mut first, state.clone());
+        assert!(sent.contains_key(&sync));
+        assert!(sent.contains_key(&pos));
+        assert!(sent.contains_key(&blob));
+
+        // Blob keeps being sent every other update, instead of waiting forever
+        let sent = server.select(&mut first, state.clone());
+        assert!(sent.contains_key(&pos));
+        assert!(!sent.contains_key(&blob));
+        let sent = server.select(&mut first, state);
+        assert!(sent.contains_key(&blob));
+    }
+}
@@ -0,0 +1,132 @@
+use std::collections::{HashMap, VecDeque};
+
+use cimvr_engine_interface::prelude::{ComponentId, EntityId};
+
+use crate::ecs::{ComponentData, EcsMap};
+
+/// Buffers timestamped snapshots of a single replicated component, so that it may be sampled in
+/// between the snapshots received from the remote
+pub struct SnapshotBuffer {
+    /// Component being buffered
+    component: ComponentId,
+    /// Snapshots, ordered by timestamp
+    snapshots: VecDeque<(f64, HashMap<EntityId, ComponentData>)>,
+    /// Snapshots older than this many seconds (relative to the newest) are discarded
+    max_age: f64,
+}
+
+/// A component value sampled from a `SnapshotBuffer`
+#[derive(Debug, PartialEq)]
+pub enum Sample<'a> {
+    /// Only a single snapshot is relevant
+    Exact(&'a [u8]),
+    /// The sample lies between the two given snapshots, at the given fraction (0 to 1)
+    Between(&'a [u8], &'a [u8], f32),
+}
+
+impl SnapshotBuffer {
+    pub fn new(component: ComponentId, max_age: f64) -> Self {
+        Self {
+            component,
+            snapshots: VecDeque::new(),
+            max_age,
+        }
+    }
+
+    /// The component being buffered
+    pub fn component(&self) -> &ComponentId {
+        &self.component
+    }
+
+    /// Record a snapshot taken at the given (remote) time
+    pub fn push(&mut self, time: f64, ecs: &EcsMap) {
+        // Snapshots must arrive in order; discard anything stale
+        if self.latest_time().is_some_and(|latest| time <= latest) {
+            return;
+        }
+
+        let data = ecs.get(&self.component).cloned().unwrap_or_default();
+        self.snapshots.push_back((time, data));
+
+        while let Some(&(oldest, _)) = self.snapshots.front() {
+            if time - oldest > self.max_age {
+                self.snapshots.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Timestamp of the newest snapshot
+    pub fn latest_time(&self) -> Option<f64> {
+        self.snapshots.back().map(|(time, _)| *time)
+    }
+
+    /// Sample the given entity's component at the given time. Times outside of the buffered
+    /// range are clamped to the oldest or newest snapshot
+    pub fn sample(&self, entity: EntityId, time: f64) -> Option<Sample<'_>> {
+        // Index of the first snapshot after the given time
+        let after = self.snapshots.partition_point(|(t, _)| *t <= time);
+
+        let before = after.checked_sub(1).and_then(|i| self.snapshots.get(i));
+        let after = self.snapshots.get(after);
+
+        let before = before.and_then(|(t, map)| Some((*t, map.get(&entity)?.as_slice())));
+        let after = after.and_then(|(t, map)| Some((*t, map.get(&entity)?.as_slice())));
+
+        match (before, after) {
+            (Some((t0, a)), Some((t1, b))) => {
+                let frac = ((time - t0) / (t1 - t0)) as f32;
+                Some(Sample::Between(a, b, frac.clamp(0., 1.)))
+            }
+            (Some((_, a)), None) | (None, Some((_, a))) => Some(Sample::Exact(a)),
+            (None, None) => None,
+        }
+    }
+
+    /// Entities present in the newest snapshot
+    pub fn entities(&self) -> impl Iterator<Item = EntityId> + '_ {
+        self.snapshots
+            .back()
+            .into_iter()
+            .flat_map(|(_, map)| map.keys().copied())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(comp: &ComponentId, entity: EntityId, val: u8) -> EcsMap {
+        let mut map = EcsMap::new();
+        map.entry(comp.clone())
+            .or_default()
+            .insert(entity, vec![val]);
+        map
+    }
+
+    #[test]
+    fn test_snapshot_sample() {
+        let comp = ComponentId {
+            id: "snapshot_test".into(),
+            size: 1,
+        };
+        let ent = EntityId(1234);
+
+        let mut buf = SnapshotBuffer::new(comp.clone(), 1.);
+        assert_eq!(buf.sample(ent, 0.), None);
+
+        buf.push(1.0, &snapshot(&comp, ent, 10));
+        buf.push(1.5, &snapshot(&comp, ent, 20));
+        // Out of order; ignored
+        buf.push(1.2, &snapshot(&comp, ent, 99));
+
+        assert_eq!(buf.sample(ent, 0.5), Some(Sample::Exact(&[10])));
+        assert_eq!(buf.sample(ent, 1.25), Some(Sample::Between(&[10], &[20], 0.5)));
+        assert_eq!(buf.sample(ent, 2.0), Some(Sample::Exact(&[20])));
+
+        // Old snapshots are discarded
+        buf.push(3.0, &snapshot(&comp, ent, 30));
+        assert_eq!(buf.sample(ent, 1.25), Some(Sample::Exact(&[30])));
+    }
+}
@@ -0,0 +1,173 @@
+use std::collections::HashMap;
+
+use cimvr_engine_interface::{
+    system::SystemId,
+    timer::{Timer, TimerCommand, TimerFired, TimerId},
+};
+
+/// Timers started by plugins, keyed by plugin index. Kept across hot reloads, since the plugin
+/// index of a reloaded plugin does not change
+#[derive(Default, Clone)]
+pub struct Timers {
+    timers: HashMap<(usize, TimerId), PendingTimer>,
+}
+
+#[derive(Clone)]
+struct PendingTimer {
+    system: SystemId,
+    /// Engine time at which the timer next fires
+    deadline: f32,
+    /// Period of repeating timers
+    repeat: Option<f32>,
+    /// Number of times fired so far
+    count: u32,
+    payload: Vec<u8>,
+}
+
+impl Timers {
+    /// Apply timer commands from the given plugin, at the given engine time
+    pub fn apply(&mut self, plugin: usize, commands: Vec<TimerCommand>, now: f32) {
+        for command in commands {
+            match command {
+                TimerCommand::Start {
+                    id,
+                    system,
+                    timer: Timer { delay, repeat },
+                    payload,
+                } => {
+                    let timer = PendingTimer {
+                        system,
+                        deadline: now + delay,
+                        repeat,
+                        count: 0,
+                        payload,
+                    };
+                    self.timers.insert((plugin, id), timer);
+                }
+                TimerCommand::Cancel(id) => self.cancel(plugin, id),
+            }
+        }
+    }
+
+    /// Stop the given timer, if it has not expired yet
+    pub fn cancel(&mut self, plugin: usize, id: TimerId) {
+        self.timers.remove(&(plugin, id));
+    }
+
+    /// Forget the timers of an unloaded plugin, shifting the indices of the plugins after it
+    pub fn remove_plugin(&mut self, plugin: usize) {
+        self.timers = std::mem::take(&mut self.timers)
+            .into_iter()
+            .filter(|((idx, _), _)| *idx != plugin)
+            .map(|((idx, id), timer)| {
+                let idx = if idx > plugin { idx - 1 } else { idx };
+                ((idx, id), timer)
+            })
+            .collect();
+    }
+
+    /// Collect the timers which expire at the given engine time, as (plugin index, system,
+    /// message), in a deterministic order. One-shot timers are removed. Timers for which `held`
+    /// returns true do not fire until it returns false, and then fire once
+    pub fn expire(
+        &mut self,
+        now: f32,
+        held: impl Fn(usize, SystemId) -> bool,
+    ) -> Vec<(usize, SystemId, TimerFired)> {
+        let mut fired = vec![];
+        for (&(plugin, id), timer) in &mut self.timers {
+            if timer.deadline > now || held(plugin, timer.system) {
+                continue;
+            }
+
+            timer.count += 1;
+            if let Some(period) = timer.repeat {
+                // Fire at most once per frame, without drifting
+                timer.deadline += period;
+                if timer.deadline <= now {
+                    timer.deadline = now + period;
+                }
+            }
+
+            let msg = TimerFired {
+                id,
+                count: timer.count,
+                payload: timer.payload.clone(),
+            };
+            fired.push((plugin, timer.system, msg));
+        }
+
+        self.timers
+            .retain(|_, timer| timer.repeat.is_some() || timer.count == 0);
+        fired.sort_by_key(|(plugin, _, msg)| (*plugin, msg.id.0));
+
+        fired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timers_expire() {
+        let mut timers = Timers::default();
+        let start = |id, timer| TimerCommand::Start {
+            id: TimerId(id),
+            system: SystemId(0),
+            timer,
+            payload: vec![],
+        };
+        timers.apply(
+            0,
+            vec![start(1, Timer::once(1.)), start(2, Timer::repeating(0.5))],
+            0.,
+        );
+        let cancel = TimerCommand::Cancel(TimerId(1));
+        timers.apply(1, vec![start(1, Timer::once(1.)), cancel], 0.);
+
+        let ids = |fired: Vec<(usize, SystemId, TimerFired)>| -> Vec<(usize, u64, u32)> {
+            fired
+                .into_iter()
+                .map(|(plugin, _, msg)| (plugin, msg.id.0, msg.count))
+                .collect()
+        };
+
+        let never = |_, _| false;
+        assert_eq!(ids(timers.expire(0.25, never)), vec![]);
+        assert_eq!(ids(timers.expire(0.5, never)), vec![(0, 2, 1)]);
+        assert_eq!(ids(timers.expire(1., never)), vec![(0, 1, 1), (0, 2, 2)]);
+
+        // Repeating timers which fall behind fire only once
+        assert_eq!(ids(timers.expire(3., never)), vec![(0, 2, 3)]);
+        assert_eq!(ids(timers.expire(3.25, never)), vec![]);
+
+        // Held timers wait, then fire once
+        assert_eq!(ids(timers.expire(4., |_, _| true)), vec![]);
+        assert_eq!(ids(timers.expire(5., |_, _| true)), vec![]);
+        assert_eq!(ids(timers.expire(5.25, never)), vec![(0, 2, 4)]);
+        assert_eq!(ids(timers.expire(5.5, never)), vec![]);
+    }
+
+    #[test]
+    fn test_timers_remove_plugin() {
+        let mut timers = Timers::default();
+        for plugin in 0..3 {
+            let start = TimerCommand::Start {
+                id: TimerId(plugin as u64),
+                system: SystemId(0),
+                timer: Timer::once(1.),
+                payload: vec![],
+            };
+            timers.apply(plugin, vec![start], 0.);
+        }
+
+        timers.remove_plugin(1);
+        let fired: Vec<(usize, u64)> = timers
+            .expire(1., |_, _| false)
+            .into_iter()
+            .map(|(plugin, _, msg)| (plugin, msg.id.0))
+            .collect();
+        assert_eq!(fired, vec![(0, 0), (1, 2)]);
+    }
+}
@@ -1,40 +1,204 @@
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
-use cimvr_engine_interface::FrameTime;
+use anyhow::{bail, Result};
+use cimvr_engine_interface::{ClockControl, FrameTime};
+
+/// Configuration for running the engine's clock at a fixed rate
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct FixedTimestep {
+    /// Simulation ticks per second
+    pub tick_rate: f32,
+    /// Maximum number of ticks which may be run in a single call to `Timing::poll()`. If the host
+    /// falls further behind than this, the remaining ticks are dropped instead of being caught up on
+    pub max_steps: u32,
+}
 
 /// Handles the management of the engine's clock,
 /// which is available to plugins in the form of `FrameTime`
 pub struct Timing {
-    init: Instant,
     last_frame: Instant,
+    last_poll: Instant,
+    /// Fixed timestep settings; if None, the clock follows the wall clock
+    fixed: Option<FixedTimestep>,
+    /// Time not yet consumed by fixed ticks
+    accumulator: Duration,
+    /// Simulated time elapsed, taking pauses and time scaling into account
+    sim_time: Duration,
+    /// Whether the clock is paused
+    paused: bool,
+    /// Number of frames to advance while paused
+    pending_steps: u32,
+    /// Rate at which simulated time passes relative to real time
+    time_scale: f32,
     time: FrameTime,
 }
 
 impl Timing {
+    /// Create a clock which follows the wall clock (variable timestep)
     pub fn init() -> Self {
+        Self::new(None)
+    }
+
+    /// Create a clock, optionally running at a fixed timestep
+    pub fn new(fixed: Option<FixedTimestep>) -> Self {
         let init = Instant::now();
         Self {
             last_frame: init,
-            init,
+            last_poll: init,
+            fixed,
+            accumulator: Duration::ZERO,
+            sim_time: Duration::ZERO,
+            paused: false,
+            pending_steps: 0,
+            time_scale: 1.,
             time: FrameTime {
                 delta: 0.,
                 time: 0.,
+                tick: 0,
+                alpha: 1.,
+                paused: false,
+                time_scale: 1.,
             },
         }
     }
 
+    /// Returns the fixed timestep settings, if any
+    pub fn fixed_timestep(&self) -> Option<FixedTimestep> {
+        self.fixed
+    }
+
+    /// Stop simulated time from advancing
+    pub fn pause(&mut self) {
+        self.paused = true;
+        self.pending_steps = 0;
+        self.time.paused = true;
+    }
+
+    /// Resume the clock after a call to `pause()`
+    pub fn resume(&mut self) {
+        self.paused = false;
+        self.pending_steps = 0;
+        self.time.paused = false;
+    }
+
+    /// Returns `true` if the clock is paused
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Set the rate at which simulated time passes relative to real time
+    pub fn set_time_scale(&mut self, scale: f32) {
+        if !scale.is_finite() || scale < 0. {
+            return log::error!("Invalid time scale {}", scale);
+        }
+        self.time_scale = scale;
+        self.time.time_scale = scale;
+    }
+
+    /// Get the rate at which simulated time passes relative to real time
+    pub fn time_scale(&self) -> f32 {
+        self.time_scale
+    }
+
+    /// Advance exactly `n` frames, then pause
+    pub fn step(&mut self, n: u32) {
+        self.pause();
+        self.pending_steps = n;
+    }
+
+    /// Apply the given clock control request
+    pub fn control(&mut self, ctrl: ClockControl) {
+        match ctrl {
+            ClockControl::Pause => self.pause(),
+            ClockControl::Resume => self.resume(),
+            ClockControl::SetTimeScale(scale) => self.set_time_scale(scale),
+            ClockControl::Step(n) => self.step(n),
+        }
+    }
+
+    /// Returns the number of frames which should be run now.
+    /// With a variable timestep, this is always 1; frames run while paused simply have zero delta.
+    /// With a fixed timestep, no frames are run while paused except those requested by `step()`.
+    pub fn poll(&mut self) -> u32 {
+        self.poll_at(Instant::now())
+    }
+
+    fn poll_at(&mut self, now: Instant) -> u32 {
+        let Some(fixed) = self.fixed else { return 1 };
+
+        let elapsed = now - self.last_poll;
+        self.last_poll = now;
+
+        if self.paused {
+            return self.pending_steps.min(fixed.max_steps);
+        }
+
+        let dt = fixed.dt();
+        self.accumulator += elapsed.mul_f32(self.time_scale);
+
+        let mut steps = (self.accumulator.as_secs_f64() / dt.as_secs_f64()) as u32;
+        if steps > fixed.max_steps {
+            log::debug!(
+                "Clock is {} ticks behind; dropping {}",
+                steps,
+                steps - fixed.max_steps
+            );
+            steps = fixed.max_steps;
+            self.accumulator =
+                Duration::from_secs_f64(self.accumulator.as_secs_f64() % dt.as_secs_f64());
+        } else {
+            self.accumulator -= dt * steps;
+        }
+
+        self.time.alpha = (self.accumulator.as_secs_f64() / dt.as_secs_f64()) as f32;
+
+        steps
+    }
+
+    /// Time remaining until the next fixed tick is due. Always zero with a variable timestep.
+    pub fn time_until_next_step(&self) -> Duration {
+        let Some(fixed) = self.fixed else { return Duration::ZERO };
+        if self.paused || self.time_scale == 0. {
+            return fixed.dt();
+        }
+        let pending = self.accumulator + self.last_poll.elapsed().mul_f32(self.time_scale);
+        fixed.dt().saturating_sub(pending).div_f32(self.time_scale)
+    }
+
     /// Begin the frame, as far as this clock is concerned.
     /// This resets the delta time to the instant this function is called, so that future calls to
     /// `self.time()` will always return the same delta until the next call to `self.frame()`
     pub fn frame(&mut self) {
-        let frame_start = Instant::now();
-        let delta = frame_start - self.last_frame;
-        let time = frame_start - self.init;
+        self.frame_at(Instant::now())
+    }
+
+    fn frame_at(&mut self, frame_start: Instant) {
+        let wall_delta = frame_start - self.last_frame;
         self.last_frame = frame_start;
 
+        // Frames while paused do not advance simulated time, unless we were asked to step
+        if self.paused {
+            if self.pending_steps == 0 {
+                self.time.delta = 0.;
+                return;
+            }
+            self.pending_steps -= 1;
+        }
+
+        let tick = self.time.tick + 1;
+        let delta = match self.fixed {
+            Some(fixed) => fixed.dt(),
+            None => wall_delta.mul_f32(self.time_scale),
+        };
+        self.sim_time += delta;
+
         self.time = FrameTime {
             delta: delta.as_secs_f32(),
-            time: time.as_secs_f32(),
+            time: self.sim_time.as_secs_f32(),
+            tick,
+            alpha: self.time.alpha,
+            paused: self.paused,
+            time_scale: self.time_scale,
         };
     }
 
@@ -43,3 +207,124 @@ impl Timing {
         self.time
     }
 }
+
+impl FixedTimestep {
+    /// Run `tick_rate` ticks per second, catching up on at most `max_steps` ticks at once
+    pub fn new(tick_rate: f32, max_steps: u32) -> Result<Self> {
+        let fixed = Self {
+            tick_rate,
+            max_steps,
+        };
+        fixed.validate()?;
+        Ok(fixed)
+    }
+
+    /// Check that the tick rate is positive and finite, and that at least one tick may be run
+    pub fn validate(&self) -> Result<()> {
+        if !(self.tick_rate.is_finite() && self.tick_rate > 0.) {
+            bail!("Tick rate must be positive, got {}", self.tick_rate);
+        }
+        if self.max_steps < 1 {
+            bail!("Maximum number of steps must be at least 1");
+        }
+        Ok(())
+    }
+
+    /// Duration of a single tick
+    pub fn dt(&self) -> Duration {
+        Duration::from_secs_f32(1. / self.tick_rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixed_timing() -> Timing {
+        Timing::new(Some(FixedTimestep {
+            tick_rate: 10.,
+            max_steps: 3,
+        }))
+    }
+
+    #[test]
+    fn test_fixed_timestep_validation() {
+        assert!(FixedTimestep::new(66., 5).is_ok());
+        assert!(FixedTimestep::new(0., 5).is_err());
+        assert!(FixedTimestep::new(-10., 5).is_err());
+        assert!(FixedTimestep::new(f32::NAN, 5).is_err());
+        assert!(FixedTimestep::new(66., 0).is_err());
+    }
+
+    #[test]
+    fn test_fixed_timestep_catch_up() {
+        let mut timing = fixed_timing();
+        let start = timing.last_poll;
+
+        assert_eq!(timing.poll_at(start + Duration::from_millis(50)), 0);
+        assert_eq!(timing.poll_at(start + Duration::from_millis(250)), 2);
+        assert!((timing.get_frame_time().alpha - 0.5).abs() < 1e-3);
+
+        // Far behind; only the maximum number of steps is run
+        assert_eq!(timing.poll_at(start + Duration::from_millis(10_250)), 3);
+        assert!((timing.get_frame_time().alpha - 0.5).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_fixed_timestep_ticks() {
+        let mut timing = fixed_timing();
+        let start = timing.last_frame;
+
+        for i in 1..=5 {
+            timing.frame_at(start + Duration::from_secs(i));
+            let time = timing.get_frame_time();
+            assert_eq!(time.tick, i);
+            assert!((time.delta - 0.1).abs() < 1e-6);
+            assert!((time.time - 0.1 * i as f32).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_pause_and_step() {
+        let mut timing = Timing::init();
+        let start = timing.last_frame;
+        let frame = |i: u64| start + Duration::from_millis(100 * i);
+
+        timing.frame_at(frame(1));
+        assert_eq!(timing.get_frame_time().tick, 1);
+
+        timing.pause();
+        timing.frame_at(frame(2));
+        let time = timing.get_frame_time();
+        assert_eq!(time.tick, 1);
+        assert_eq!(time.delta, 0.);
+        assert!(time.paused);
+
+        timing.step(2);
+        for i in 3..6 {
+            timing.frame_at(frame(i));
+        }
+        let time = timing.get_frame_time();
+        assert_eq!(time.tick, 3);
+        assert!((time.time - 0.3).abs() < 1e-5);
+
+        timing.resume();
+        timing.set_time_scale(0.5);
+        timing.frame_at(frame(6));
+        let time = timing.get_frame_time();
+        assert_eq!(time.tick, 4);
+        assert!((time.delta - 0.05).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_fixed_timestep_paused() {
+        let mut timing = fixed_timing();
+        let start = timing.last_poll;
+
+        timing.pause();
+        assert_eq!(timing.poll_at(start + Duration::from_secs(1)), 0);
+
+        timing.step(5);
+        assert_eq!(timing.poll_at(start + Duration::from_secs(2)), 3);
+    }
+}
@@ -1,6 +1,11 @@
 extern crate proc_macro;
 use proc_macro::TokenStream;
-use syn::{parse_macro_input, DeriveInput, LitStr};
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{
+    ext::IdentExt, parse_macro_input, Data, DeriveInput, GenericArgument, LitStr, Meta, NestedMeta,
+    PathArguments, Type,
+};
 
 #[proc_macro_derive(Component)]
 pub fn component_derive(input: TokenStream) -> TokenStream {
@@ -10,12 +15,17 @@ pub fn component_derive(input: TokenStream) -> TokenStream {
     // Get the name of the struct being derived
     let name = &input.ident;
 
+    // Describe the layout of the component
+    let schema = schema_fn(&input, quote! { Self::ID });
+
     // Generate the implementation of the `Component` trait
     let gen = quote::quote! {
          impl Component for #name {
              // Define a constant `ID` that identifies the component type
              // Use the namespace of the current crate and the name of the struct
              const ID: &'static str = pkg_namespace!(stringify!(#name));
+
+             #schema
          }
     };
 
@@ -46,13 +56,162 @@ pub fn message_derive(input: TokenStream) -> TokenStream {
 
     let locality: proc_macro2::TokenStream = locality.parse().unwrap();
 
+    // Describe the layout of the message
+    let schema = schema_fn(&input, quote! { Self::CHANNEL.id });
+
     let output = quote::quote! {
        impl Message for #name {
             const CHANNEL: ChannelIdStatic = ChannelIdStatic {
                 id: pkg_namespace!(stringify!(#name)),
                 locality: Locality::#locality,
             };
+
+            #schema
         }
     };
     output.into()
 }
+
+/// Generate the `schema()` method, describing the fields (or variants) of the given type
+fn schema_fn(input: &DeriveInput, id: TokenStream2) -> TokenStream2 {
+    let name = input.ident.to_string();
+
+    let schema = match &input.data {
+        Data::Struct(data) => {
+            let fields = fields_schema(&data.fields);
+            quote! { schema::Schema::new_struct(#id, #name, #fields) }
+        }
+        Data::Enum(data) => {
+            let variants = data.variants.iter().map(|variant| {
+                let name = variant.ident.unraw().to_string();
+                let fields = fields_schema(&variant.fields);
+                quote! { schema::Variant { name: #name.into(), fields: #fields } }
+            });
+            quote! { schema::Schema::new_enum(#id, #name, vec![#(#variants),*]) }
+        }
+        // Unions cannot be serialized by serde anyway
+        Data::Union(_) => return quote! {},
+    };
+
+    quote! {
+        fn schema() -> Option<schema::Schema> {
+            Some(#schema)
+        }
+    }
+}
+
+/// Describe the given fields of a struct or enum variant
+fn fields_schema(fields: &syn::Fields) -> TokenStream2 {
+    let described = fields
+        .iter()
+        .enumerate()
+        .filter(|(_, field)| !serde_skipped(field))
+        .map(|(idx, field)| {
+            let name = match &field.ident {
+                Some(ident) => ident.unraw().to_string(),
+                None => idx.to_string(),
+            };
+            let ty = field_type(&field.ty);
+            quote! { schema::Field::new(#name, #ty) }
+        });
+
+    match fields {
+        syn::Fields::Named(_) => quote! { schema::Fields::Named(vec![#(#described),*]) },
+        syn::Fields::Unnamed(_) => quote! { schema::Fields::Unnamed(vec![#(#described),*]) },
+        syn::Fields::Unit => quote! { schema::Fields::Unit },
+    }
+}
+
+/// Returns true if serde never serializes the given field
+fn serde_skipped(field: &syn::Field) -> bool {
+    field
+        .attrs
+        .iter()
+        .filter(|attr| attr.path.is_ident("serde"))
+        .filter_map(|attr| match attr.parse_meta() {
+            Ok(Meta::List(list)) => Some(list.nested),
+            _ => None,
+        })
+        .flatten()
+        .any(|nested| match nested {
+            NestedMeta::Meta(Meta::Path(path)) => {
+                path.is_ident("skip") || path.is_ident("skip_serializing")
+            }
+            _ => false,
+        })
+}
+
+/// Describe the given field type. Types other than primitives and common containers are
+/// referred to by name
+fn field_type(ty: &Type) -> TokenStream2 {
+    match ty {
+        Type::Array(array) => {
+            let elem = field_type(&array.elem);
+            let len = &array.len;
+            quote! { schema::FieldType::Array(Box::new(#elem), (#len) as u32) }
+        }
+        Type::Tuple(tuple) => {
+            let elems = tuple.elems.iter().map(field_type);
+            quote! { schema::FieldType::Tuple(vec![#(#elems),*]) }
+        }
+        Type::Paren(paren) => field_type(&paren.elem),
+        Type::Group(group) => field_type(&group.elem),
+        Type::Path(path) => {
+            let Some(last) = path.path.segments.last() else {
+                return named_type(ty);
+            };
+
+            let primitive = match last.ident.to_string().as_str() {
+                "bool" => Some(quote! { Bool }),
+                "u8" => Some(quote! { U8 }),
+                "u16" => Some(quote! { U16 }),
+                "u32" => Some(quote! { U32 }),
+                "u64" | "usize" => Some(quote! { U64 }),
+                "u128" => Some(quote! { U128 }),
+                "i8" => Some(quote! { I8 }),
+                "i16" => Some(quote! { I16 }),
+                "i32" => Some(quote! { I32 }),
+                "i64" | "isize" => Some(quote! { I64 }),
+                "i128" => Some(quote! { I128 }),
+                "f32" => Some(quote! { F32 }),
+                "f64" => Some(quote! { F64 }),
+                "char" => Some(quote! { Char }),
+                "String" => Some(quote! { String }),
+                _ => None,
+            };
+            if let Some(primitive) = primitive {
+                return quote! { schema::FieldType::#primitive };
+            }
+
+            // Containers of a single type
+            let inner = match &last.arguments {
+                PathArguments::AngleBracketed(args) if args.args.len() == 1 => {
+                    match &args.args[0] {
+                        GenericArgument::Type(inner) => Some(field_type(inner)),
+                        _ => None,
+                    }
+                }
+                _ => None,
+            };
+            match (last.ident.to_string().as_str(), inner) {
+                ("Option", Some(inner)) => quote! { schema::FieldType::Option(Box::new(#inner)) },
+                ("Vec", Some(inner)) => quote! { schema::FieldType::Vec(Box::new(#inner)) },
+                ("Box", Some(inner)) => inner,
+                ("FixedOption", Some(inner)) => {
+                    quote! { schema::FieldType::Tuple(vec![schema::FieldType::Bool, #inner]) }
+                }
+                _ => {
+                    let name = last.ident.to_string();
+                    quote! { schema::FieldType::Named(#name.into()) }
+                }
+            }
+        }
+        _ => named_type(ty),
+    }
+}
+
+/// Refer to the given type by name
+fn named_type(ty: &Type) -> TokenStream2 {
+    let name = quote!(#ty).to_string();
+    quote! { schema::FieldType::Named(#name.into()) }
+}
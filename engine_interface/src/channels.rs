@@ -11,7 +11,7 @@ use std::collections::HashMap;
 
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
-use crate::network::ClientId;
+use crate::{network::ClientId, schema::Schema};
 
 pub type Inbox = HashMap<ChannelId, Vec<MessageData>>;
 
@@ -69,6 +69,11 @@ pub trait Message: Serialize + DeserializeOwned + Sized {
     /// You ***MUST*** change this ID if you change the datatype of this Message, to avoid
     /// sending corrupted data to other plugins
     const CHANNEL: ChannelIdStatic;
+
+    /// Layout of this message, if known. Implemented by `#[derive(Message)]`
+    fn schema() -> Option<Schema> {
+        None
+    }
 }
 
 impl From<ChannelIdStatic> for ChannelId {
@@ -8,7 +8,9 @@ use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 use crate::{
     component_id,
+    schema::{Schema, SchemaSource},
     serial::{deserialize, serialize, EcsData},
+    Parent,
 };
 
 /// A single requirement in a query
@@ -21,9 +23,12 @@ pub struct QueryComponent {
 }
 
 /// A description of an ECS query
-#[derive(Default, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[derive(Default, Serialize, Deserialize, Debug, Clone)]
 pub struct Query {
     pub intersect: Vec<QueryComponent>,
+    /// Schemas of the components added by type, sent to the host by the plugin querying them
+    #[serde(skip)]
+    pub(crate) schemas: Vec<SchemaSource>,
 }
 
 /// Universally-unique Entity ID
@@ -53,6 +58,11 @@ pub enum Access {
 pub trait Component: Serialize + DeserializeOwned + Copy + Default {
     /// Unique ID of this component
     const ID: &'static str;
+
+    /// Layout of this component, if known. Implemented by `#[derive(Component)]`
+    fn schema() -> Option<Schema> {
+        None
+    }
 }
 
 /// Single command to be sent to engine
@@ -160,6 +170,21 @@ impl QueryResult {
         }
     }
 
+    /// Queried entities whose `Parent` is the given entity, in no particular order. Only children
+    /// matched by a query including `Parent` are found
+    pub fn children(&self, parent: EntityId) -> Vec<EntityId> {
+        let Some(parents) = self.ecs.get(&component_id::<Parent>()) else {
+            return vec![];
+        };
+        parents
+            .iter()
+            .filter(|(_, data)| {
+                deserialize(data.as_slice()).is_ok_and(|Parent(entity)| entity == parent)
+            })
+            .map(|(&child, _)| child)
+            .collect()
+    }
+
     /// Read the data in the given component
     #[track_caller]
     pub fn read<C: Component>(&self, entity: EntityId) -> C {
@@ -220,6 +245,40 @@ impl Query {
     /// Require this component to be present for each entity returned by this query.
     pub fn intersect<T: Component>(mut self, access: Access) -> Self {
         self.intersect.push(QueryComponent::new::<T>(access));
+        self.schemas.push((T::ID, T::schema));
+        self
+    }
+
+    /// Require the given component to be present for each entity returned by this query
+    pub fn intersect_raw(mut self, component: ComponentId, access: Access) -> Self {
+        self.intersect.push(QueryComponent { component, access });
         self
     }
 }
+
+impl PartialEq for Query {
+    fn eq(&self, other: &Self) -> bool {
+        self.intersect == other.intersect
+    }
+}
+
+impl Eq for Query {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query_children() {
+        let (root, a, b) = (EntityId(1), EntityId(2), EntityId(3));
+        let parent = |entity| serialize(&Parent(entity)).unwrap();
+        let parents = [(a, parent(root)), (b, parent(a))].into_iter().collect();
+        let ecs = [(component_id::<Parent>(), parents)].into_iter().collect();
+        let query = Query::new().intersect::<Parent>(Access::Read);
+        let result = QueryResult::new(ecs, [("q".to_string(), query)].into_iter().collect());
+
+        assert_eq!(result.children(root), [a]);
+        assert_eq!(result.children(a), [b]);
+        assert!(result.children(b).is_empty());
+    }
+}
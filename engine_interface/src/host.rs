@@ -0,0 +1,41 @@
+//! # Custom host functions
+//! Hosts embedding the engine may provide plugins with additional native functions (see
+//! `cimvr_engine::plugin::HostFunctions`). Plugins declare the functions they use with
+//! [host_functions!()](crate::host_functions), which generates safe wrappers around the imports.
+//!
+//! Parameters and results must be WASM value types: integers, floats, or pointers into plugin
+//! memory. A plugin using a function its host does not provide fails to load.
+
+/// Declare functions provided by the host. Outside of plugins (e.g. in unit tests), calling them
+/// panics.
+/// ```rust
+/// use cimvr_engine_interface::host_functions;
+///
+/// host_functions! {
+///     /// Number of players in the lobby, provided by our launcher
+///     pub fn lobby_size() -> u32;
+///     fn add_score(player: u32, points: i64);
+/// }
+/// ```
+#[macro_export]
+macro_rules! host_functions {
+    ($($(#[$meta:meta])* $vis:vis fn $name:ident($($arg:ident: $ty:ty),* $(,)?) $(-> $ret:ty)?;)*) => {
+        $(
+            $(#[$meta])*
+            #[allow(unused_variables)]
+            $vis fn $name($($arg: $ty),*) $(-> $ret)? {
+                #[cfg(target_family = "wasm")]
+                {
+                    #[link(wasm_import_module = "env")]
+                    extern "C" {
+                        fn $name($($arg: $ty),*) $(-> $ret)?;
+                    }
+                    unsafe { $name($($arg),*) }
+                }
+
+                #[cfg(not(target_family = "wasm"))]
+                panic!("Host function {} is only available to plugins", stringify!($name))
+            }
+        )*
+    };
+}
@@ -30,6 +30,17 @@ pub mod system;
 /// Networking
 pub mod network;
 
+/// Scene files
+pub mod scene;
+
+pub mod schema;
+
+pub mod rpc;
+
+pub mod timer;
+
+pub mod host;
+
 /// PCG algorithm for generating random universally-unique entity IDs
 pub mod pcg;
 
@@ -41,6 +52,7 @@ pub mod prelude {
     pub use super::log::*;
     pub use super::network::*;
     pub use super::plugin::*;
+    pub use super::schema;
     pub use super::stdout::*;
     pub use super::system::*;
     pub use cimvr_derive_macros::{Component, Message};
@@ -65,9 +77,18 @@ macro_rules! pkg_namespace {
 #[derive(Component, Copy, Clone, Debug, Hash, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Saved;
 
+/// Attaches an entity to a parent entity.
+///
+/// The host computes each entity's global (world-space) transform from its own transform and those
+/// of its ancestors, parents first. Deleting an entity also deletes its children. Children are not
+/// stored as a component, since components must be fixed-size; query for `Parent` and use
+/// `QueryResult::children` instead.
+#[derive(Component, Copy, Clone, Debug, Hash, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Parent(pub EntityId);
+
 use ecs::Component;
 use once_cell::sync::Lazy;
-use prelude::{ChannelIdStatic, ComponentId, Locality, Message};
+use prelude::{ChannelIdStatic, ComponentId, EntityId, Locality, Message};
 use serde::{Deserialize, Serialize};
 use serial::serialized_size;
 
@@ -82,6 +103,46 @@ pub struct FrameTime {
     pub delta: f32,
     /// Time since engine start, in seconds
     pub time: f32,
+    /// Number of frames (or fixed ticks) elapsed since engine start. Monotonically increasing
+    pub tick: u64,
+    /// When running at a fixed timestep, the fraction of a tick which has accumulated but not yet
+    /// been simulated. Useful for interpolating between the previous and current state for rendering.
+    /// Always 1 with a variable timestep
+    pub alpha: f32,
+    /// Whether the engine clock is paused. Delta is zero for frames run while paused
+    pub paused: bool,
+    /// Rate at which the engine clock runs relative to real time
+    pub time_scale: f32,
+}
+
+/// Requests a change to the engine clock. Handled by the host; available to both the host and
+/// plugins, e.g. for debugging tools
+#[derive(Message, Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[locality("Local")]
+pub enum ClockControl {
+    /// Stop the clock. Systems still run with a variable timestep, but with zero delta time
+    Pause,
+    /// Resume the clock after a pause
+    Resume,
+    /// Set the rate at which time passes relative to real time
+    SetTimeScale(f32),
+    /// Advance exactly this many frames, then pause
+    Step(u32),
+}
+
+/// Lists the entities deleted since the previous frame, so that plugins may recognize stale
+/// `EntityId`s they are holding on to. Sent by the host at the start of any frame in which
+/// entities were deleted
+#[derive(Message, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[locality("Local")]
+pub struct EntitiesDespawned {
+    pub entities: Vec<EntityId>,
+}
+
+impl Default for Parent {
+    fn default() -> Self {
+        Self(EntityId(0xBAD_BAD_BAD_BAD_BAD_BAD_BAD_BAD_BAD_BAD))
+    }
 }
 
 /// Get the maximum size of this component
@@ -7,13 +7,69 @@ use serde::{Deserialize, Serialize};
 #[derive(Copy, Clone, Debug, Hash, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ClientId(pub u32);
 
-/// Component indicating the entity is forcibly copied from client to server
+/// Component indicating the entity is copied from server to clients
 ///
 /// Cannot be added to or removed from entities clientside!
+///
+/// Each component of the entity is replicated according to its `ReplicationPolicy`. Writes made
+/// clientside are overwritten by the server's state, except for the client-writable components of
+/// entities owned by that client (see `ClientOwned`).
 #[derive(Component, Copy, Clone, Debug, Hash, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Synchronized;
 //pub struct Synchronized(Reliability);
 
+/// Component marking a `Synchronized` entity as controlled locally, clientside.
+///
+/// Synchronized entities are normally displayed slightly in the past, interpolated between the
+/// snapshots received from the server. Entities marked with this component instead keep the
+/// `Transform` written by local plugins (client-side prediction), which is only corrected when the
+/// authoritative state from the server disagrees with the locally predicted state.
+///
+/// Add this component to entities clientside; it is preserved when synchronizing with the server.
+#[derive(Component, Copy, Clone, Debug, Hash, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Predicted;
+
+/// Component assigning ownership of a `Synchronized` entity to a client.
+///
+/// Only added serverside. The owning client's writes to components marked `client_writable` in
+/// their `ReplicationPolicy` are sent to the server, which re-broadcasts them to all other
+/// clients. Writes to entities owned by other clients, or to other components, are discarded.
+#[derive(Component, Copy, Clone, Debug, Hash, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientOwned(pub ClientId);
+
+/// Informs client plugins of the connection ID this client was assigned by the server, so that
+/// they may recognize the entities they own. Sent by the client each frame
+#[derive(Message, Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[locality("Local")]
+pub struct LocalClient {
+    pub id: ClientId,
+}
+
+/// Describes how a component type is replicated from the server to clients
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ReplicationPolicy {
+    /// Whether the component is sent to clients at all
+    pub replicate: bool,
+    /// Send the component once every this many server updates. Clients keep the most recently
+    /// received value in between.
+    pub interval: u32,
+    /// When the server limits how much data it sends per update, components with a higher priority
+    /// are sent first. Components which do not fit are deferred to the next update.
+    pub priority: i32,
+    /// Whether the owner of a `ClientOwned` entity may write to this component. Must be set on
+    /// both the server and the client.
+    pub client_writable: bool,
+}
+
+/// Request that the server change the replication policy of a component type. Only meaningful
+/// serverside.
+#[derive(Message, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[locality("Local")]
+pub struct SetReplicationPolicy {
+    pub component: ComponentId,
+    pub policy: ReplicationPolicy,
+}
+
 /// Information about a connected client
 #[derive(Clone, Debug, Hash, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Connection {
@@ -30,6 +86,38 @@ pub struct Connections {
     pub clients: Vec<Connection>,
 }
 
+/// Estimate of the server's clock, sent to plugins by the host each frame.
+/// Clientside, this is estimated by exchanging timestamps with the server. Serverside, this is
+/// simply the server's own clock, so timestamps may be compared between client and server.
+#[derive(Message, Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[locality("Local")]
+pub struct ServerTime {
+    /// Estimated server time, in seconds since the server started
+    pub time: f64,
+    /// Estimated round trip time to the server, in seconds. Zero serverside
+    pub rtt: f32,
+    /// Estimated offset of the server clock relative to the local clock, in seconds
+    pub offset: f64,
+}
+
+/// Network latency statistics for a single connection
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LatencyStats {
+    /// Connection identifier
+    pub id: ClientId,
+    /// Round trip time as estimated by the client, in seconds
+    pub rtt: f32,
+    /// Offset of the server clock relative to the client clock, in seconds
+    pub offset: f64,
+}
+
+/// Message which lists latency statistics for connected clients. Available server-only
+#[derive(Message, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[locality("Local")]
+pub struct ConnectionStats {
+    pub clients: Vec<LatencyStats>,
+}
+
 /// Connection request from client to server
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ConnectionRequest {
@@ -44,6 +132,8 @@ pub struct ConnectionRequest {
 pub struct ConnectionResponse {
     /// Contains pairs of (name, code), corresponding to the plugins the server wants
     pub plugins: Vec<(String, PluginData)>,
+    /// Connection ID assigned to this client
+    pub client_id: ClientId,
 }
 
 /// Connection data
@@ -55,7 +145,7 @@ pub enum PluginData {
 }
 
 impl ConnectionRequest {
-    const PROTOCOL_VERSION: u32 = 2;
+    const PROTOCOL_VERSION: u32 = 5;
 
     /// Create a new connection request with the current protocol version
     pub fn new(username: String, plugin_manifest: Vec<Digest>) -> Self {
@@ -98,6 +188,55 @@ impl Default for ClientId {
     }
 }
 
+impl Default for ReplicationPolicy {
+    fn default() -> Self {
+        Self {
+            replicate: true,
+            interval: 1,
+            priority: 0,
+            client_writable: false,
+        }
+    }
+}
+
+impl ReplicationPolicy {
+    /// Never send this component to clients
+    pub fn never() -> Self {
+        Self {
+            replicate: false,
+            ..Default::default()
+        }
+    }
+
+    /// Send this component once every `interval` server updates
+    pub fn with_interval(mut self, interval: u32) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Set the priority of this component
+    pub fn with_priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Allow the owners of `ClientOwned` entities to write to this component
+    pub fn client_writable(mut self) -> Self {
+        self.client_writable = true;
+        self
+    }
+}
+
+impl SetReplicationPolicy {
+    /// Set the replication policy of the given component type
+    pub fn new<C: Component>(policy: ReplicationPolicy) -> Self {
+        Self {
+            component: crate::component_id::<C>(),
+            policy,
+        }
+    }
+}
+
 impl Display for Digest {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let Self(hash) = self;
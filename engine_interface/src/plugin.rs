@@ -2,12 +2,18 @@ use crate::{
     component_id,
     pcg::Pcg,
     prelude::*,
+    rpc::{envelope_channel, Request},
+    scene::{Scene, SceneError},
+    schema::{Schema, SchemaRegistry, SchemaSource},
     serial::{
-        deserialize, serialize, serialize_into, serialized_size, EcsData, ReceiveBuf, SendBuf,
+        deserialize, serialize, serialize_into, serialized_size, EcsData, MigrationData,
+        ReceiveBuf, SendBuf, UserStateData,
     },
+    timer::TimerCommand,
 };
 pub use once_cell::sync::Lazy;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::HashSet;
 
 /// Defines the given structure to represent the state of a plugin (on either the **Client** or the
 /// **Server**). Essentially defines the entry point for the plugin.
@@ -15,6 +21,106 @@ use serde::{Deserialize, Serialize};
 pub trait UserState: Sized {
     /// Constructor for this state; called once before the **Init** stage.
     fn new(io: &mut EngineIo, sched: &mut EngineSchedule<Self>) -> Self;
+
+    /// Serialize the in-memory state before a hot reload. Generated by
+    /// [persistent_state!()](persistent_state), see [PersistentState]
+    fn save_state(&self) -> Option<UserStateData> {
+        None
+    }
+
+    /// Take over the in-memory state saved by the previous instance of the plugin, returning
+    /// `false` if it is incompatible. Generated by [persistent_state!()](persistent_state)
+    fn restore_state(&mut self, _state: UserStateData) -> bool {
+        false
+    }
+}
+
+/// A [UserState] whose in-memory state (UI state, counters, caches) survives hot reloads of the
+/// plugin, rather than only its `Saved` entities. Opt in by implementing this trait, and invoking
+/// [persistent_state!()](persistent_state) within the `UserState` impl:
+/// ```rust
+/// use cimvr_engine_interface::{persistent_state, prelude::*};
+/// use serde::{Deserialize, Serialize};
+///
+/// #[derive(Serialize, Deserialize)]
+/// struct Counter {
+///     count: u32,
+/// }
+///
+/// impl UserState for Counter {
+///     fn new(_io: &mut EngineIo, _sched: &mut EngineSchedule<Self>) -> Self {
+///         Self { count: 0 }
+///     }
+///
+///     persistent_state!();
+/// }
+///
+/// impl PersistentState for Counter {
+///     const VERSION: u32 = 1;
+/// }
+/// ```
+///
+/// The old instance serializes its state just before it is unloaded. The new instance is
+/// constructed with [UserState::new] as usual, which sets up its schedule, and then restores the
+/// saved state. If the saved state has a different `VERSION` or fails to decode, the fresh state
+/// from `new` is kept.
+pub trait PersistentState: UserState + Serialize + DeserializeOwned {
+    /// Version of the serialized layout. Bump this whenever the fields of the state change
+    const VERSION: u32;
+
+    /// Take over the state of the previous instance. By default the fresh state is replaced
+    /// entirely; override this to keep parts of it, e.g. IDs of unsaved entities created by `new`
+    fn restore(&mut self, old: Self) {
+        *self = old;
+    }
+}
+
+/// Implements the hot reload hooks of [UserState] for a [PersistentState]. Invoke within the
+/// `UserState` impl
+#[macro_export]
+macro_rules! persistent_state {
+    () => {
+        fn save_state(&self) -> Option<$crate::serial::UserStateData> {
+            $crate::plugin::save_persistent(self)
+        }
+
+        fn restore_state(&mut self, state: $crate::serial::UserStateData) -> bool {
+            $crate::plugin::restore_persistent(self, state)
+        }
+    };
+}
+
+/// Serialize the state of a [PersistentState]. Used by [persistent_state!()](persistent_state)
+pub fn save_persistent<U: PersistentState>(user: &U) -> Option<UserStateData> {
+    match serialize(user) {
+        Ok(data) => Some(UserStateData {
+            version: U::VERSION,
+            data,
+        }),
+        Err(e) => {
+            log::error!("Failed to serialize plugin state; {}", e);
+            None
+        }
+    }
+}
+
+/// Restore the state of a [PersistentState], if compatible. Used by
+/// [persistent_state!()](persistent_state)
+pub fn restore_persistent<U: PersistentState>(user: &mut U, state: UserStateData) -> bool {
+    if state.version != U::VERSION {
+        return false;
+    }
+
+    match deserialize(std::io::Cursor::new(&state.data)) {
+        Ok(old) => {
+            user.restore(old);
+            true
+        }
+        Err(e) => {
+            log::error!("Failed to deserialize plugin state; {}", e);
+            false
+        }
+    }
 }
 
 /// A dummy UserState that doesn't do anything.
@@ -32,6 +138,8 @@ pub struct Context<C, S> {
     user: Option<ClientOrServerState<C, S>>,
     /// Buffer for communication with host
     buf: Vec<u8>, // TODO: SAFETY: Make this buffer volatile?! Host writes to it externally...
+    /// IDs of types whose schemas have already been sent to the host
+    registered: HashSet<&'static str>,
 }
 
 /// Stores client or server specific state, callbacks
@@ -110,6 +218,21 @@ pub struct EngineIo {
     pub(crate) outbox: Vec<MessageData>,
     /// Inbox
     pub(crate) inbox: Inbox,
+    /// Schemas to be sent to the host
+    #[serde(skip)]
+    pub(crate) schemas: Vec<Schema>,
+    /// IDs of types whose schemas have already been sent to the host
+    #[serde(skip)]
+    pub(crate) registered: HashSet<&'static str>,
+    /// Components registered since the last dispatch
+    #[serde(skip)]
+    pub(crate) components: Vec<ComponentId>,
+    /// Changes to the schedule
+    #[serde(skip)]
+    pub(crate) schedule: Vec<ScheduleCommand>,
+    /// Timers started or cancelled
+    #[serde(skip)]
+    pub(crate) timers: Vec<TimerCommand>,
 }
 
 /// Scheduling of systems
@@ -118,6 +241,17 @@ pub struct EngineIo {
 pub struct EngineSchedule<U> {
     systems: Vec<SystemDescriptor>,
     callbacks: Vec<Callback<U>>,
+    migrations: Vec<Migration>,
+    /// Schemas of the components queried and messages subscribed to by systems
+    schemas: Vec<SchemaSource>,
+}
+
+/// Converts component data from an outdated layout to the current one, if it can
+type MigrationFn = Box<dyn Fn(&[u8]) -> Option<Vec<u8>> + Send + Sync>;
+
+struct Migration {
+    component: ComponentId,
+    convert: MigrationFn,
 }
 
 impl<U> EngineSchedule<U> {
@@ -125,6 +259,68 @@ impl<U> EngineSchedule<U> {
         Self {
             systems: Vec::new(),
             callbacks: Vec::new(),
+            migrations: Vec::new(),
+            schemas: Vec::new(),
+        }
+    }
+
+    /// Convert data of component `C` stored with an older layout `Old`, e.g. `Saved` entities
+    /// created before a field was added to `C`. `Old` is a copy of the previous definition of `C`
+    /// (without the `Component` derive), and must serialize to the size of the old data.
+    ///
+    /// Several migrations may be registered for the same component. Data which no migration
+    /// accepts is left in place, and reported by the host.
+    pub fn migrate<C, Old>(&mut self, convert: fn(Old) -> C)
+    where
+        C: Component + 'static,
+        Old: Serialize + DeserializeOwned + 'static,
+    {
+        self.migrations.push(Migration {
+            component: component_id::<C>(),
+            convert: Box::new(move |data| {
+                let old: Old = deserialize(std::io::Cursor::new(data)).ok()?;
+                // Reject layouts which merely happen to decode
+                if serialize(&old).ok()?.len() != data.len() {
+                    return None;
+                }
+                serialize(&convert(old)).ok()
+            }),
+        });
+    }
+
+    /// Send the schemas of all types used by the schedule, so that the host knows them before
+    /// any of their data arrives
+    fn register_schemas(&self, io: &mut EngineIo) {
+        for &(id, schema) in &self.schemas {
+            io.register(id, schema);
+        }
+    }
+
+    fn migration_targets(&self) -> Vec<ComponentId> {
+        let mut targets: Vec<ComponentId> = vec![];
+        for migration in &self.migrations {
+            if !targets.contains(&migration.component) {
+                targets.push(migration.component.clone());
+            }
+        }
+        targets
+    }
+
+    /// Convert the given component data, producing commands which add the new data
+    fn run_migrations(&self, io: &mut EngineIo, migrations: Vec<MigrationData>) {
+        for MigrationData { to, data, .. } in migrations {
+            let hooks: Vec<&Migration> = self
+                .migrations
+                .iter()
+                .filter(|migration| migration.component == to)
+                .collect();
+
+            for (entity, old) in data {
+                if let Some(new) = hooks.iter().find_map(|migration| (migration.convert)(&old)) {
+                    io.commands
+                        .push(EcsCommand::AddComponent(entity, to.clone(), new));
+                }
+            }
         }
     }
 
@@ -175,20 +371,72 @@ impl<'a, U> SystemBuilder<'a, U> {
 
     /// Query the given component and provide an access level to it.
     pub fn query(mut self, name: &'static str, query: Query) -> Self {
+        self.sched.schemas.extend(query.schemas.iter().copied());
         self.desc.queries.insert(name.to_string(), query);
         self
     }
 
     /// Subscribe to the given channel by telling it which message type you want.
     pub fn subscribe<M: Message>(mut self) -> Self {
+        self.sched.schemas.push((M::CHANNEL.id, M::schema));
         self.desc.subscriptions.push(M::CHANNEL.into());
         self
     }
 
-    /// Builds the system
-    pub fn build(self) {
+    /// Subscribe to requests of the given type, to answer them with `EngineIo::respond`
+    pub fn subscribe_requests<R: Request>(mut self) -> Self {
+        self.desc.subscriptions.push(envelope_channel::<R>());
+        self
+    }
+
+    /// Subscribe to responses to requests of the given type, to collect them with `Caller::poll`
+    pub fn subscribe_responses<R: Request>(mut self) -> Self {
+        self.desc
+            .subscriptions
+            .push(envelope_channel::<R::Response>());
+        self
+    }
+
+    /// Only run the system when a subscribed channel has messages
+    pub fn run_if_messages(mut self) -> Self {
+        self.desc.conditions.push(RunCondition::HasMessages);
+        self
+    }
+
+    /// Run the system at most once every `frames` frames
+    pub fn run_every_frames(mut self, frames: u32) -> Self {
+        self.desc.conditions.push(RunCondition::EveryFrames(frames));
+        self
+    }
+
+    /// Run the system at most once every `seconds` seconds of engine time, e.g. 0.2 for 5 Hz
+    pub fn run_every_seconds(mut self, seconds: f32) -> Self {
+        self.desc
+            .conditions
+            .push(RunCondition::EverySeconds(seconds));
+        self
+    }
+
+    /// Only run the system when the named query matches at least one entity
+    pub fn run_if_query(mut self, name: &'static str) -> Self {
+        self.desc
+            .conditions
+            .push(RunCondition::QueryNotEmpty(name.to_string()));
+        self
+    }
+
+    /// Start with the system disabled. Enable it later with `EngineIo::enable_system`
+    pub fn disabled(mut self) -> Self {
+        self.desc.enabled = false;
+        self
+    }
+
+    /// Builds the system, returning its ID
+    pub fn build(self) -> SystemId {
+        let id = SystemId(self.sched.systems.len());
         self.sched.systems.push(self.desc);
         self.sched.callbacks.push(self.callback);
+        id
     }
 }
 
@@ -209,9 +457,14 @@ impl<U: UserState> PluginState<U> {
 }
 
 impl<U: UserState> PluginState<U> {
-    fn new(io: &mut EngineIo) -> Self {
+    fn new(io: &mut EngineIo, state: Option<UserStateData>) -> Self {
         let mut sched = EngineSchedule::new();
-        let user = U::new(io, &mut sched);
+        let mut user = U::new(io, &mut sched);
+        if let Some(state) = state {
+            if !user.restore_state(state) {
+                log::info!("Discarding incompatible state of the previous instance");
+            }
+        }
         Self { user, sched }
     }
 }
@@ -222,10 +475,12 @@ impl<C: UserState, S: UserState> Context<C, S> {
     /// Called from _reserve() oddly enough, because this structure manages memory.
     pub fn new() -> Self {
         setup_panic();
+        setup_logger();
 
         Self {
             user: None,
             buf: vec![],
+            registered: HashSet::new(),
         }
     }
 
@@ -235,9 +490,31 @@ impl<C: UserState, S: UserState> Context<C, S> {
         let recv: ReceiveBuf =
             deserialize(std::io::Cursor::new(&self.buf)).expect("Failed to decode host message");
 
+        if let Some(level) = recv.log_level {
+            set_log_level(level);
+        }
+
         let mut io = EngineIo::new(recv.inbox);
+        io.registered = std::mem::take(&mut self.registered);
+
+        // Components we can migrate, sent once on init
+        let mut migrations = vec![];
+
+        // In-memory state, sent before a hot reload
+        let mut state = None;
 
-        if let (Some(sys_idx), Some(user)) = (recv.system, self.user.as_mut()) {
+        if recv.save_state {
+            state = match self.user.as_ref().expect("Saving state before init") {
+                ClientOrServerState::Client(c) => c.user.save_state(),
+                ClientOrServerState::Server(s) => s.user.save_state(),
+            };
+        } else if !recv.migrations.is_empty() {
+            // Convert component data stored with outdated layouts
+            match self.user.as_ref().expect("Migrating before init") {
+                ClientOrServerState::Client(c) => c.sched.run_migrations(&mut io, recv.migrations),
+                ClientOrServerState::Server(s) => s.sched.run_migrations(&mut io, recv.migrations),
+            }
+        } else if let (Some(sys_idx), Some(user)) = (recv.system, self.user.as_mut()) {
             // Dispatch plugin code
             match (recv.is_server, user) {
                 (true, ClientOrServerState::Server(s)) => s.dispatch(&mut io, recv.ecs, sys_idx),
@@ -247,8 +524,18 @@ impl<C: UserState, S: UserState> Context<C, S> {
         } else {
             // Initialize plugin internals
             let user = match recv.is_server {
-                true => ClientOrServerState::Server(PluginState::new(&mut io)),
-                false => ClientOrServerState::Client(PluginState::new(&mut io)),
+                true => ClientOrServerState::Server(PluginState::new(&mut io, recv.state)),
+                false => ClientOrServerState::Client(PluginState::new(&mut io, recv.state)),
+            };
+            migrations = match &user {
+                ClientOrServerState::Client(c) => {
+                    c.sched.register_schemas(&mut io);
+                    c.sched.migration_targets()
+                }
+                ClientOrServerState::Server(s) => {
+                    s.sched.register_schemas(&mut io);
+                    s.sched.migration_targets()
+                }
             };
             self.user = Some(user);
         }
@@ -259,10 +546,18 @@ impl<C: UserState, S: UserState> Context<C, S> {
             ClientOrServerState::Server(s) => s.sched.systems.clone(),
         };
 
+        self.registered = std::mem::take(&mut io.registered);
+
         // Write return state
         let send = SendBuf {
             commands: std::mem::take(&mut io.commands),
             outbox: std::mem::take(&mut io.outbox),
+            schemas: std::mem::take(&mut io.schemas),
+            components: std::mem::take(&mut io.components),
+            schedule: std::mem::take(&mut io.schedule),
+            migrations,
+            state,
+            timers: std::mem::take(&mut io.timers),
             systems,
         };
         let len: u32 = serialized_size(&send).expect("Failed to get size of host message") as u32;
@@ -293,6 +588,11 @@ impl EngineIo {
             pcg: Pcg::new(),
             outbox: vec![],
             inbox,
+            schemas: vec![],
+            registered: HashSet::new(),
+            components: vec![],
+            schedule: vec![],
+            timers: vec![],
         }
     }
 
@@ -313,12 +613,35 @@ impl EngineIo {
     /// query for large batches instead
     #[track_caller]
     pub fn add_component<C: Component>(&mut self, entity: EntityId, data: C) {
+        self.register_component::<C>();
         let data = serialize(&data).expect("Failed to serialize component data");
 
         self.commands
             .push(EcsCommand::AddComponent(entity, component_id::<C>(), data));
     }
 
+    /// Create the entities described by the given scene, returning their IDs. Components are
+    /// decoded using the schemas in the given registry
+    pub fn spawn_scene(
+        &mut self,
+        scene: &Scene,
+        registry: &SchemaRegistry,
+    ) -> Result<Vec<EntityId>, SceneError> {
+        let decoded = scene.decode(registry, || EntityId(self.pcg.gen_u128()))?;
+
+        let mut entities = vec![];
+        for (entity, components) in decoded {
+            self.commands.push(EcsCommand::Create(entity));
+            for (component, data) in components {
+                self.commands
+                    .push(EcsCommand::AddComponent(entity, component, data));
+            }
+            entities.push(entity);
+        }
+
+        Ok(entities)
+    }
+
     /// Delete an entity and all of it's components
     pub fn remove_entity(&mut self, id: EntityId) {
         self.commands.push(EcsCommand::Delete(id));
@@ -341,6 +664,14 @@ impl EngineIo {
             })
     }
 
+    /// Read the raw messages received on the given channel
+    pub fn inbox_channel(&self, channel: &ChannelId) -> &[MessageData] {
+        self.inbox
+            .get(channel)
+            .map(|v| v.as_slice())
+            .unwrap_or_default()
+    }
+
     /// Read inbox for this message type, along with client sender information
     pub fn inbox_clients<M: Message>(&mut self) -> impl Iterator<Item = (ClientId, M)> + '_ {
         assert_eq!(
@@ -360,8 +691,32 @@ impl EngineIo {
             })
     }
 
+    /// Send the schema and layout of the given component to the host, unless already sent. Done
+    /// automatically when a component is first added
+    pub fn register_component<C: Component>(&mut self) {
+        if self.register(C::ID, C::schema) {
+            self.components.push(component_id::<C>());
+        }
+    }
+
+    /// Send the schema of the given message to the host, unless already sent. Done automatically
+    /// when a message is first sent
+    pub fn register_message<M: Message>(&mut self) {
+        self.register(M::CHANNEL.id, M::schema);
+    }
+
+    /// Returns `true` if the type was not yet registered
+    fn register(&mut self, id: &'static str, schema: fn() -> Option<Schema>) -> bool {
+        let new = self.registered.insert(id);
+        if new {
+            self.schemas.extend(schema());
+        }
+        new
+    }
+
     /// Send a message
     pub fn send<M: Message>(&mut self, data: &M) {
+        self.register_message::<M>();
         self.outbox.push(MessageData {
             channel: M::CHANNEL.into(),
             data: serialize(data).expect("Failed to serialize message data"),
@@ -371,6 +726,7 @@ impl EngineIo {
 
     /// Send a message to a specific client
     pub fn send_to_client<M: Message>(&mut self, data: &M, client: ClientId) {
+        self.register_message::<M>();
         self.outbox.push(MessageData {
             channel: M::CHANNEL.into(),
             data: serialize(data).expect("Failed to serialize message data"),
@@ -378,6 +734,40 @@ impl EngineIo {
         });
     }
 
+    /// Subscribe the given system to the channel of this message type
+    pub fn subscribe<M: Message>(&mut self, system: SystemId) {
+        self.subscribe_channel(system, M::CHANNEL.into());
+    }
+
+    /// Unsubscribe the given system from the channel of this message type
+    pub fn unsubscribe<M: Message>(&mut self, system: SystemId) {
+        self.unsubscribe_channel(system, M::CHANNEL.into());
+    }
+
+    /// Subscribe the given system to a channel, e.g. one only known at runtime. The system must
+    /// decode the messages itself
+    pub fn subscribe_channel(&mut self, system: SystemId, channel: ChannelId) {
+        self.schedule
+            .push(ScheduleCommand::Subscribe(system, channel));
+    }
+
+    /// Unsubscribe the given system from a channel. Unread messages are discarded
+    pub fn unsubscribe_channel(&mut self, system: SystemId, channel: ChannelId) {
+        self.schedule
+            .push(ScheduleCommand::Unsubscribe(system, channel));
+    }
+
+    /// Resume running the given system. Takes effect once the current stage has been run
+    pub fn enable_system(&mut self, system: SystemId) {
+        self.schedule.push(ScheduleCommand::Enable(system));
+    }
+
+    /// Stop running the given system and delivering messages to it. Takes effect once the current
+    /// stage has been run
+    pub fn disable_system(&mut self, system: SystemId) {
+        self.schedule.push(ScheduleCommand::Disable(system));
+    }
+
     /// Get the first message on this channel, or return None
     pub fn inbox_first<M: Message>(&mut self) -> Option<M> {
         self.inbox
@@ -395,3 +785,123 @@ impl UserState for DummyUserState {
         Self
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pkg_namespace;
+
+    #[derive(Component, Serialize, Deserialize, Default, Copy, Clone, Debug, PartialEq)]
+    struct Health {
+        current: u32,
+        max: u32,
+    }
+
+    #[derive(Component, Serialize, Deserialize, Default, Copy, Clone, Debug, PartialEq)]
+    struct Mana(u32);
+
+    /// Layout of `Health` before `max` was added
+    #[derive(Serialize, Deserialize)]
+    struct OldHealth {
+        current: u32,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct Counter {
+        count: u32,
+    }
+
+    impl UserState for Counter {
+        fn new(_: &mut EngineIo, _: &mut EngineSchedule<Self>) -> Self {
+            Self { count: 0 }
+        }
+
+        crate::persistent_state!();
+    }
+
+    impl PersistentState for Counter {
+        const VERSION: u32 = 2;
+    }
+
+    #[test]
+    fn test_persistent_state() {
+        let mut io = EngineIo::new(Default::default());
+        let mut old = PluginState::<Counter>::new(&mut io, None);
+        old.user.count = 5;
+
+        let saved = old.user.save_state().unwrap();
+        assert_eq!(saved.version, 2);
+        let new = PluginState::<Counter>::new(&mut io, Some(saved.clone()));
+        assert_eq!(new.user.count, 5);
+
+        // Mismatched versions fall back to a fresh state
+        let stale = UserStateData {
+            version: 1,
+            ..saved
+        };
+        let new = PluginState::<Counter>::new(&mut io, Some(stale));
+        assert_eq!(new.user.count, 0);
+    }
+
+    #[test]
+    fn test_register_schemas() {
+        let mut sched = EngineSchedule::<DummyUserState>::new();
+        sched
+            .add_system(|_, _, _| {})
+            .query("health", Query::new().intersect::<Health>(Access::Read))
+            .subscribe::<crate::FrameTime>()
+            .build();
+
+        // Sent at init, before any component is added or message sent
+        let mut io = EngineIo::new(Default::default());
+        sched.register_schemas(&mut io);
+        let ids: Vec<&str> = io.schemas.iter().map(|schema| schema.id.as_str()).collect();
+        assert_eq!(ids, vec![Health::ID, crate::FrameTime::CHANNEL.id]);
+
+        // Only once
+        io.add_component(EntityId(1), Health::default());
+        io.register_message::<crate::FrameTime>();
+        assert_eq!(io.schemas.len(), 2);
+
+        // Layouts of components added without being queried are reported too
+        io.add_component(EntityId(1), Mana(3));
+        io.add_component(EntityId(2), Mana(4));
+        assert_eq!(io.components, vec![component_id::<Mana>()]);
+    }
+
+    #[test]
+    fn test_migrations() {
+        let mut sched = EngineSchedule::<DummyUserState>::new();
+        sched.migrate(|OldHealth { current }| Health { current, max: 100 });
+        assert_eq!(sched.migration_targets(), vec![component_id::<Health>()]);
+
+        let old = ComponentId {
+            id: component_id::<Health>().id,
+            size: 4,
+        };
+        let migration = MigrationData {
+            from: old,
+            to: component_id::<Health>(),
+            data: vec![
+                (EntityId(1), serialize(&OldHealth { current: 7 }).unwrap()),
+                // Some other layout
+                (EntityId(2), vec![0; 2]),
+            ],
+        };
+
+        let mut io = EngineIo::new(Default::default());
+        sched.run_migrations(&mut io, vec![migration]);
+
+        let expected = serialize(&Health {
+            current: 7,
+            max: 100,
+        })
+        .unwrap();
+        assert_eq!(io.commands.len(), 1);
+        assert!(matches!(
+            &io.commands[0],
+            EcsCommand::AddComponent(EntityId(1), id, data)
+                if *id == component_id::<Health>() && *data == expected
+        ));
+    }
+}
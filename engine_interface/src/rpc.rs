@@ -0,0 +1,275 @@
+//! # Request/response calls
+//! A thin layer over message channels for plugins which need answers, such as "what is my
+//! `ClientId`". A [Request] type names its [Response](Request::Response) type, and each request
+//! carries a [RequestId] which is copied into the matching response.
+//!
+//! Requests and responses are routed like any other message with the same locality: locally
+//! between plugins, or remotely between client and server. A server answering a remote request
+//! replies only to the client which asked.
+//!
+//! Requests and responses are wrapped in [Envelope]s, and travel on channels of their own (see
+//! [envelope_channel]) rather than those of the bare types, so they are only seen through
+//! [Caller], [EngineIo::requests] and [EngineIo::respond]. Requesting systems must subscribe
+//! with `subscribe_responses`, and answering systems with `subscribe_requests`.
+//!
+//! ```rust
+//! use cimvr_engine_interface::{pkg_namespace, prelude::*, rpc::*};
+//! use serde::{Deserialize, Serialize};
+//!
+//! #[derive(Message, Serialize, Deserialize)]
+//! #[locality("Remote")]
+//! struct Ping;
+//!
+//! #[derive(Message, Serialize, Deserialize)]
+//! #[locality("Remote")]
+//! struct Pong;
+//!
+//! impl Request for Ping {
+//!     type Response = Pong;
+//! }
+//! ```
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    prelude::*,
+    serial::{deserialize, serialize},
+};
+
+/// A message which expects an answer
+pub trait Request: Message {
+    /// Type of the answer. Should have the same locality as the request
+    type Response: Message;
+}
+
+/// Suffix of the channel IDs of envelopes, appended to the ID of the type they carry
+const ENVELOPE_SUFFIX: &str = "/rpc";
+
+/// Correlates a response with the request it answers
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RequestId(pub u64);
+
+/// Wire format of requests and responses
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Envelope<T> {
+    pub id: RequestId,
+    pub body: T,
+}
+
+/// Where to send the response to a request
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ReplyTo {
+    id: RequestId,
+    client: Option<ClientId>,
+}
+
+/// The request went unanswered for too long
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TimedOut;
+
+/// Sends requests of type `R` and collects their responses.
+///
+/// Keep one of these in the plugin state, and call [Caller::poll] once per frame from a system
+/// subscribed to `R::Response`; timeouts are counted in calls to `poll`.
+pub struct Caller<R> {
+    /// Requests awaiting a response, and the number of frames they have left
+    pending: HashMap<RequestId, u32>,
+    /// Number of frames to wait for a response
+    timeout: u32,
+    _phantom: std::marker::PhantomData<fn() -> R>,
+}
+
+impl<R: Request> Caller<R> {
+    /// Responses not received within `timeout` frames are reported as `TimedOut`
+    pub fn new(timeout: u32) -> Self {
+        Self {
+            pending: HashMap::new(),
+            timeout,
+            _phantom: Default::default(),
+        }
+    }
+
+    /// Send a request. Remote requests sent clientside go to the server
+    pub fn request(&mut self, io: &mut EngineIo, request: &R) -> RequestId {
+        self.request_internal(io, request, None)
+    }
+
+    /// Send a request to a specific client (serverside)
+    pub fn request_to_client(
+        &mut self,
+        io: &mut EngineIo,
+        request: &R,
+        client: ClientId,
+    ) -> RequestId {
+        self.request_internal(io, request, Some(client))
+    }
+
+    fn request_internal(
+        &mut self,
+        io: &mut EngineIo,
+        request: &R,
+        client: Option<ClientId>,
+    ) -> RequestId {
+        // Random, so that callers in different plugins sharing a channel don't collide
+        let id = RequestId(io.random() as u64);
+        io.send_envelope::<R>(&Envelope { id, body: request }, client);
+        self.pending.insert(id, self.timeout);
+        id
+    }
+
+    /// Returns `true` if any requests are awaiting a response
+    pub fn is_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Collect the responses to our requests, and the requests which have timed out. Responses to
+    /// other callers' requests are ignored
+    pub fn poll(&mut self, io: &EngineIo) -> Vec<(RequestId, Result<R::Response, TimedOut>)> {
+        let mut results = vec![];
+        for (_, envelope) in io.envelopes::<R::Response>() {
+            if self.pending.remove(&envelope.id).is_some() {
+                results.push((envelope.id, Ok(envelope.body)));
+            }
+        }
+
+        self.pending.retain(|&id, frames_left| {
+            if *frames_left == 0 {
+                results.push((id, Err(TimedOut)));
+                false
+            } else {
+                *frames_left -= 1;
+                true
+            }
+        });
+
+        results
+    }
+}
+
+/// Channel carrying envelopes of the given request or response type. Distinct from the channel of
+/// the bare type, whose messages have a different layout
+pub fn envelope_channel<M: Message>() -> ChannelId {
+    ChannelId {
+        id: format!("{}{}", M::CHANNEL.id, ENVELOPE_SUFFIX),
+        locality: M::CHANNEL.locality,
+    }
+}
+
+impl ReplyTo {
+    /// ID of the request being answered
+    pub fn id(&self) -> RequestId {
+        self.id
+    }
+
+    /// Client which sent the request, if received remotely serverside
+    pub fn client(&self) -> Option<ClientId> {
+        self.client
+    }
+}
+
+impl EngineIo {
+    /// Read the requests of this type, along with where to send their responses
+    pub fn requests<R: Request>(&self) -> Vec<(ReplyTo, R)> {
+        self.envelopes::<R>()
+            .into_iter()
+            .map(|(client, envelope)| {
+                let reply = ReplyTo {
+                    id: envelope.id,
+                    client,
+                };
+                (reply, envelope.body)
+            })
+            .collect()
+    }
+
+    /// Answer a request
+    pub fn respond<R: Request>(&mut self, reply: ReplyTo, response: &R::Response) {
+        let envelope = Envelope {
+            id: reply.id,
+            body: response,
+        };
+        self.send_envelope::<R::Response>(&envelope, reply.client);
+    }
+
+    fn send_envelope<M: Message>(&mut self, envelope: &Envelope<&M>, client: Option<ClientId>) {
+        self.outbox.push(MessageData {
+            channel: envelope_channel::<M>(),
+            data: serialize(envelope).expect("Failed to serialize message data"),
+            client,
+        });
+    }
+
+    fn envelopes<M: Message>(&self) -> Vec<(Option<ClientId>, Envelope<M>)> {
+        let Some(messages) = self.inbox.get(&envelope_channel::<M>()) else { return vec![] };
+        messages
+            .iter()
+            .filter_map(|m| match deserialize(std::io::Cursor::new(&m.data)) {
+                Ok(envelope) => Some((m.client, envelope)),
+                Err(e) => {
+                    log::error!("Malformed envelope on {}; {}", M::CHANNEL.id, e);
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pkg_namespace;
+
+    #[derive(Message, Serialize, Deserialize, Debug, PartialEq)]
+    #[locality("Remote")]
+    struct Add(u32, u32);
+
+    #[derive(Message, Serialize, Deserialize, Debug, PartialEq)]
+    #[locality("Remote")]
+    struct Sum(u32);
+
+    impl Request for Add {
+        type Response = Sum;
+    }
+
+    /// Move messages sent by one plugin into the inbox of another
+    fn deliver(from: &mut EngineIo, to: &mut EngineIo, client: Option<ClientId>) {
+        to.inbox.clear();
+        for mut msg in from.outbox.drain(..) {
+            msg.client = client;
+            to.inbox.entry(msg.channel.clone()).or_default().push(msg);
+        }
+    }
+
+    #[test]
+    fn test_rpc_roundtrip() {
+        let mut client = EngineIo::new(Default::default());
+        let mut server = EngineIo::new(Default::default());
+        let mut caller = Caller::<Add>::new(1);
+
+        let id = caller.request(&mut client, &Add(2, 3));
+        let other = caller.request(&mut client, &Add(1, 1));
+        deliver(&mut client, &mut server, Some(ClientId(7)));
+
+        // Envelopes are not mistaken for bare messages
+        assert_eq!(server.inbox::<Add>().count(), 0);
+        let channel = envelope_channel::<Add>();
+        assert_eq!(channel.locality, Locality::Remote);
+        assert_eq!(server.inbox_channel(&channel).len(), 2);
+
+        // Only answer the first request
+        let (reply, Add(a, b)) = server.requests::<Add>().remove(0);
+        assert_eq!((reply.id(), reply.client()), (id, Some(ClientId(7))));
+        server.respond::<Add>(reply, &Sum(a + b));
+        assert_eq!(server.outbox[0].client, Some(ClientId(7)));
+        deliver(&mut server, &mut client, None);
+
+        assert_eq!(caller.poll(&client), vec![(id, Ok(Sum(5)))]);
+        assert!(caller.is_pending());
+
+        // The unanswered request times out after one more frame
+        deliver(&mut server, &mut client, None);
+        assert_eq!(caller.poll(&client), vec![(other, Err(TimedOut))]);
+        assert!(!caller.is_pending());
+    }
+}
@@ -0,0 +1,154 @@
+//! # Scenes
+//! Human-readable (JSON) descriptions of entities and their components, which may be loaded into
+//! the ECS by plugins or by the host, and saved back out by the host.
+//!
+//! ```json
+//! {
+//!   "entities": [
+//!     {
+//!       "id": 1,
+//!       "components": {
+//!         "cimvr_common/Transform": { "pos": [0.0, 1.0, 0.0], "orient": [0.0, 0.0, 0.0, 1.0] },
+//!         "cimvr_engine_interface/Synchronized": null
+//!       }
+//!     }
+//!   ]
+//! }
+//! ```
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::{self, Display},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    component_id,
+    prelude::*,
+    schema::{SchemaError, SchemaRegistry},
+    serial::{deserialize, serialize},
+    Parent,
+};
+
+/// A collection of entities and their components, as stored in a scene file
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq)]
+pub struct Scene {
+    pub entities: Vec<SceneEntity>,
+}
+
+/// A single entity in a scene
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq)]
+pub struct SceneEntity {
+    /// Identifies this entity within the scene, so that other entities may refer to it with
+    /// `Parent`. Entities are given fresh IDs when the scene is loaded, and `Parent` components
+    /// are updated to match; other references to entities are not.
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "scene_id")]
+    pub id: Option<EntityId>,
+    /// Component data, keyed by `Component::ID`
+    pub components: BTreeMap<String, Value>,
+}
+
+/// Errors encountered while loading or saving scenes
+#[derive(Debug)]
+pub enum SceneError {
+    /// Component data did not match the registered schema, or none was registered
+    Schema(String, SchemaError),
+    /// Component data could not be converted to or from its binary representation
+    Encoding(String, bincode::Error),
+}
+
+/// Entities decoded from a scene, paired with their components
+pub type DecodedScene = Vec<(EntityId, Vec<(ComponentId, Vec<u8>)>)>;
+
+impl Scene {
+    /// Parse a scene from JSON
+    pub fn from_json(json: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(json)
+    }
+
+    /// Write this scene as (pretty-printed) JSON
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(self)
+    }
+
+    /// Convert the entities in this scene to their binary representation using the schemas in
+    /// the given registry, assigning each a new ID using the given function
+    pub fn decode(
+        &self,
+        registry: &SchemaRegistry,
+        mut new_id: impl FnMut() -> EntityId,
+    ) -> Result<DecodedScene, SceneError> {
+        let ids: Vec<EntityId> = self.entities.iter().map(|_| new_id()).collect();
+
+        let remap: HashMap<EntityId, EntityId> = self
+            .entities
+            .iter()
+            .zip(&ids)
+            .filter_map(|(entity, &new)| Some((entity.id?, new)))
+            .collect();
+
+        let parent = component_id::<Parent>();
+
+        let mut decoded = vec![];
+        for (entity, &id) in self.entities.iter().zip(&ids) {
+            let mut components = vec![];
+            for (name, value) in &entity.components {
+                let mut data = registry
+                    .from_json(name, value)
+                    .map_err(|e| SceneError::Schema(name.clone(), e))?;
+                let component = ComponentId {
+                    id: name.clone(),
+                    size: data.len() as u16,
+                };
+
+                // Point at the new IDs of parents within the scene
+                if component == parent {
+                    let Parent(old) = deserialize(data.as_slice())
+                        .map_err(|e| SceneError::Encoding(name.clone(), e))?;
+                    if let Some(&new) = remap.get(&old) {
+                        data = serialize(&Parent(new))
+                            .map_err(|e| SceneError::Encoding(name.clone(), e))?;
+                    }
+                }
+
+                components.push((component, data));
+            }
+            decoded.push((id, components));
+        }
+
+        Ok(decoded)
+    }
+}
+
+/// Entity IDs are written the same way as `EntityId` fields of components
+mod scene_id {
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+    use serde_json::Value;
+
+    use crate::{prelude::EntityId, schema};
+
+    pub fn serialize<S: Serializer>(id: &Option<EntityId>, s: S) -> Result<S::Ok, S::Error> {
+        id.map(|id| schema::u128_to_json(id.0)).serialize(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<EntityId>, D::Error> {
+        let Some(value) = Option::<Value>::deserialize(d)? else {
+            return Ok(None);
+        };
+        let id = schema::u128_from_json(&value)
+            .ok_or_else(|| D::Error::custom(format!("Invalid entity ID {}", value)))?;
+        Ok(Some(EntityId(id)))
+    }
+}
+
+impl Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SceneError::Schema(id, e) => write!(f, "Invalid data for component {}; {}", id, e),
+            SceneError::Encoding(id, e) => write!(f, "Failed to encode component {}; {}", id, e),
+        }
+    }
+}
+
+impl std::error::Error for SceneError {}
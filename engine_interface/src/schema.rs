@@ -0,0 +1,664 @@
+//! # Schemas
+//! Descriptions of the layout of components and messages, emitted by `#[derive(Component)]` and
+//! `#[derive(Message)]`. Plugins send these to the host the first time each type is used, which
+//! allows the host (and tools built on it) to convert otherwise opaque component and message data
+//! to and from JSON.
+//!
+//! The JSON representation matches what `serde_json` would produce for the original type, except
+//! that 128-bit integers too large for a JSON number are written as strings.
+use std::{
+    collections::HashMap,
+    fmt::{self, Display},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{
+    prelude::*,
+    serial::{deserialize, serialize},
+    Parent, Saved,
+};
+
+/// Maximum depth of nested named types, to guard against self-referential schemas
+const MAX_DEPTH: usize = 32;
+
+/// ID of a component or message type, and a function producing its schema. Lets plugins collect
+/// the types they use without building schemas which may never be sent
+pub(crate) type SchemaSource = (&'static str, fn() -> Option<Schema>);
+
+/// Layout of a component or message type
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Schema {
+    /// `Component::ID`, or the channel ID of a message
+    pub id: String,
+    /// Name of the Rust type
+    pub name: String,
+    /// Fields or variants of the type
+    pub kind: SchemaKind,
+}
+
+/// Shape of a type
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum SchemaKind {
+    Struct(Fields),
+    /// Variants, in declaration order. Encoded as a `u32` variant index followed by its fields
+    Enum(Vec<Variant>),
+}
+
+/// Single variant of an enum
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Variant {
+    pub name: String,
+    pub fields: Fields,
+}
+
+/// Fields of a struct or enum variant
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum Fields {
+    Named(Vec<Field>),
+    Unnamed(Vec<Field>),
+    Unit,
+}
+
+/// Single field of a struct or enum variant
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Field {
+    /// Field name, or its index for tuple structs
+    pub name: String,
+    /// Field type
+    pub ty: FieldType,
+    /// Byte offset of this field within the encoded struct (or variant, including the index).
+    /// None if preceded by a field of variable or unknown size
+    pub offset: Option<u32>,
+}
+
+/// Type of a field
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum FieldType {
+    Bool,
+    U8,
+    U16,
+    U32,
+    U64,
+    U128,
+    I8,
+    I16,
+    I32,
+    I64,
+    I128,
+    F32,
+    F64,
+    Char,
+    String,
+    /// Fixed-length array of the given type
+    Array(Box<FieldType>, u32),
+    Tuple(Vec<FieldType>),
+    /// Variable-length sequence, prefixed with a `u64` length
+    Vec(Box<FieldType>),
+    /// Prefixed with a `u8` tag
+    Option(Box<FieldType>),
+    /// Any other type, by name. Decodable if it is a well-known type (see
+    /// [FieldType::well_known]) or a registered schema shares its name
+    Named(String),
+}
+
+/// Schemas of the components and messages known to the host, by ID
+#[derive(Clone, Debug)]
+pub struct SchemaRegistry {
+    schemas: HashMap<String, Schema>,
+    /// Type names, for looking up nested types
+    names: HashMap<String, String>,
+}
+
+/// Errors encountered while converting data using a schema
+#[derive(Debug)]
+pub enum SchemaError {
+    /// No schema was registered with this ID or type name
+    Unknown(String),
+    /// Data did not match the schema
+    Invalid(String),
+}
+
+impl Schema {
+    /// Describe a struct
+    pub fn new_struct(id: &str, name: &str, fields: Fields) -> Self {
+        Self::new(id, name, SchemaKind::Struct(fields.with_offsets(0)))
+    }
+
+    /// Describe an enum
+    pub fn new_enum(id: &str, name: &str, variants: Vec<Variant>) -> Self {
+        let variants = variants
+            .into_iter()
+            .map(|variant| Variant {
+                name: variant.name,
+                fields: variant.fields.with_offsets(4),
+            })
+            .collect();
+        Self::new(id, name, SchemaKind::Enum(variants))
+    }
+
+    fn new(id: &str, name: &str, kind: SchemaKind) -> Self {
+        Self {
+            id: id.into(),
+            name: name.into(),
+            kind,
+        }
+    }
+}
+
+impl Field {
+    /// A field whose offset has not yet been computed
+    pub fn new(name: &str, ty: FieldType) -> Self {
+        Self {
+            name: name.into(),
+            ty,
+            offset: None,
+        }
+    }
+}
+
+impl Fields {
+    /// Returns the fields, if any
+    pub fn as_slice(&self) -> &[Field] {
+        match self {
+            Fields::Named(fields) | Fields::Unnamed(fields) => fields,
+            Fields::Unit => &[],
+        }
+    }
+
+    fn with_offsets(mut self, start: u32) -> Self {
+        if let Fields::Named(fields) | Fields::Unnamed(fields) = &mut self {
+            let mut offset = Some(start);
+            for field in fields {
+                field.offset = offset;
+                offset = offset.zip(field.ty.size()).map(|(a, b)| a + b);
+            }
+        }
+        self
+    }
+}
+
+impl FieldType {
+    /// Size of this type in the fixint encoding, if it is fixed
+    pub fn size(&self) -> Option<u32> {
+        match self {
+            FieldType::Bool | FieldType::U8 | FieldType::I8 => Some(1),
+            FieldType::U16 | FieldType::I16 => Some(2),
+            FieldType::U32 | FieldType::I32 | FieldType::F32 => Some(4),
+            FieldType::U64 | FieldType::I64 | FieldType::F64 => Some(8),
+            FieldType::U128 | FieldType::I128 => Some(16),
+            FieldType::Char | FieldType::String | FieldType::Vec(_) | FieldType::Option(_) => None,
+            FieldType::Array(ty, len) => ty.size().map(|size| size * len),
+            FieldType::Tuple(tys) => tys.iter().map(FieldType::size).sum(),
+            FieldType::Named(name) => FieldType::well_known(name)?.size(),
+        }
+    }
+
+    /// Layout of well-known types which do not derive a schema themselves
+    pub fn well_known(name: &str) -> Option<FieldType> {
+        let floats = |n| Some(FieldType::Array(Box::new(FieldType::F32), n));
+        match name {
+            "Vec2" => floats(2),
+            "Vec3" | "Vec3A" => floats(3),
+            "Vec4" | "Quat" | "Mat2" => floats(4),
+            "Mat3" => floats(9),
+            "Mat4" => floats(16),
+            "EntityId" => Some(FieldType::U128),
+            "ClientId" => Some(FieldType::U32),
+            _ => None,
+        }
+    }
+}
+
+impl SchemaRegistry {
+    /// Create a registry containing the types built into the engine interface
+    pub fn new() -> Self {
+        let mut registry = Self {
+            schemas: HashMap::new(),
+            names: HashMap::new(),
+        };
+        let builtin = [
+            Synchronized::schema(),
+            Saved::schema(),
+            Parent::schema(),
+            ClientOwned::schema(),
+            Predicted::schema(),
+        ];
+        registry.extend(builtin.into_iter().flatten());
+        registry
+    }
+
+    /// Add a schema, replacing any other with the same ID
+    pub fn insert(&mut self, schema: Schema) {
+        self.names.insert(schema.name.clone(), schema.id.clone());
+        self.schemas.insert(schema.id.clone(), schema);
+    }
+
+    /// Get the schema with the given ID
+    pub fn get(&self, id: &str) -> Option<&Schema> {
+        self.schemas.get(id)
+    }
+
+    /// Iterate over all schemas
+    pub fn iter(&self) -> impl Iterator<Item = &Schema> {
+        self.schemas.values()
+    }
+
+    /// Convert data of the type with the given ID to JSON
+    pub fn to_json(&self, id: &str, data: &[u8]) -> Result<Value, SchemaError> {
+        let schema = self.lookup(id)?;
+        let mut reader = Reader(data);
+        self.decode_schema(schema, &mut reader, 0)
+    }
+
+    /// Convert JSON to data of the type with the given ID
+    pub fn from_json(&self, id: &str, value: &Value) -> Result<Vec<u8>, SchemaError> {
+        let schema = self.lookup(id)?;
+        let mut out = vec![];
+        self.encode_schema(schema, value, &mut out, 0)?;
+        Ok(out)
+    }
+
+    fn lookup(&self, id: &str) -> Result<&Schema, SchemaError> {
+        self.get(id).ok_or_else(|| SchemaError::Unknown(id.into()))
+    }
+
+    fn lookup_name(&self, name: &str) -> Result<&Schema, SchemaError> {
+        self.names
+            .get(name)
+            .and_then(|id| self.get(id))
+            .ok_or_else(|| SchemaError::Unknown(name.into()))
+    }
+
+    fn decode_schema(
+        &self,
+        schema: &Schema,
+        r: &mut Reader,
+        depth: usize,
+    ) -> Result<Value, SchemaError> {
+        if depth > MAX_DEPTH {
+            return Err(SchemaError::Invalid(format!(
+                "{} is too deeply nested",
+                schema.name
+            )));
+        }
+
+        match &schema.kind {
+            SchemaKind::Struct(fields) => self.decode_fields(fields, r, depth),
+            SchemaKind::Enum(variants) => {
+                let index: u32 = r.primitive()?;
+                let variant = variants.get(index as usize).ok_or_else(|| {
+                    SchemaError::Invalid(format!("No variant {} of {}", index, schema.name))
+                })?;
+                let name = Value::String(variant.name.clone());
+                Ok(match &variant.fields {
+                    Fields::Unit => name,
+                    fields => {
+                        let mut map = Map::new();
+                        map.insert(variant.name.clone(), self.decode_fields(fields, r, depth)?);
+                        Value::Object(map)
+                    }
+                })
+            }
+        }
+    }
+
+    fn decode_fields(
+        &self,
+        fields: &Fields,
+        r: &mut Reader,
+        depth: usize,
+    ) -> Result<Value, SchemaError> {
+        match fields {
+            Fields::Named(fields) => {
+                let mut map = Map::new();
+                for field in fields {
+                    map.insert(field.name.clone(), self.decode(&field.ty, r, depth)?);
+                }
+                Ok(Value::Object(map))
+            }
+            // Newtypes are represented by their contents
+            Fields::Unnamed(fields) if fields.len() == 1 => self.decode(&fields[0].ty, r, depth),
+            Fields::Unnamed(fields) => fields
+                .iter()
+                .map(|field| self.decode(&field.ty, r, depth))
+                .collect(),
+            Fields::Unit => Ok(Value::Null),
+        }
+    }
+
+    fn decode(&self, ty: &FieldType, r: &mut Reader, depth: usize) -> Result<Value, SchemaError> {
+        Ok(match ty {
+            FieldType::Bool => to_value(r.primitive::<bool>()?)?,
+            FieldType::U8 => to_value(r.primitive::<u8>()?)?,
+            FieldType::U16 => to_value(r.primitive::<u16>()?)?,
+            FieldType::U32 => to_value(r.primitive::<u32>()?)?,
+            FieldType::U64 => to_value(r.primitive::<u64>()?)?,
+            FieldType::U128 => u128_to_json(r.primitive()?),
+            FieldType::I8 => to_value(r.primitive::<i8>()?)?,
+            FieldType::I16 => to_value(r.primitive::<i16>()?)?,
+            FieldType::I32 => to_value(r.primitive::<i32>()?)?,
+            FieldType::I64 => to_value(r.primitive::<i64>()?)?,
+            FieldType::I128 => i128_to_json(r.primitive()?),
+            FieldType::F32 => to_value(r.primitive::<f32>()?)?,
+            FieldType::F64 => to_value(r.primitive::<f64>()?)?,
+            FieldType::Char => to_value(r.primitive::<char>()?)?,
+            FieldType::String => to_value(r.primitive::<String>()?)?,
+            FieldType::Array(ty, len) => (0..*len)
+                .map(|_| self.decode(ty, r, depth))
+                .collect::<Result<_, _>>()?,
+            FieldType::Tuple(tys) => tys
+                .iter()
+                .map(|ty| self.decode(ty, r, depth))
+                .collect::<Result<_, _>>()?,
+            FieldType::Vec(ty) => {
+                let len: u64 = r.primitive()?;
+                (0..len)
+                    .map(|_| self.decode(ty, r, depth))
+                    .collect::<Result<_, _>>()?
+            }
+            FieldType::Option(ty) => match r.primitive::<u8>()? {
+                0 => Value::Null,
+                _ => self.decode(ty, r, depth)?,
+            },
+            FieldType::Named(name) => match FieldType::well_known(name) {
+                Some(ty) => self.decode(&ty, r, depth)?,
+                None => self.decode_schema(self.lookup_name(name)?, r, depth + 1)?,
+            },
+        })
+    }
+
+    fn encode_schema(
+        &self,
+        schema: &Schema,
+        value: &Value,
+        out: &mut Vec<u8>,
+        depth: usize,
+    ) -> Result<(), SchemaError> {
+        if depth > MAX_DEPTH {
+            return Err(SchemaError::Invalid(format!(
+                "{} is too deeply nested",
+                schema.name
+            )));
+        }
+
+        match &schema.kind {
+            SchemaKind::Struct(fields) => self.encode_fields(fields, value, out, depth),
+            SchemaKind::Enum(variants) => {
+                // Unit variants are represented by their name, others by a single-entry object
+                let (name, inner) = match value {
+                    Value::String(name) => (name, &Value::Null),
+                    Value::Object(map) if map.len() == 1 => map.iter().next().unwrap(),
+                    _ => return Err(mismatch(&schema.name, value)),
+                };
+                let (index, variant) = variants
+                    .iter()
+                    .enumerate()
+                    .find(|(_, variant)| &variant.name == name)
+                    .ok_or_else(|| {
+                        SchemaError::Invalid(format!("No variant {} of {}", name, schema.name))
+                    })?;
+                write(out, &(index as u32))?;
+                self.encode_fields(&variant.fields, inner, out, depth)
+            }
+        }
+    }
+
+    fn encode_fields(
+        &self,
+        fields: &Fields,
+        value: &Value,
+        out: &mut Vec<u8>,
+        depth: usize,
+    ) -> Result<(), SchemaError> {
+        match fields {
+            Fields::Named(fields) => {
+                let map = value.as_object().ok_or_else(|| mismatch("struct", value))?;
+                for field in fields {
+                    let value = map.get(&field.name).ok_or_else(|| {
+                        SchemaError::Invalid(format!("Missing field {}", field.name))
+                    })?;
+                    self.encode(&field.ty, value, out, depth)?;
+                }
+                Ok(())
+            }
+            Fields::Unnamed(fields) if fields.len() == 1 => {
+                self.encode(&fields[0].ty, value, out, depth)
+            }
+            Fields::Unnamed(fields) => {
+                let values = as_array(value, fields.len())?;
+                for (field, value) in fields.iter().zip(values) {
+                    self.encode(&field.ty, value, out, depth)?;
+                }
+                Ok(())
+            }
+            Fields::Unit => Ok(()),
+        }
+    }
+
+    fn encode(
+        &self,
+        ty: &FieldType,
+        value: &Value,
+        out: &mut Vec<u8>,
+        depth: usize,
+    ) -> Result<(), SchemaError> {
+        match ty {
+            FieldType::Bool => write(out, &from_value::<bool>(value)?),
+            FieldType::U8 => write(out, &from_value::<u8>(value)?),
+            FieldType::U16 => write(out, &from_value::<u16>(value)?),
+            FieldType::U32 => write(out, &from_value::<u32>(value)?),
+            FieldType::U64 => write(out, &from_value::<u64>(value)?),
+            FieldType::U128 => {
+                let x = u128_from_json(value).ok_or_else(|| mismatch("u128", value))?;
+                write(out, &x)
+            }
+            FieldType::I8 => write(out, &from_value::<i8>(value)?),
+            FieldType::I16 => write(out, &from_value::<i16>(value)?),
+            FieldType::I32 => write(out, &from_value::<i32>(value)?),
+            FieldType::I64 => write(out, &from_value::<i64>(value)?),
+            FieldType::I128 => {
+                let x = i128_from_json(value).ok_or_else(|| mismatch("i128", value))?;
+                write(out, &x)
+            }
+            FieldType::F32 => write(out, &from_value::<f32>(value)?),
+            FieldType::F64 => write(out, &from_value::<f64>(value)?),
+            FieldType::Char => write(out, &from_value::<char>(value)?),
+            FieldType::String => write(out, &from_value::<String>(value)?),
+            FieldType::Array(ty, len) => {
+                for value in as_array(value, *len as usize)? {
+                    self.encode(ty, value, out, depth)?;
+                }
+                Ok(())
+            }
+            FieldType::Tuple(tys) => {
+                for (ty, value) in tys.iter().zip(as_array(value, tys.len())?) {
+                    self.encode(ty, value, out, depth)?;
+                }
+                Ok(())
+            }
+            FieldType::Vec(ty) => {
+                let values = value.as_array().ok_or_else(|| mismatch("array", value))?;
+                write(out, &(values.len() as u64))?;
+                for value in values {
+                    self.encode(ty, value, out, depth)?;
+                }
+                Ok(())
+            }
+            FieldType::Option(ty) => match value {
+                Value::Null => write(out, &0_u8),
+                value => {
+                    write(out, &1_u8)?;
+                    self.encode(ty, value, out, depth)
+                }
+            },
+            FieldType::Named(name) => match FieldType::well_known(name) {
+                Some(ty) => self.encode(&ty, value, out, depth),
+                None => self.encode_schema(self.lookup_name(name)?, value, out, depth + 1),
+            },
+        }
+    }
+}
+
+impl Extend<Schema> for SchemaRegistry {
+    fn extend<T: IntoIterator<Item = Schema>>(&mut self, iter: T) {
+        for schema in iter {
+            self.insert(schema);
+        }
+    }
+}
+
+impl Default for SchemaRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Reads primitives from the front of a buffer
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn primitive<T: serde::de::DeserializeOwned>(&mut self) -> Result<T, SchemaError> {
+        deserialize(&mut self.0).map_err(|e| SchemaError::Invalid(e.to_string()))
+    }
+}
+
+fn write<T: Serialize>(out: &mut Vec<u8>, value: &T) -> Result<(), SchemaError> {
+    let data = serialize(value).map_err(|e| SchemaError::Invalid(e.to_string()))?;
+    out.extend(data);
+    Ok(())
+}
+
+fn to_value<T: Serialize>(value: T) -> Result<Value, SchemaError> {
+    serde_json::to_value(value).map_err(|e| SchemaError::Invalid(e.to_string()))
+}
+
+fn from_value<T: serde::de::DeserializeOwned>(value: &Value) -> Result<T, SchemaError> {
+    T::deserialize(value).map_err(|e| SchemaError::Invalid(e.to_string()))
+}
+
+/// Write a `u128` as a JSON number if it fits in a `u64`, and as a string otherwise
+pub(crate) fn u128_to_json(x: u128) -> Value {
+    match u64::try_from(x) {
+        Ok(x) => x.into(),
+        Err(_) => x.to_string().into(),
+    }
+}
+
+/// Read a `u128` written by [u128_to_json]
+pub(crate) fn u128_from_json(value: &Value) -> Option<u128> {
+    match value {
+        Value::Number(n) => n.as_u64().map(u128::from),
+        Value::String(s) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+fn i128_to_json(x: i128) -> Value {
+    match i64::try_from(x) {
+        Ok(x) => x.into(),
+        Err(_) => x.to_string().into(),
+    }
+}
+
+fn i128_from_json(value: &Value) -> Option<i128> {
+    match value {
+        Value::Number(n) => n.as_i64().map(i128::from),
+        Value::String(s) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+fn as_array(value: &Value, len: usize) -> Result<&[Value], SchemaError> {
+    match value.as_array() {
+        Some(values) if values.len() == len => Ok(values),
+        _ => Err(mismatch(&format!("array of length {}", len), value)),
+    }
+}
+
+fn mismatch(expected: &str, value: &Value) -> SchemaError {
+    SchemaError::Invalid(format!("Expected {}, found {}", expected, value))
+}
+
+impl Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SchemaError::Unknown(id) => write!(f, "No schema for {}", id),
+            SchemaError::Invalid(e) => write!(f, "Data does not match schema; {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SchemaError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pkg_namespace;
+
+    #[derive(Component, Serialize, Deserialize, Default, Copy, Clone, Debug, PartialEq)]
+    struct Particle {
+        pos: [f32; 3],
+        mass: f64,
+        id: u128,
+        charge: Option<i8>,
+        spin: Spin,
+    }
+
+    #[derive(Message, Serialize, Deserialize, Default, Copy, Clone, Debug, PartialEq)]
+    #[locality("Local")]
+    enum Spin {
+        #[default]
+        Up,
+        Down(u8),
+        Other {
+            x: f32,
+            y: f32,
+        },
+    }
+
+    #[test]
+    fn test_schema_json_roundtrip() {
+        let mut registry = SchemaRegistry::new();
+        registry.extend(Particle::schema());
+        registry.extend(Spin::schema());
+
+        // Offsets are known up to the first variable-size field
+        let schema = Particle::schema().unwrap();
+        let SchemaKind::Struct(fields) = &schema.kind else {
+            panic!()
+        };
+        let offsets: Vec<_> = fields.as_slice().iter().map(|f| f.offset).collect();
+        assert_eq!(offsets, [Some(0), Some(12), Some(20), Some(36), None]);
+
+        for spin in [Spin::Up, Spin::Down(3), Spin::Other { x: 1., y: -2. }] {
+            let particle = Particle {
+                pos: [1., 2., 3.],
+                mass: 4.,
+                id: u64::MAX.into(),
+                charge: Some(-1),
+                spin,
+            };
+            let data = serialize(&particle).unwrap();
+
+            // Matches the representation of the type itself
+            let json = registry.to_json(Particle::ID, &data).unwrap();
+            assert_eq!(json, serde_json::to_value(particle).unwrap());
+            assert_eq!(registry.from_json(Particle::ID, &json).unwrap(), data);
+        }
+
+        // Integers too large for a JSON number are written as strings
+        let particle = Particle {
+            id: u128::MAX,
+            ..Default::default()
+        };
+        let data = serialize(&particle).unwrap();
+        let json = registry.to_json(Particle::ID, &data).unwrap();
+        assert_eq!(json["id"], Value::String(u128::MAX.to_string()));
+        assert_eq!(registry.from_json(Particle::ID, &json).unwrap(), data);
+
+        assert!(registry.to_json("nope/Nope", &[]).is_err());
+        assert!(registry.to_json(Particle::ID, &[0; 4]).is_err());
+    }
+}
@@ -5,7 +5,7 @@ use std::{
     io::{Read, Write},
 };
 
-use crate::prelude::*;
+use crate::{prelude::*, schema::Schema, timer::TimerCommand};
 use bincode::Options;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
@@ -50,6 +50,35 @@ pub struct ReceiveBuf {
     pub inbox: Inbox,
     /// True if plugin is server-side
     pub is_server: bool,
+    /// Component data stored with outdated layouts, to be converted by the plugin's migrations
+    pub migrations: Vec<MigrationData>,
+    /// Serialize the plugin's in-memory state instead of running a system, before a hot reload
+    pub save_state: bool,
+    /// In-memory state saved by the previous instance of the plugin. Only given on init
+    pub state: Option<UserStateData>,
+    /// Most verbose level logged by the host, as a `log::LevelFilter` integer (0 is off). Records
+    /// above it are not sent by the plugin at all. Only given on init
+    pub log_level: Option<u8>,
+}
+
+/// Component data stored under an outdated layout of a component
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MigrationData {
+    /// Outdated layout
+    pub from: ComponentId,
+    /// Current layout, for which the plugin registered a migration
+    pub to: ComponentId,
+    /// Data of each entity with the outdated layout
+    pub data: Vec<(EntityId, Vec<u8>)>,
+}
+
+/// In-memory state of a plugin, carried across a hot reload
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UserStateData {
+    /// Layout version, see `PersistentState::VERSION`
+    pub version: u32,
+    /// Serialized state
+    pub data: Vec<u8>,
 }
 
 /// Data transferred from Plugin to Host
@@ -63,8 +92,20 @@ pub struct SendBuf {
     */
     /// Schedule setup on init. Must be empty except for first use!
     pub systems: Vec<SystemDescriptor>,
+    /// Changes to the schedule made since the last dispatch
+    pub schedule: Vec<ScheduleCommand>,
+    /// Timers started or cancelled since the last dispatch
+    pub timers: Vec<TimerCommand>,
+    /// Components for which the plugin can convert data from outdated layouts. Only sent on init
+    pub migrations: Vec<ComponentId>,
+    /// In-memory state, if requested and the plugin keeps it across hot reloads
+    pub state: Option<UserStateData>,
     /// Message outbox
     pub outbox: Vec<MessageData>,
+    /// Schemas of components and messages used for the first time
+    pub schemas: Vec<Schema>,
+    /// Components added for the first time, other than those queried by the plugin's systems
+    pub components: Vec<ComponentId>,
 }
 
 fn bincode_opts() -> impl Options {
@@ -1,8 +1,33 @@
+use serde::{Deserialize, Serialize};
+
+#[cfg(target_family = "wasm")]
+use crate::serial::serialize;
+
 extern "C" {
     #[cfg(target_family = "wasm")]
     fn _print(ptr: *const u8, len: usize);
+    #[cfg(target_family = "wasm")]
+    fn _log(ptr: *const u8, len: usize);
 }
 
+/// A `log` record sent from a plugin to the host, which re-emits it with the plugin's name as the
+/// target
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LogRecord {
+    /// `log::Level` as an integer, 1 (error) through 5 (trace)
+    pub level: u8,
+    /// Target within the plugin, usually its module path
+    pub target: String,
+    pub message: String,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+}
+
+/// Sends log records to the host
+struct PluginLogger;
+
+static LOGGER: PluginLogger = PluginLogger;
+
 pub fn _print_str(s: &str) {
     #[cfg(target_family = "wasm")]
     unsafe {
@@ -20,6 +45,62 @@ pub(crate) fn setup_panic() {
     }))
 }
 
+/// Route records from the `log` crate to the host. Nothing is logged until the host's level is
+/// known (see `set_log_level`)
+pub(crate) fn setup_logger() {
+    let _ = log::set_logger(&LOGGER);
+}
+
+/// Only send records the host logs at all, given its `log::LevelFilter` as an integer. Finer
+/// filtering (e.g. per plugin) happens host-side
+pub(crate) fn set_log_level(level: u8) {
+    let filter = log::LevelFilter::iter()
+        .find(|filter| *filter as u8 == level)
+        .unwrap_or(log::LevelFilter::Trace);
+    log::set_max_level(filter);
+}
+
+impl LogRecord {
+    /// Level of the record, if valid
+    pub fn level(&self) -> Option<log::Level> {
+        log::Level::iter().find(|level| *level as u8 == self.level)
+    }
+}
+
+impl log::Log for PluginLogger {
+    fn enabled(&self, _: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        #[cfg(target_family = "wasm")]
+        {
+            let record = LogRecord {
+                level: record.level() as u8,
+                target: record.target().to_string(),
+                message: record.args().to_string(),
+                file: record.file().map(|file| file.to_string()),
+                line: record.line(),
+            };
+            if let Ok(data) = serialize(&record) {
+                unsafe {
+                    _log(data.as_ptr(), data.len());
+                }
+            }
+        }
+
+        #[cfg(not(target_family = "wasm"))]
+        _print_str(&format!(
+            "[{} {}] {}",
+            record.level(),
+            record.target(),
+            record.args()
+        ));
+    }
+
+    fn flush(&self) {}
+}
+
 /// Similar to the print!() macro from the stdlib, but for plugins.
 #[macro_export]
 macro_rules! print {
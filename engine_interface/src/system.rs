@@ -13,6 +13,41 @@ pub struct SystemDescriptor {
     pub subscriptions: Vec<ChannelId>,
     /// Component queries
     pub queries: HashMap<String, Query>,
+    /// Disabled systems are not run, and do not receive messages
+    pub enabled: bool,
+    /// The system only runs in its stage when all of these hold
+    pub conditions: Vec<RunCondition>,
+}
+
+/// Criterion deciding whether a system runs in a given frame. Systems which do not run are skipped
+/// by the host entirely; their messages are kept until they next run
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum RunCondition {
+    /// Run only when a subscribed channel has messages
+    HasMessages,
+    /// Run at most once every this many frames
+    EveryFrames(u32),
+    /// Run at most once every this many seconds of engine time
+    EverySeconds(f32),
+    /// Run only when the named query matches at least one entity
+    QueryNotEmpty(String),
+}
+
+/// Identifies a system within its plugin, by the order in which it was added
+#[derive(Clone, Copy, Debug, Hash, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SystemId(pub usize);
+
+/// Changes to a plugin's schedule after initialization
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum ScheduleCommand {
+    /// Deliver messages on the channel to the system
+    Subscribe(SystemId, ChannelId),
+    /// Stop delivering messages on the channel to the system. Unread messages are discarded
+    Unsubscribe(SystemId, ChannelId),
+    /// Resume running the system
+    Enable(SystemId),
+    /// Stop running the system. Unread messages are discarded
+    Disable(SystemId),
 }
 
 /// This flag indicates which stage the plugin is to be executed **after**.
@@ -41,6 +76,8 @@ impl Default for SystemDescriptor {
             stage: Stage::Update,
             subscriptions: vec![],
             queries: Default::default(),
+            enabled: true,
+            conditions: vec![],
         }
     }
 }
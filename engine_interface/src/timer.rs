@@ -0,0 +1,102 @@
+//! # Timers
+//! Host-managed timers, for delayed and periodic actions. Timers run on the engine clock (see
+//! `FrameTime`), so they respect pausing and time scaling, and they survive hot reloads of the
+//! plugin which started them.
+//!
+//! When a timer expires, the host places a [TimerFired] message carrying the timer's payload
+//! directly into the inbox of the system which owns it; the system need not subscribe to it.
+//! Timers of a disabled system are held, and fire once it is enabled again.
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    pkg_namespace,
+    prelude::*,
+    serial::{deserialize, serialize},
+};
+
+/// Identifies a timer within its plugin
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TimerId(pub u64);
+
+/// When a timer fires
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Timer {
+    /// Seconds of engine time until the timer first fires
+    pub delay: f32,
+    /// If set, the timer fires again every this many seconds until cancelled. Repeating timers
+    /// fire at most once per frame
+    pub repeat: Option<f32>,
+}
+
+/// Timer changes sent from plugins to the host
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum TimerCommand {
+    Start {
+        id: TimerId,
+        /// System which receives the `TimerFired` messages
+        system: SystemId,
+        timer: Timer,
+        payload: Vec<u8>,
+    },
+    Cancel(TimerId),
+}
+
+/// Sent to the owning system when a timer expires
+#[derive(Message, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[locality("Local")]
+pub struct TimerFired {
+    pub id: TimerId,
+    /// Number of times this timer has fired, including this time
+    pub count: u32,
+    /// Payload given when the timer was started
+    pub payload: Vec<u8>,
+}
+
+impl Timer {
+    /// Fire once, after `delay` seconds
+    pub fn once(delay: f32) -> Self {
+        Self {
+            delay,
+            repeat: None,
+        }
+    }
+
+    /// Fire every `period` seconds, starting `period` seconds from now
+    pub fn repeating(period: f32) -> Self {
+        Self {
+            delay: period,
+            repeat: Some(period),
+        }
+    }
+}
+
+impl TimerFired {
+    /// Decode the payload given when the timer was started
+    pub fn payload<T: DeserializeOwned>(&self) -> bincode::Result<T> {
+        deserialize(std::io::Cursor::new(&self.payload))
+    }
+}
+
+impl EngineIo {
+    /// Start a timer, which sends `TimerFired` with the given payload to `system` when it expires
+    pub fn start_timer<T: Serialize>(
+        &mut self,
+        system: SystemId,
+        timer: Timer,
+        payload: &T,
+    ) -> TimerId {
+        let id = TimerId(self.random() as u64);
+        self.timers.push(TimerCommand::Start {
+            id,
+            system,
+            timer,
+            payload: serialize(payload).expect("Failed to serialize timer payload"),
+        });
+        id
+    }
+
+    /// Stop a timer before it fires (again)
+    pub fn cancel_timer(&mut self, id: TimerId) {
+        self.timers.push(TimerCommand::Cancel(id));
+    }
+}
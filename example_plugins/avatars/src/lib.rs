@@ -5,7 +5,12 @@ use cimvr_common::{
     vr::VrUpdate,
     Transform,
 };
-use cimvr_engine_interface::{dbg, make_app_state, pkg_namespace, prelude::*, FrameTime};
+use cimvr_engine_interface::{
+    dbg, make_app_state, pkg_namespace,
+    prelude::*,
+    rpc::{Caller, Request},
+    FrameTime,
+};
 
 use serde::{Deserialize, Serialize};
 
@@ -15,6 +20,9 @@ struct ServerState {
 
 struct ClientState {
     animation: SkeletonAnimator,
+    whoami: Caller<WhoAmI>,
+    /// Our connection ID, once the server has told us
+    client_id: Option<ClientId>,
 }
 
 make_app_state!(ClientState, ServerState);
@@ -29,11 +37,23 @@ pub struct AvatarUpdate {
     skeleton: Skeleton,
 }
 
+/// Asks the server which ID this client has
+#[derive(Message, Serialize, Deserialize, Clone)]
+#[locality("Remote")]
+pub struct WhoAmI;
+
 /// Informs a client which ID it has
 #[derive(Message, Serialize, Deserialize, Clone)]
 #[locality("Remote")]
 pub struct ClientIdMessage(ClientId);
 
+impl Request for WhoAmI {
+    type Response = ClientIdMessage;
+}
+
+/// Frames to wait for the server to tell us our ID before asking again
+const WHOAMI_TIMEOUT: u32 = 120;
+
 /// Associates an entity server-side with a client ID
 #[derive(Component, Serialize, Deserialize, Clone, Default, Copy)]
 pub struct AvatarComponent(ClientId);
@@ -56,7 +76,7 @@ impl UserState for ClientState {
         sched
             .add_system(Self::update)
             .subscribe::<VrUpdate>()
-            .subscribe::<ClientIdMessage>()
+            .subscribe_responses::<WhoAmI>()
             .subscribe::<FrameTime>()
             .query(
                 "Camera",
@@ -78,14 +98,29 @@ impl UserState for ClientState {
             )
             .build();
 
+        let mut whoami = Caller::new(WHOAMI_TIMEOUT);
+        whoami.request(io, &WhoAmI);
+
         Self {
             animation: SkeletonAnimator::new(),
+            whoami,
+            client_id: None,
         }
     }
 }
 
 impl ClientState {
     fn update(&mut self, io: &mut EngineIo, query: &mut QueryResult) {
+        // Find out which client we are, asking again if the server didn't answer
+        for (_, response) in self.whoami.poll(io) {
+            match response {
+                Ok(ClientIdMessage(client_id)) => self.client_id = Some(client_id),
+                Err(_) => {
+                    self.whoami.request(io, &WhoAmI);
+                }
+            }
+        }
+
         let Some(FrameTime { delta, .. }) = io.inbox_first() else { return };
 
         // Get the camera position
@@ -113,7 +148,7 @@ impl ClientState {
         io.send(&AvatarUpdate { skeleton });
 
         // Delete our own avatar from the scene...
-        if let Some(ClientIdMessage(client_id)) = io.inbox_first() {
+        if let Some(client_id) = self.client_id {
             for entity in query.iter("Avatars") {
                 let AvatarComponent(other_client_id) = query.read(entity);
                 if other_client_id == client_id {
@@ -160,6 +195,12 @@ impl UserState for ServerState {
             )
             .build();
 
+        sched
+            .add_system(Self::answer_whoami)
+            .subscribe_requests::<WhoAmI>()
+            .run_if_messages()
+            .build();
+
         Self {
             tracker: ClientTracker::new(),
         }
@@ -225,9 +266,15 @@ impl ServerState {
             if let Some(entity) = skeleton_entity {
                 io.add_component(entity, AvatarSkeleton(update.skeleton));
             }
+        }
+    }
 
-            // Inform the client which one it is
-            io.send_to_client(&ClientIdMessage(client), client);
+    /// Inform clients which one they are
+    fn answer_whoami(&mut self, io: &mut EngineIo, _query: &mut QueryResult) {
+        for (reply, WhoAmI) in io.requests::<WhoAmI>() {
+            if let Some(client) = reply.client() {
+                io.respond::<WhoAmI>(reply, &ClientIdMessage(client));
+            }
         }
     }
 }
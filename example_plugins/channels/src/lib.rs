@@ -1,6 +1,8 @@
-use cimvr_engine_interface::{dbg, make_app_state, pkg_namespace, prelude::*};
+use cimvr_engine_interface::{dbg, make_app_state, persistent_state, pkg_namespace, prelude::*};
 use serde::{Deserialize, Serialize};
 
+/// Keeps counting where it left off when the plugin is hot reloaded
+#[derive(Serialize, Deserialize)]
 struct ClientState {
     increment: i32,
 }
@@ -29,6 +31,12 @@ impl UserState for ClientState {
 
         Self { increment: 0 }
     }
+
+    persistent_state!();
+}
+
+impl PersistentState for ClientState {
+    const VERSION: u32 = 1;
 }
 
 impl ClientState {
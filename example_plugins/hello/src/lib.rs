@@ -11,6 +11,9 @@ impl UserState for ClientState {
         cimvr_engine_interface::println!("This prints");
         std::println!("But this doesn't");
 
+        // Log records reach the host's logger, with this plugin's name as the target
+        info!("Hello from the log");
+
         Self
     }
 }
@@ -5,8 +5,7 @@ use cimvr_common::{
     render::{Mesh, MeshHandle, Primitive, Render, UploadMesh, Vertex},
     Transform,
 };
-use cimvr_engine_interface::{dbg, make_app_state, pkg_namespace, prelude::*, FrameTime};
-use parenting::ChildOf;
+use cimvr_engine_interface::{dbg, make_app_state, pkg_namespace, prelude::*, FrameTime, Parent};
 use serde::{Deserialize, Serialize};
 
 struct ServerState;
@@ -50,15 +49,14 @@ impl UserState for ServerState {
             let angle = TAU * i as f32 / n as f32;
             let _cube_ent = io
                 .create_entity()
-                .add_component(Transform::default())
-                .add_component(Render::new(CUBE_HANDLE).primitive(Primitive::Triangles))
-                .add_component(Synchronized)
-                .add_component(ChildOf(
-                    parent_id,
+                .add_component(
                     Transform::new()
                         .with_position(10. * Vec3::new(0., angle.cos(), angle.sin()))
                         .with_rotation(Quat::from_euler(EulerRot::XYZ, angle, 0., 0.)),
-                ))
+                )
+                .add_component(Render::new(CUBE_HANDLE).primitive(Primitive::Triangles))
+                .add_component(Synchronized)
+                .add_component(Parent(parent_id))
                 .build();
         }
 
@@ -1,25 +1,33 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 
-use cimvr_engine::hotload::Hotloader;
+use cimvr_common::{glam::Vec3, register_schemas, GlobalTransform, QuantizedTransform, Transform};
+use cimvr_engine::hierarchy::Hierarchy;
+use cimvr_engine::hotload::{wasm_files, HotloadEvent, Hotloader};
 use cimvr_engine::interface::prelude::{
-    Access, ClientId, ConnectionRequest, ConnectionResponse, Connections, Digest, PluginData,
-    Query, Synchronized,
+    Access, ClientId, ConnectionRequest, ConnectionResponse, ConnectionStats, Connections, Digest,
+    LatencyStats, PluginData, Query, ServerTime, Synchronized,
 };
+use cimvr_engine::interface::scene::Scene;
 use cimvr_engine::interface::serial::{deserialize, serialize, serialize_into};
+use cimvr_engine::interface::Saved;
+use cimvr_engine::replication::{Codec, ReplicationState};
+use cimvr_engine::timing::FixedTimestep;
 use cimvr_engine::{calculate_digest, Config};
 use cimvr_engine::{interface::system::Stage, network::*, Engine};
 
-use std::time::Instant;
 use std::{
     io::Write,
     net::{SocketAddr, TcpListener, TcpStream},
     sync::mpsc::{self, Receiver, Sender},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use std::path::{Path, PathBuf};
 use structopt::StructOpt;
 
+mod relevance;
+use relevance::{filter_entities, RelevanceIndex};
+
 #[derive(Debug, StructOpt)]
 #[structopt(
     name = "ChatImproVR Server",
@@ -30,6 +38,63 @@ struct Opt {
     #[structopt(short, long, default_value = "0.0.0.0:5031")]
     bind: SocketAddr,
 
+    /// Simulation ticks per second
+    #[structopt(long, default_value = "66")]
+    tick_rate: f32,
+
+    /// Maximum number of ticks to catch up on in a single update, if the server falls behind
+    #[structopt(long, default_value = "5")]
+    max_catchup_ticks: u32,
+
+    /// Start with the engine clock paused
+    #[structopt(long)]
+    paused: bool,
+
+    /// Rate at which the engine clock runs relative to real time
+    #[structopt(long, default_value = "1")]
+    time_scale: f32,
+
+    /// Radius (in meters) around each client's viewpoint within which entities are sent to it
+    #[structopt(long, default_value = "100")]
+    interest_radius: f32,
+
+    /// Maximum number of bytes of component data sent per update. Unlimited by default
+    #[structopt(long)]
+    replication_budget: Option<usize>,
+
+    /// Scene (JSON) to load into the ECS at startup
+    #[structopt(long)]
+    scene: Option<PathBuf>,
+
+    /// Periodically write the `Saved` entities to this scene (JSON), which may be loaded again
+    /// with --scene
+    #[structopt(long)]
+    save_scene: Option<PathBuf>,
+
+    /// Seconds between writes of --save-scene
+    #[structopt(long, default_value = "30")]
+    save_interval: f32,
+
+    /// Capture a profiling trace of the first frames, and write it to this path. The trace may be
+    /// opened with chrome://tracing or Perfetto
+    #[structopt(long)]
+    trace: Option<PathBuf>,
+
+    /// Number of frames to capture with --trace
+    #[structopt(long, default_value = "300")]
+    trace_frames: u32,
+
+    /// Run plugin systems which do not access the same components in parallel, rather than one
+    /// after another. Systems running alongside each other do not see entities created or deleted,
+    /// or components added, by each other until the next stage
+    #[structopt(long)]
+    parallel: bool,
+
+    /// Load all plugins in this directory, including ones added while running, and unload those
+    /// deleted from it
+    #[structopt(long)]
+    plugin_dir: Option<PathBuf>,
+
     /// Plugins
     plugins: Vec<PathBuf>,
 }
@@ -44,10 +109,25 @@ fn main() -> Result<()> {
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
 
     // Set up engine and initialize plugins
-    let hotload = Hotloader::new(&args.plugins)?;
+    let mut hotload = Hotloader::new(&args.plugins)?;
+
+    let mut plugin_paths = args.plugins.clone();
+    if let Some(dir) = &args.plugin_dir {
+        hotload.watch_dir(dir)?;
+
+        // Skip plugins which were also given individually
+        let given: Vec<PathBuf> = plugin_paths
+            .iter()
+            .filter_map(|path| path.canonicalize().ok())
+            .collect();
+        for path in wasm_files(dir)? {
+            if !given.contains(&path.canonicalize()?) {
+                plugin_paths.push(path);
+            }
+        }
+    }
 
-    let plugins: Vec<(String, Vec<u8>)> = args
-        .plugins
+    let plugins: Vec<(String, Vec<u8>)> = plugin_paths
         .iter()
         .map(|path| {
             let name = path_to_plugin_name(&path);
@@ -56,24 +136,69 @@ fn main() -> Result<()> {
         })
         .collect::<Result<_>>()?;
 
-    let mut engine = Engine::new(&plugins, Config { is_server: true })?;
+    let cfg = Config {
+        is_server: true,
+        fixed_timestep: Some(
+            FixedTimestep::new(args.tick_rate, args.max_catchup_ticks)
+                .context("Invalid --tick-rate or --max-catchup-ticks")?,
+        ),
+        parallel: args.parallel,
+        host_functions: Default::default(),
+    };
+    let mut engine = Engine::new(&plugins, cfg)?;
+    engine.set_time_scale(args.time_scale);
+    engine
+        .ecs()
+        .replication()
+        .set_budget(args.replication_budget);
+    engine
+        .ecs()
+        .replication()
+        .set_codec::<Transform>(Codec::via::<Transform, QuantizedTransform>());
+    engine.set_hierarchy(Hierarchy::new::<Transform, GlobalTransform>());
+    register_schemas(engine.schemas_mut());
+    if args.paused {
+        engine.pause();
+    }
+    if let Some(path) = &args.scene {
+        load_scene(&mut engine, path)?;
+    }
+    if args.trace.is_some() {
+        engine.profiler_mut().capture(args.trace_frames);
+    }
     engine.init_plugins()?;
 
     // Create a new thread for the connection listener
     let (conn_tx, conn_rx) = mpsc::channel();
     std::thread::spawn(move || connection_listener(bind_addr, conn_tx));
 
-    let mut server = Server::new(conn_rx, engine, hotload, plugins);
-    let target = Duration::from_millis(15);
+    let mut server = Server::new(conn_rx, engine, hotload, plugins, args.interest_radius);
+
+    let save_interval =
+        Duration::try_from_secs_f32(args.save_interval).context("Invalid --save-interval")?;
+    let mut last_save = Instant::now();
 
     loop {
-        let start = Instant::now();
         server.update()?;
-        let elap = start.elapsed();
 
-        if let Some(wait_time) = target.checked_sub(elap) {
-            std::thread::sleep(wait_time);
+        if let Some(path) = &args.save_scene {
+            if last_save.elapsed() >= save_interval {
+                last_save = Instant::now();
+                if let Err(e) = save_scene(&mut server.engine, path) {
+                    log::error!("{:#}", e);
+                }
+            }
         }
+
+        if let Some(trace) = server.engine.profiler_mut().take_trace() {
+            let path = args.trace.as_ref().unwrap();
+            match trace.write(path) {
+                Ok(()) => log::info!("Wrote profiling trace to {}", path.display()),
+                Err(e) => log::error!("Failed to write profiling trace; {:#}", e),
+            }
+        }
+
+        std::thread::sleep(server.engine.time_until_next_frame());
     }
 }
 
@@ -109,6 +234,14 @@ struct Connection {
     id: ClientId,
     /// Username
     username: String,
+    /// Most recent clock ping, and the server time at which it was received
+    ping: Option<(ClockPing, f64)>,
+    /// Latency statistics reported by the client
+    latency: Option<LatencyStats>,
+    /// Replication progress towards this client
+    replication: ReplicationState,
+    /// Most recently reported viewpoint
+    viewpoint: Option<Vec3>,
 }
 
 /// Server internals
@@ -126,6 +259,10 @@ struct Server {
     /// Currently loaded plugin bytecode. Can change during runtime,
     /// so we keep this in order to send it to new clients
     bytecode: Vec<(Digest, String, Vec<u8>)>,
+    /// Server clock, used for clock synchronization with clients
+    clock: NetClock,
+    /// Radius of the area of interest around each client's viewpoint
+    interest_radius: f32,
 }
 
 impl Server {
@@ -134,6 +271,7 @@ impl Server {
         engine: Engine,
         hotload: Hotloader,
         bytecode: Vec<(String, Vec<u8>)>,
+        interest_radius: f32,
     ) -> Self {
         let bytecode = bytecode
             .into_iter()
@@ -146,32 +284,63 @@ impl Server {
             conn_rx,
             conns: vec![],
             id_counter: 0,
+            clock: NetClock::new(),
+            interest_radius,
         }
     }
 
     fn update(&mut self) -> Result<()> {
         // Check for hotloaded plugins
         let mut hotloaded = vec![];
-        for path in self.hotload.hotload()? {
-            log::info!("Reloading {}", path.display());
-            let name = path_to_plugin_name(&path);
-            let bytecode = std::fs::read(path)?;
-            self.engine.reload(name.clone(), &bytecode)?;
-
-            // Update bytecode on our side so that newly connected clients will have the current code
-            let (entry_digest, entry_bytecode) = self
-                .bytecode
-                .iter_mut()
-                .find_map(|(digest, plugin_name, code)| {
-                    (plugin_name == &name).then(|| (digest, code))
-                })
-                .unwrap();
-            *entry_digest = calculate_digest(&bytecode);
-            *entry_bytecode = bytecode.clone();
+        let mut unloaded = vec![];
+        for event in self.hotload.hotload() {
+            match event {
+                HotloadEvent::Load(path, bytecode) => {
+                    let name = path_to_plugin_name(&path);
+                    let digest = calculate_digest(&bytecode);
+
+                    // Update bytecode on our side so that newly connected clients will have the
+                    // current code
+                    let entry = self
+                        .bytecode
+                        .iter_mut()
+                        .find(|(_, plugin_name, _)| plugin_name == &name);
+                    // Plugins which fail to load are not sent to clients
+                    if let Some((entry_digest, _, entry_bytecode)) = entry {
+                        log::info!("Reloading {}", path.display());
+                        if let Err(e) = self.engine.reload(name.clone(), &bytecode) {
+                            log::error!("Failed to reload {}; {:#}", path.display(), e);
+                            continue;
+                        }
+                        *entry_digest = digest;
+                        *entry_bytecode = bytecode.clone();
+                    } else {
+                        log::info!("Loading {}", path.display());
+                        if let Err(e) = self.engine.load_plugin(name.clone(), &bytecode) {
+                            log::error!("Failed to load {}; {:#}", path.display(), e);
+                            continue;
+                        }
+                        self.bytecode.push((digest, name.clone(), bytecode.clone()));
+                    }
 
-            // Remember which plugins were hotloaded, so that we can send code to
-            // the clients!
-            hotloaded.push((name, bytecode));
+                    // Remember which plugins were hotloaded, so that we can send code to
+                    // the clients!
+                    hotloaded.push((name, bytecode));
+                }
+                HotloadEvent::Unload(path) => {
+                    let name = path_to_plugin_name(&path);
+                    if self.engine.is_loaded(&name) {
+                        log::info!("Unloading {}", path.display());
+                        if let Err(e) = self.engine.unload_plugin(&name) {
+                            log::error!("Failed to unload {}; {:#}", path.display(), e);
+                            continue;
+                        }
+                        self.bytecode
+                            .retain(|(_, plugin_name, _)| plugin_name != &name);
+                        unloaded.push(name);
+                    }
+                }
+            }
         }
 
         let mut conns_tmp = vec![];
@@ -194,8 +363,10 @@ impl Server {
                 }
             }
 
+            let id = ClientId(self.id_counter);
             let resp = ConnectionResponse {
                 plugins: response_plugins,
+                client_id: id,
             };
 
             // Write response
@@ -209,7 +380,11 @@ impl Server {
                     msg_buf: AsyncBufferedReceiver::new(),
                     stream,
                     username: req.username,
-                    id: ClientId(self.id_counter),
+                    id,
+                    ping: None,
+                    latency: None,
+                    replication: ReplicationState::default(),
+                    viewpoint: None,
                 });
                 self.id_counter += 1;
             }
@@ -226,6 +401,26 @@ impl Server {
                     ReadState::Complete(buf) => {
                         let msgs: ClientToServer =
                             deserialize(std::io::Cursor::new(buf)).expect("Malformed message");
+
+                        // Remember clock pings so that we may respond to them
+                        if let Some(ping) = msgs.ping {
+                            conn.ping = Some((ping, self.clock.now()));
+                            if let (Some(rtt), Some(offset)) = (ping.rtt, ping.offset) {
+                                conn.latency = Some(LatencyStats {
+                                    id: conn.id,
+                                    rtt,
+                                    offset,
+                                });
+                            }
+                        }
+
+                        if let Some(viewpoint) = msgs.viewpoint {
+                            conn.viewpoint = Some(Vec3::from_array(viewpoint));
+                        }
+
+                        // Accept the client's writes to the entities it owns
+                        self.engine.ecs().import_owned(conn.id, msgs.ecs);
+
                         // Broadcast from client to server modules
                         for mut msg in msgs.messages {
                             // Set the client ID for each message(!)
@@ -260,32 +455,73 @@ impl Server {
                 .collect(),
         });
 
-        // Execute update steps
-        self.engine.dispatch(Stage::PreUpdate)?;
-        self.engine.dispatch(Stage::Update)?;
-        self.engine.dispatch(Stage::PostUpdate)?;
+        // Send latency statistics
+        self.engine.send(ConnectionStats {
+            clients: conns_tmp.iter().filter_map(|c| c.latency).collect(),
+        });
 
-        // Gather current synchronized state
-        let state = ServerToClient {
-            ecs: self
-                .engine
-                .ecs()
-                .export(&Query::new().intersect::<Synchronized>(Access::Read)),
-            messages: self.engine.network_inbox(),
-            hotload: hotloaded,
-        };
+        // Serverside, the server time is just our own clock
+        self.engine.send(ServerTime {
+            time: self.clock.now(),
+            rtt: 0.,
+            offset: 0.,
+        });
 
-        // Write header and serialize message
+        // Execute update steps, catching up on any ticks we have fallen behind on
+        for _ in 0..self.engine.poll_frames() {
+            self.engine.dispatch(Stage::PreUpdate)?;
+            self.engine.dispatch(Stage::Update)?;
+            self.engine.dispatch(Stage::PostUpdate)?;
+        }
+
+        // Gather current synchronized state
+        let ecs = self
+            .engine
+            .ecs()
+            .export(&Query::new().intersect::<Synchronized>(Access::Read));
+        let ecs = self.engine.ecs().replication().encode(ecs);
+        let messages = self.engine.network_inbox();
+        let relevance = RelevanceIndex::build(&mut self.engine, self.interest_radius);
+
+        // Messages addressed to clients which have since disconnected are lost
+        for msg in &messages {
+            if msg
+                .client
+                .is_some_and(|client| !conns_tmp.iter().any(|conn| conn.id == client))
+            {
+                self.engine.metrics_mut().record_dropped(None, &msg.channel);
+            }
+        }
 
         // Broadcast to clients
         for mut conn in conns_tmp.drain(..) {
-            // TODO: This is a dumb, slow way to do this lol
-            // Only send message to the clients which they are destined for
-            let mut state = state.clone();
-            state.messages.retain(|m| match m.client {
-                None => true,
-                Some(outgoing) => outgoing == conn.id,
-            });
+            // Only send the entities relevant to this client
+            let relevant = relevance.relevant(conn.id, conn.viewpoint);
+
+            let ecs = self
+                .engine
+                .ecs()
+                .replication()
+                .select(&mut conn.replication, filter_entities(&ecs, &relevant));
+
+            let state = ServerToClient {
+                ecs,
+                // Only send message to the clients which they are destined for
+                messages: messages
+                    .iter()
+                    .filter(|m| m.client.is_none_or(|outgoing| outgoing == conn.id))
+                    .cloned()
+                    .collect(),
+                hotload: hotloaded.clone(),
+                unload: unloaded.clone(),
+                server_time: self.clock.now(),
+                // Respond to the client's clock ping
+                pong: conn.ping.take().map(|(ping, server_recv)| ClockPong {
+                    client_send: ping.client_send,
+                    server_recv,
+                    server_send: self.clock.now(),
+                }),
+            };
 
             // Serialize message
             conn.stream.set_nonblocking(false)?;
@@ -305,3 +541,30 @@ impl Server {
 fn path_to_plugin_name(path: &Path) -> String {
     path.file_name().unwrap().to_str().unwrap().to_string()
 }
+
+/// Load the scene at the given path into the ECS
+fn load_scene(engine: &mut Engine, path: &Path) -> Result<()> {
+    let json = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read scene {}", path.display()))?;
+    let scene = Scene::from_json(&json)
+        .with_context(|| format!("Failed to parse scene {}", path.display()))?;
+
+    let entities = engine.load_scene(&scene)?;
+    log::info!("Loaded {} entities from {}", entities.len(), path.display());
+
+    Ok(())
+}
+
+/// Write the `Saved` entities in the ECS to the scene at the given path
+fn save_scene(engine: &mut Engine, path: &Path) -> Result<()> {
+    let scene = engine.save_scene(&Query::new().intersect::<Saved>(Access::Read));
+    let json = scene
+        .to_json()
+        .with_context(|| format!("Failed to encode scene {}", path.display()))?;
+    std::fs::write(path, json)
+        .with_context(|| format!("Failed to write scene {}", path.display()))?;
+    let count = scene.entities.len();
+    log::debug!("Saved {} entities to {}", count, path.display());
+
+    Ok(())
+}
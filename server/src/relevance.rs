@@ -0,0 +1,139 @@
+use std::collections::{HashMap, HashSet};
+
+use cimvr_common::{
+    glam::Vec3,
+    relevance::{Relevance, RelevanceMode},
+    GlobalTransform, Transform,
+};
+use cimvr_engine::{
+    ecs::EcsMap,
+    interface::prelude::{Access, ClientId, EntityId, Query, Synchronized},
+    Engine,
+};
+
+/// Uniform grid of cells over entity positions
+struct SpatialGrid {
+    cell_size: f32,
+    cells: HashMap<[i32; 3], Vec<(EntityId, Vec3)>>,
+}
+
+/// Determines which `Synchronized` entities are relevant to each client
+pub struct RelevanceIndex {
+    /// Radius of the area of interest around each client's viewpoint
+    radius: f32,
+    /// Spatially relevant entities
+    grid: SpatialGrid,
+    /// Entities relevant to all clients
+    always: Vec<EntityId>,
+    /// Entities relevant only to their owner
+    owned: HashMap<ClientId, Vec<EntityId>>,
+}
+
+impl RelevanceIndex {
+    /// Index the current `Synchronized` entities, using the given interest radius
+    pub fn build(engine: &mut Engine, radius: f32) -> Self {
+        let mut index = Self {
+            radius,
+            grid: SpatialGrid::new(radius),
+            always: vec![],
+            owned: HashMap::new(),
+        };
+
+        let entities = engine
+            .ecs()
+            .query(&Query::new().intersect::<Synchronized>(Access::Read));
+
+        for entity in entities {
+            let relevance = engine.ecs().get::<Relevance>(entity).unwrap_or_default();
+
+            match relevance.mode {
+                RelevanceMode::Always => index.always.push(entity),
+                RelevanceMode::OwnerOnly => {
+                    index.owned.entry(relevance.owner).or_default().push(entity)
+                }
+                RelevanceMode::Spatial => match world_position(engine, entity) {
+                    Some(pos) => index.grid.insert(entity, pos),
+                    // Entities without a position cannot be culled
+                    None => index.always.push(entity),
+                },
+            }
+        }
+
+        index
+    }
+
+    /// Entities relevant to the given client. If the client has not told us its viewpoint yet, all
+    /// spatially relevant entities are included.
+    pub fn relevant(&self, client: ClientId, viewpoint: Option<Vec3>) -> HashSet<EntityId> {
+        let mut relevant: HashSet<EntityId> = self.always.iter().copied().collect();
+
+        if let Some(owned) = self.owned.get(&client) {
+            relevant.extend(owned.iter().copied());
+        }
+
+        match viewpoint {
+            Some(pos) => relevant.extend(self.grid.within(pos, self.radius)),
+            None => relevant.extend(self.grid.all()),
+        }
+
+        relevant
+    }
+}
+
+/// Position of the given entity in world space
+fn world_position(engine: &mut Engine, entity: EntityId) -> Option<Vec3> {
+    match engine.ecs().get::<GlobalTransform>(entity) {
+        Some(GlobalTransform(transf)) => Some(transf.pos),
+        None => engine.ecs().get::<Transform>(entity).map(|transf| transf.pos),
+    }
+}
+
+impl SpatialGrid {
+    fn new(cell_size: f32) -> Self {
+        Self {
+            cell_size: cell_size.max(f32::EPSILON),
+            cells: HashMap::new(),
+        }
+    }
+
+    fn cell(&self, pos: Vec3) -> [i32; 3] {
+        (pos / self.cell_size).floor().as_ivec3().to_array()
+    }
+
+    fn insert(&mut self, entity: EntityId, pos: Vec3) {
+        let cell = self.cell(pos);
+        self.cells.entry(cell).or_default().push((entity, pos));
+    }
+
+    /// Entities within the given radius of the given position
+    fn within(&self, pos: Vec3, radius: f32) -> impl Iterator<Item = EntityId> + '_ {
+        let [x0, y0, z0] = self.cell(pos - radius);
+        let [x1, y1, z1] = self.cell(pos + radius);
+
+        (x0..=x1)
+            .flat_map(move |x| (y0..=y1).flat_map(move |y| (z0..=z1).map(move |z| [x, y, z])))
+            .filter_map(|cell| self.cells.get(&cell))
+            .flatten()
+            .filter(move |(_, other)| other.distance_squared(pos) <= radius * radius)
+            .map(|(entity, _)| *entity)
+    }
+
+    /// All entities in the grid
+    fn all(&self) -> impl Iterator<Item = EntityId> + '_ {
+        self.cells.values().flatten().map(|(entity, _)| *entity)
+    }
+}
+
+/// Copy only the data belonging to the given entities
+pub fn filter_entities(ecs: &EcsMap, entities: &HashSet<EntityId>) -> EcsMap {
+    ecs.iter()
+        .map(|(component, data)| {
+            let data = data
+                .iter()
+                .filter(|(entity, _)| entities.contains(entity))
+                .map(|(entity, bytes)| (*entity, bytes.clone()))
+                .collect();
+            (component.clone(), data)
+        })
+        .collect()
+}